@@ -72,6 +72,7 @@ async fn spawn_socks_server() -> Result<()> {
     let mut config = Config::default();
     config.set_request_timeout(opt.request_timeout);
     config.set_skip_auth(opt.skip_auth);
+    config.set_session_end_callback(|stats| debug!("session summary: {:?}", stats));
 
     match opt.auth {
         AuthMode::NoAuth => warn!("No authentication has been set!"),
@@ -93,8 +93,10 @@ async fn spawn_socks_server() -> Result<()> {
     while let Some(socket_res) = incoming.next().await {
         match socket_res {
             Ok(socket) => {
-                info!("Connection from {}", socket.peer_addr()?);
-                let socket = Socks5Socket::new(socket, config.clone());
+                let peer_addr = socket.peer_addr()?;
+                info!("Connection from {}", peer_addr);
+                let mut socket = Socks5Socket::new(socket, config.clone());
+                socket.set_peer_addr(peer_addr).set_half_close(true);
 
                 //                                socket.upgrade_to_socks5().await;
                 spawn_and_log_error(socket.upgrade_to_socks5());
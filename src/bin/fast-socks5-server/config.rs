@@ -0,0 +1,753 @@
+//! Configuration file of the server, see `examples/fast-socks5-server.toml`.
+//!
+//! The file is parsed, then validated: every error points to the line of the faulty value.
+use fast_socks5::server::access_log::{AccessLog, AccessLogFormat, FileSink};
+use fast_socks5::server::acl::{AccessControl, Action, DomainPattern, Rule};
+use fast_socks5::server::proxy_protocol::ProxyProtocol;
+use fast_socks5::server::ssrf::SsrfProtection;
+use fast_socks5::server::unix::UnixListenerOptions;
+use fast_socks5::server::{self, Authentication, ListenerAddr};
+use fast_socks5::Command;
+use ipnet::IpNet;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fmt;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+use subtle::ConstantTimeEq;
+use toml::Spanned;
+
+/// Invalid configuration, on this line of the file if known.
+#[derive(Debug)]
+pub struct ConfigError {
+    pub line: Option<usize>,
+    pub message: String,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError {
+                line: Some(line),
+                message,
+            } => write!(f, "line {}: {}", line, message),
+            ConfigError {
+                line: None,
+                message,
+            } => f.write_str(message),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// Locates the errors within the source of the file.
+struct Source<'a>(&'a str);
+
+impl Source<'_> {
+    fn error<M: fmt::Display>(&self, span: Option<Range<usize>>, message: M) -> ConfigError {
+        ConfigError {
+            line: span.map(|span| self.0[..span.start.min(self.0.len())].matches('\n').count() + 1),
+            message: message.to_string(),
+        }
+    }
+
+    /// Parse a value written as a string.
+    fn parse<T>(&self, value: &Spanned<String>, what: &str) -> Result<T, ConfigError>
+    where
+        T: FromStr,
+        T::Err: fmt::Display,
+    {
+        value.get_ref().parse().map_err(|e| {
+            self.error(
+                Some(value.span()),
+                format!("invalid {} `{}`: {}", what, value.get_ref(), e),
+            )
+        })
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct File {
+    #[serde(default)]
+    listeners: Vec<Spanned<Listener>>,
+    #[serde(default)]
+    server: ServerSection,
+    #[serde(default)]
+    dns: DnsSection,
+    #[serde(default)]
+    auth: AuthSection,
+    acl: Option<AclSection>,
+    #[serde(default)]
+    logging: LoggingSection,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Listener {
+    /// Shown in the logs, the address by default
+    label: Option<Spanned<String>>,
+    /// TCP address, `path` of a Unix socket, or name of a socket passed by systemd
+    /// (`FileDescriptorName=`)
+    address: Option<Spanned<String>>,
+    path: Option<Spanned<PathBuf>>,
+    systemd: Option<Spanned<String>>,
+    /// Permissions of the Unix socket, in octal like "0660"
+    mode: Option<Spanned<String>>,
+    /// Sections replacing the top-level ones for this listener
+    server: Option<ServerSection>,
+    dns: Option<DnsSection>,
+    auth: Option<AuthSection>,
+    acl: Option<AclSection>,
+}
+
+/// Durations in seconds, `shutdown_timeout` is only read from the top-level section
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct ServerSection {
+    request_timeout: Option<u64>,
+    handshake_timeout: Option<u64>,
+    read_timeout: Option<u64>,
+    idle_timeout: Option<u64>,
+    max_session_duration: Option<u64>,
+    linger_timeout: Option<u64>,
+    shutdown_timeout: Option<u64>,
+    skip_auth: Option<bool>,
+    execute_command: Option<bool>,
+    /// Networks of the load balancers sending a PROXY protocol header
+    #[serde(default)]
+    proxy_protocol_trusted: Vec<Spanned<String>>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct DnsSection {
+    resolve: Option<bool>,
+    connection_attempt_delay_ms: Option<u64>,
+    ssrf_protection: Option<bool>,
+    #[serde(default)]
+    ssrf_forbidden: Vec<Spanned<String>>,
+    #[serde(default)]
+    ssrf_exceptions: Vec<Spanned<String>>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct AuthSection {
+    backend: Option<Spanned<String>>,
+    htpasswd: Option<Spanned<PathBuf>>,
+    #[serde(default)]
+    users: Vec<User>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct User {
+    username: Spanned<String>,
+    password: String,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct AclSection {
+    default: Spanned<String>,
+    #[serde(default)]
+    dry_run: bool,
+    #[serde(default)]
+    rules: Vec<RuleSection>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RuleSection {
+    action: Spanned<String>,
+    #[serde(default)]
+    sources: Vec<Spanned<String>>,
+    #[serde(default)]
+    destinations: Vec<Spanned<String>>,
+    #[serde(default)]
+    domains: Vec<Spanned<String>>,
+    /// `22` or `"8000-8100"`
+    #[serde(default)]
+    ports: Vec<Spanned<Port>>,
+    #[serde(default)]
+    commands: Vec<Spanned<String>>,
+    #[serde(default)]
+    users: Vec<String>,
+    #[serde(default)]
+    groups: Vec<String>,
+    /// Users and groups of the clients connected through a Unix socket
+    #[serde(default)]
+    uids: Vec<u32>,
+    #[serde(default)]
+    gids: Vec<u32>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Port {
+    Single(u16),
+    Range(String),
+}
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct LoggingSection {
+    /// `env_logger` filter, overridden by `RUST_LOG`
+    level: Option<String>,
+    access_log: Option<Spanned<PathBuf>>,
+    access_log_format: Option<Spanned<String>>,
+    access_log_max_size: Option<u64>,
+    access_log_max_files: Option<usize>,
+}
+
+/// Where a listener gets its socket from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ListenerSource {
+    Bind(ListenerAddr),
+    /// Passed by systemd, or the process replacing another one
+    Systemd(String),
+}
+
+impl fmt::Display for ListenerSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ListenerSource::Bind(address) => address.fmt(f),
+            ListenerSource::Systemd(name) => write!(f, "systemd:{}", name),
+        }
+    }
+}
+
+pub struct ListenerSettings {
+    pub label: String,
+    pub source: ListenerSource,
+    pub unix_options: UnixListenerOptions,
+    pub config: server::Config,
+}
+
+/// Everything the server needs, once validated.
+pub struct Settings {
+    pub listeners: Vec<ListenerSettings>,
+    /// How long the sessions are given to end once asked to stop
+    pub shutdown_timeout: Duration,
+    pub log_filter: String,
+    /// File of the access log, to keep writing to it once reloaded
+    pub access_log: Option<AccessLogFile>,
+}
+
+/// The access log file opened, a single writer must append to it and rotate it.
+#[derive(Clone)]
+pub struct AccessLogFile {
+    path: PathBuf,
+    max_size: Option<u64>,
+    max_files: Option<usize>,
+    sink: Arc<FileSink>,
+}
+
+/// Users listed in the configuration file.
+struct Users(HashMap<String, String>);
+
+impl Authentication for Users {
+    fn authenticate(&self, username: &str, password: &str) -> bool {
+        match self.0.get(username) {
+            Some(expected) => expected.as_bytes().ct_eq(password.as_bytes()).into(),
+            None => false,
+        }
+    }
+}
+
+fn seconds(value: Option<u64>) -> Option<Duration> {
+    value.map(Duration::from_secs)
+}
+
+impl Settings {
+    /// `current` is the access log of the settings being replaced, kept if its file is the same.
+    pub fn load<P: AsRef<Path>>(
+        path: P,
+        current: Option<&AccessLogFile>,
+    ) -> Result<Self, ConfigError> {
+        let path = path.as_ref();
+        let content = std::fs::read_to_string(path).map_err(|e| ConfigError {
+            line: None,
+            message: format!("can't read {}: {}", path.display(), e),
+        })?;
+        Settings::parse(&content, current)
+    }
+
+    pub fn parse(content: &str, current: Option<&AccessLogFile>) -> Result<Self, ConfigError> {
+        let source = Source(content);
+        let file: File =
+            toml::from_str(content).map_err(|e| source.error(e.span(), e.message()))?;
+
+        if file.listeners.is_empty() {
+            return Err(source.error(None, "at least one [[listeners]] is required"));
+        }
+
+        // Shared by the config of every listener
+        let mut base = server::Config::default();
+        let access_log = Self::apply_access_log(&source, &file.logging, current, &mut base)?;
+
+        let mut listeners: Vec<ListenerSettings> = Vec::new();
+        for spanned in &file.listeners {
+            let listener = spanned.get_ref();
+            let mut unix_options = UnixListenerOptions::default();
+            let listen = match (&listener.address, &listener.path, &listener.systemd) {
+                (Some(address), None, None) => ListenerSource::Bind(ListenerAddr::Tcp(
+                    source.parse(address, "listener address")?,
+                )),
+                (None, Some(path), None) => {
+                    if let Some(mode) = &listener.mode {
+                        let parsed = u32::from_str_radix(mode.get_ref(), 8)
+                            .ok()
+                            .filter(|mode| *mode <= 0o777);
+                        unix_options.set_mode(parsed.ok_or_else(|| {
+                            source.error(
+                                Some(mode.span()),
+                                "invalid mode, expected octal permissions like \"0660\"",
+                            )
+                        })?);
+                    }
+                    ListenerSource::Bind(ListenerAddr::Unix(path.get_ref().clone()))
+                }
+                (None, None, Some(name)) => ListenerSource::Systemd(name.get_ref().clone()),
+                _ => {
+                    return Err(source.error(
+                        Some(spanned.span()),
+                        "a listener requires one of `address`, `path` or `systemd`",
+                    ))
+                }
+            };
+            if let (Some(mode), None) = (&listener.mode, &listener.path) {
+                return Err(source.error(
+                    Some(mode.span()),
+                    "`mode` only applies to the Unix sockets created from a `path`",
+                ));
+            }
+            let label = match &listener.label {
+                Some(label) => {
+                    if listeners
+                        .iter()
+                        .any(|other| other.label == *label.get_ref())
+                    {
+                        return Err(source.error(
+                            Some(label.span()),
+                            format!("duplicate listener label `{}`", label.get_ref()),
+                        ));
+                    }
+                    label.get_ref().clone()
+                }
+                None => listen.to_string(),
+            };
+            if listeners.iter().any(|other| other.source == listen) {
+                return Err(source.error(
+                    Some(spanned.span()),
+                    format!("duplicate listener address `{}`", listen),
+                ));
+            }
+
+            let mut config = base.clone();
+            Self::apply_server(
+                &source,
+                listener.server.as_ref().unwrap_or(&file.server),
+                &mut config,
+            )?;
+            Self::apply_dns(
+                &source,
+                listener.dns.as_ref().unwrap_or(&file.dns),
+                &mut config,
+            )?;
+            Self::apply_auth(
+                &source,
+                listener.auth.as_ref().unwrap_or(&file.auth),
+                &mut config,
+            )?;
+            if let Some(acl) = listener.acl.as_ref().or(file.acl.as_ref()) {
+                config.set_access_control(Self::access_control(&source, acl)?);
+            }
+
+            listeners.push(ListenerSettings {
+                label,
+                source: listen,
+                unix_options,
+                config,
+            });
+        }
+
+        Ok(Settings {
+            listeners,
+            shutdown_timeout: Duration::from_secs(file.server.shutdown_timeout.unwrap_or(30)),
+            log_filter: file
+                .logging
+                .level
+                .clone()
+                .unwrap_or_else(|| "info".to_string()),
+            access_log,
+        })
+    }
+
+    fn apply_server(
+        source: &Source,
+        server: &ServerSection,
+        config: &mut server::Config,
+    ) -> Result<(), ConfigError> {
+        if let Some(timeout) = server.request_timeout {
+            config.set_request_timeout(timeout);
+        }
+        config
+            .set_handshake_timeout(seconds(server.handshake_timeout))
+            .set_read_timeout(seconds(server.read_timeout))
+            .set_idle_timeout(seconds(server.idle_timeout))
+            .set_max_session_duration(seconds(server.max_session_duration));
+        if let Some(timeout) = server.linger_timeout {
+            config.set_linger_timeout(Some(Duration::from_secs(timeout)));
+        }
+        if let Some(skip_auth) = server.skip_auth {
+            config.set_skip_auth(skip_auth);
+        }
+        if let Some(execute_command) = server.execute_command {
+            config.set_execute_command(execute_command);
+        }
+
+        if !server.proxy_protocol_trusted.is_empty() {
+            let mut proxy_protocol = ProxyProtocol::new();
+            for network in &server.proxy_protocol_trusted {
+                proxy_protocol.add_trusted(source.parse::<IpNet>(network, "network")?);
+            }
+            config.set_proxy_protocol(proxy_protocol);
+        }
+        Ok(())
+    }
+
+    fn apply_dns(
+        source: &Source,
+        dns: &DnsSection,
+        config: &mut server::Config,
+    ) -> Result<(), ConfigError> {
+        if let Some(resolve) = dns.resolve {
+            config.set_dns_resolve(resolve);
+        }
+        if let Some(delay) = dns.connection_attempt_delay_ms {
+            config.set_connection_attempt_delay(Duration::from_millis(delay));
+        }
+
+        if dns.ssrf_protection.unwrap_or(true) {
+            let mut protection = SsrfProtection::default();
+            for network in &dns.ssrf_forbidden {
+                protection.add_forbidden(source.parse::<IpNet>(network, "network")?);
+            }
+            for network in &dns.ssrf_exceptions {
+                protection.add_exception(source.parse::<IpNet>(network, "network")?);
+            }
+            config.set_ssrf_protection(protection);
+        }
+        Ok(())
+    }
+
+    fn apply_auth(
+        source: &Source,
+        auth: &AuthSection,
+        config: &mut server::Config,
+    ) -> Result<(), ConfigError> {
+        let backend = match &auth.backend {
+            Some(backend) => backend,
+            None if auth.users.is_empty() => return Ok(()),
+            None => return Err(source.error(None, "[auth] requires a `backend`")),
+        };
+
+        match backend.get_ref().as_str() {
+            "none" => {}
+            "users" => {
+                if auth.users.is_empty() {
+                    return Err(source.error(
+                        Some(backend.span()),
+                        "the `users` backend requires at least one [[auth.users]]",
+                    ));
+                }
+                let mut users = HashMap::new();
+                for user in &auth.users {
+                    let username = user.username.get_ref();
+                    if username.is_empty() || username.len() > 255 || user.password.len() > 255 {
+                        return Err(source.error(
+                            Some(user.username.span()),
+                            "usernames and passwords must be 1 to 255 bytes long",
+                        ));
+                    }
+                    if users
+                        .insert(username.clone(), user.password.clone())
+                        .is_some()
+                    {
+                        return Err(source.error(
+                            Some(user.username.span()),
+                            format!("duplicate user `{}`", username),
+                        ));
+                    }
+                }
+                config.set_authentication(Users(users));
+            }
+            "htpasswd" => {
+                let path = auth.htpasswd.as_ref().ok_or_else(|| {
+                    source.error(
+                        Some(backend.span()),
+                        "the `htpasswd` backend requires an `htpasswd` file",
+                    )
+                })?;
+                Self::apply_htpasswd(source, path, config)?;
+            }
+            other => {
+                return Err(source.error(
+                    Some(backend.span()),
+                    format!(
+                        "unknown auth backend `{}`, expected `none`, `users` or `htpasswd`",
+                        other
+                    ),
+                ))
+            }
+        }
+        Ok(())
+    }
+
+    fn apply_htpasswd(
+        source: &Source,
+        path: &Spanned<PathBuf>,
+        config: &mut server::Config,
+    ) -> Result<(), ConfigError> {
+        let users = server::htpasswd::HtpasswdFile::open(path.get_ref())
+            .map_err(|e| source.error(Some(path.span()), format!("{:#}", e)))?;
+        config.set_authentication(users);
+        Ok(())
+    }
+
+    fn action(source: &Source, action: &Spanned<String>) -> Result<Action, ConfigError> {
+        match action.get_ref().as_str() {
+            "allow" => Ok(Action::Allow),
+            "deny" => Ok(Action::Deny),
+            other => Err(source.error(
+                Some(action.span()),
+                format!("unknown action `{}`, expected `allow` or `deny`", other),
+            )),
+        }
+    }
+
+    fn access_control(source: &Source, acl: &AclSection) -> Result<AccessControl, ConfigError> {
+        let mut access_control = AccessControl::new(Self::action(source, &acl.default)?);
+        access_control.set_dry_run(acl.dry_run);
+
+        for section in &acl.rules {
+            let mut rule = Rule::new(Self::action(source, &section.action)?);
+            for network in &section.sources {
+                rule.add_source(source.parse(network, "network")?);
+            }
+            for network in &section.destinations {
+                rule.add_destination(source.parse(network, "network")?);
+            }
+            for domain in &section.domains {
+                rule.add_domain(source.parse::<DomainPattern>(domain, "domain pattern")?);
+            }
+            for port in &section.ports {
+                rule.add_port_range(Self::port_range(source, port)?);
+            }
+            for command in &section.commands {
+                rule.add_command(match command.get_ref().as_str() {
+                    "connect" => Command::TcpConnect,
+                    "bind" => Command::TcpBind,
+                    "udp_associate" => Command::UdpAssociate,
+                    other => {
+                        return Err(source.error(
+                            Some(command.span()),
+                            format!(
+                            "unknown command `{}`, expected `connect`, `bind` or `udp_associate`",
+                            other
+                        ),
+                        ))
+                    }
+                });
+            }
+            for user in &section.users {
+                rule.add_user(user.as_str());
+            }
+            for group in &section.groups {
+                rule.add_group(group.as_str());
+            }
+            for uid in &section.uids {
+                rule.add_uid(*uid);
+            }
+            for gid in &section.gids {
+                rule.add_gid(*gid);
+            }
+            access_control.add_rule(rule);
+        }
+
+        Ok(access_control)
+    }
+
+    fn port_range(
+        source: &Source,
+        port: &Spanned<Port>,
+    ) -> Result<std::ops::RangeInclusive<u16>, ConfigError> {
+        let range = match port.get_ref() {
+            Port::Single(port) => Some(*port..=*port),
+            Port::Range(range) => range.split_once('-').and_then(|(start, end)| {
+                let start = start.trim().parse::<u16>().ok()?;
+                let end = end.trim().parse::<u16>().ok()?;
+                Some(start..=end).filter(|range| !range.is_empty())
+            }),
+        };
+        range.ok_or_else(|| {
+            source.error(
+                Some(port.span()),
+                "invalid port, expected a number or a range like \"8000-8100\"",
+            )
+        })
+    }
+
+    fn apply_access_log(
+        source: &Source,
+        logging: &LoggingSection,
+        current: Option<&AccessLogFile>,
+        config: &mut server::Config,
+    ) -> Result<Option<AccessLogFile>, ConfigError> {
+        let format = match &logging.access_log_format {
+            None => AccessLogFormat::JsonLines,
+            Some(format) => match format.get_ref().as_str() {
+                "json" => AccessLogFormat::JsonLines,
+                "common" => AccessLogFormat::Common,
+                other => {
+                    return Err(source.error(
+                        Some(format.span()),
+                        format!(
+                            "unknown access log format `{}`, expected `json` or `common`",
+                            other
+                        ),
+                    ))
+                }
+            },
+        };
+
+        let path = match &logging.access_log {
+            Some(path) => path,
+            None => return Ok(None),
+        };
+        let file = match current.filter(|current| current.path == *path.get_ref()) {
+            // Opening the file again would make two writers append to it and rotate it
+            Some(current) => {
+                if current.max_size != logging.access_log_max_size
+                    || current.max_files != logging.access_log_max_files
+                {
+                    warn!(
+                        "The rotation of the access log {} will be changed on restart",
+                        current.path.display()
+                    );
+                }
+                current.clone()
+            }
+            None => {
+                let mut sink = FileSink::open(path.get_ref())
+                    .map_err(|e| source.error(Some(path.span()), format!("{:#}", e)))?;
+                sink.set_max_size(logging.access_log_max_size);
+                if let Some(max_files) = logging.access_log_max_files {
+                    sink.set_max_files(max_files);
+                }
+                AccessLogFile {
+                    path: path.get_ref().clone(),
+                    max_size: logging.access_log_max_size,
+                    max_files: logging.access_log_max_files,
+                    sink: Arc::new(sink),
+                }
+            }
+        };
+        config.add_observer(AccessLog::new(format, file.sink.clone()));
+        Ok(Some(file))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Settings;
+    use std::sync::Arc;
+
+    fn error(content: &str) -> String {
+        match Settings::parse(content, None) {
+            Ok(_) => panic!("no error"),
+            Err(e) => e.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_example() {
+        let settings = Settings::parse(
+            include_str!("../../../examples/fast-socks5-server.toml"),
+            None,
+        )
+        .expect("invalid example");
+        let labels: Vec<_> = settings
+            .listeners
+            .iter()
+            .map(|l| l.label.as_str())
+            .collect();
+        assert_eq!(labels, ["local", "partner"]);
+    }
+
+    #[test]
+    fn test_access_log_reload() {
+        let dir = std::env::temp_dir();
+        let content = |name: &str| {
+            format!(
+                "[[listeners]]\naddress = \"127.0.0.1:1080\"\n\n[logging]\naccess_log = {:?}\n",
+                dir.join(format!("fast-socks5-{}-{}.log", name, std::process::id()))
+            )
+        };
+
+        let first = Settings::parse(&content("access"), None).unwrap();
+        let first = first.access_log.unwrap();
+        let same = Settings::parse(&content("access"), Some(&first)).unwrap();
+        assert!(Arc::ptr_eq(&first.sink, &same.access_log.unwrap().sink));
+        let other = Settings::parse(&content("other"), Some(&first)).unwrap();
+        assert!(!Arc::ptr_eq(&first.sink, &other.access_log.unwrap().sink));
+
+        std::fs::remove_file(&first.path).unwrap();
+        std::fs::remove_file(dir.join(format!("fast-socks5-other-{}.log", std::process::id())))
+            .unwrap();
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(
+            error(
+                "[[listeners]]\naddress = \"127.0.0.1:1080\"\n\n[server]\nidle_timeout = \"5\"\n"
+            ),
+            "line 5: invalid type: string \"5\", expected u64"
+        );
+        assert_eq!(
+            error("[[listeners]]\naddress = \"127.0.0.1:1080\"\n[dns]\nresolv = true\n"),
+            "line 4: unknown field `resolv`, expected one of `resolve`, \
+             `connection_attempt_delay_ms`, `ssrf_protection`, `ssrf_forbidden`, `ssrf_exceptions`"
+        );
+        assert_eq!(
+            error("[[listeners]]\naddress = \"localhost\"\n"),
+            "line 2: invalid listener address `localhost`: invalid socket address syntax"
+        );
+        assert_eq!(
+            error(
+                "[[listeners]]\naddress = \"127.0.0.1:1080\"\n\n[acl]\ndefault = \"deny\"\n\n\
+                 [[acl.rules]]\naction = \"allow\"\nports = [443, \"8000-80\"]\n"
+            ),
+            "line 9: invalid port, expected a number or a range like \"8000-8100\""
+        );
+        assert_eq!(
+            error(
+                "[[listeners]]\nlabel = \"a\"\naddress = \"127.0.0.1:1080\"\n\n\
+                 [[listeners]]\nlabel = \"a\"\naddress = \"127.0.0.1:1081\"\n"
+            ),
+            "line 6: duplicate listener label `a`"
+        );
+        assert_eq!(
+            error("[[listeners]]\nsystemd = \"socks\"\nmode = \"0660\"\n"),
+            "line 3: `mode` only applies to the Unix sockets created from a `path`"
+        );
+        assert_eq!(error(""), "at least one [[listeners]] is required");
+    }
+}
@@ -0,0 +1,246 @@
+#![deny(unsafe_code)]
+#[macro_use]
+extern crate log;
+
+mod config;
+
+use anyhow::Context;
+use async_std::task;
+use config::{AccessLogFile, ListenerSettings, ListenerSource, Settings};
+use fast_socks5::server::activation;
+use fast_socks5::server::reload::ConfigHandle;
+use fast_socks5::server::{ListenerAddr, Socks5Server};
+use futures::StreamExt;
+use signal_hook::consts::{SIGHUP, SIGINT, SIGTERM};
+use signal_hook::iterator::Signals;
+use std::io;
+use std::ops::Range;
+use std::os::unix::io::{FromRawFd, OwnedFd};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::time::Duration;
+use structopt::StructOpt;
+
+/// # How to use it:
+///
+///     `$ fast-socks5-server --config /etc/fast-socks5-server.toml`
+///
+/// Send SIGHUP to reload the configuration file, SIGINT or SIGTERM to stop gracefully.
+///
+/// The listeners with a `systemd` name take the socket of this name passed by systemd, or by
+/// any parent process setting `LISTEN_PID`, `LISTEN_FDS` and `LISTEN_FDNAMES`.
+///
+/// See `examples/fast-socks5-server.toml` for the configuration file.
+#[derive(Debug, StructOpt)]
+#[structopt(
+    name = "fast-socks5-server",
+    about = "A SOCKS5 server configured by a TOML file."
+)]
+struct Opt {
+    /// Configuration file
+    #[structopt(short, long, parse(from_os_str))]
+    config: PathBuf,
+
+    /// Only check the configuration file, then exit
+    #[structopt(long)]
+    check: bool,
+}
+
+fn main() {
+    let inherited = activation::listen_fds().map(|sockets| {
+        sockets
+            .into_iter()
+            .map(|socket| {
+                // Safety: nothing owns the inherited sockets yet, no file is opened before
+                #[allow(unsafe_code)]
+                let fd = unsafe { OwnedFd::from_raw_fd(socket.fd) };
+                (socket.name, fd)
+            })
+            .collect()
+    });
+    let opt = Opt::from_args();
+
+    let settings = match Settings::load(&opt.config, None) {
+        Ok(settings) => settings,
+        Err(e) => {
+            eprintln!("{}: {}", opt.config.display(), e);
+            process::exit(2);
+        }
+    };
+    if opt.check {
+        println!("{}: OK", opt.config.display());
+        return;
+    }
+
+    env_logger::Builder::from_env(
+        env_logger::Env::default().default_filter_or(settings.log_filter.as_str()),
+    )
+    .init();
+
+    let inherited = match inherited {
+        Ok(inherited) => inherited,
+        Err(e) => {
+            error!("Can't take the sockets passed by systemd: {}", e);
+            process::exit(1);
+        }
+    };
+
+    if let Err(e) = task::block_on(run(opt.config, settings, inherited)) {
+        error!("{:#}", e);
+        process::exit(1);
+    }
+}
+
+/// A listener running, with the handle to reload its config.
+struct Running {
+    label: String,
+    source: ListenerSource,
+    config: ConfigHandle,
+}
+
+async fn run(
+    path: PathBuf,
+    settings: Settings,
+    mut inherited: Vec<(String, OwnedFd)>,
+) -> anyhow::Result<()> {
+    let mut shutdown_timeout = settings.shutdown_timeout;
+    let mut access_log = settings.access_log;
+    let mut server = Socks5Server::new();
+    let mut running = Vec::new();
+    for listener in settings.listeners {
+        let ListenerSettings {
+            label,
+            source,
+            unix_options,
+            config,
+        } = listener;
+        let config = match &source {
+            ListenerSource::Bind(ListenerAddr::Tcp(addr)) => {
+                server.add_listener(label.as_str(), *addr, config).await
+            }
+            ListenerSource::Bind(ListenerAddr::Unix(path)) => {
+                server
+                    .add_unix_listener(label.as_str(), path, &unix_options, config)
+                    .await
+            }
+            ListenerSource::Systemd(name) => match inherited.iter().position(|s| s.0 == *name) {
+                Some(i) => {
+                    server.add_inherited_listener(label.as_str(), inherited.remove(i).1, config)
+                }
+                None => Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    "no socket of this name was passed (LISTEN_FDNAMES)",
+                )),
+            },
+        }
+        .with_context(|| format!("Can't listen on {}", source))?;
+        info!("Listen for socks connections @ {} ({})", source, label);
+        running.push(Running {
+            label,
+            source,
+            config,
+        });
+    }
+    for (unused, _) in inherited {
+        warn!(
+            "The socket {} passed by systemd isn't used by any listener",
+            unused
+        );
+    }
+
+    let shutdown = server.shutdown_handle();
+    let mut signals =
+        Signals::new([SIGINT, SIGTERM, SIGHUP]).context("Can't handle the signals")?;
+    let signal_thread = std::thread::spawn(move || {
+        for signal in signals.forever() {
+            if signal == SIGHUP {
+                if let Some((timeout, log)) = reload(&path, &running, access_log.as_ref()) {
+                    shutdown_timeout = timeout;
+                    access_log = log;
+                }
+                continue;
+            }
+
+            info!(
+                "Received signal {}, stopping within {:?}",
+                signal, shutdown_timeout
+            );
+            task::block_on(shutdown.shutdown(shutdown_timeout));
+            break;
+        }
+    });
+
+    serve(&server).await;
+    // Stop listening, removing the Unix sockets, then wait for the sessions to end
+    drop(server);
+    task::spawn_blocking(move || signal_thread.join())
+        .await
+        .map_err(|_| anyhow::anyhow!("The signal handler panicked"))?;
+    info!("Stopped");
+    Ok(())
+}
+
+/// Load the file again and swap the new configs in, for the next clients only. The listeners
+/// are matched by label, adding, removing or moving one, as well as changing the log level,
+/// requires a restart. The access log keeps its file if the path doesn't change. Returns the
+/// new shutdown timeout and access log.
+fn reload(
+    path: &Path,
+    running: &[Running],
+    access_log: Option<&AccessLogFile>,
+) -> Option<(Duration, Option<AccessLogFile>)> {
+    info!("Reloading {}", path.display());
+    let settings = match Settings::load(path, access_log) {
+        Ok(settings) => settings,
+        Err(e) => {
+            error!(
+                "Can't reload {}, keeping the current configuration: {}",
+                path.display(),
+                e
+            );
+            return None;
+        }
+    };
+
+    for listener in settings.listeners {
+        match running.iter().find(|r| r.label == listener.label) {
+            Some(r) if r.source == listener.source => {
+                r.config.replace(listener.config);
+            }
+            _ => warn!(
+                "Listener {} @ {} will be applied on restart",
+                listener.label, listener.source
+            ),
+        }
+    }
+    info!("{} reloaded", path.display());
+    Some((settings.shutdown_timeout, settings.access_log))
+}
+
+/// Delays before accepting again after an error, e.g. out of file descriptors.
+const ACCEPT_BACKOFF: Range<Duration> = Duration::from_millis(5)..Duration::from_secs(1);
+
+async fn serve(server: &Socks5Server) {
+    let mut incoming = server.incoming();
+    let mut backoff = None;
+    while let Some(socket) = incoming.next().await {
+        match socket {
+            Ok(socket) => {
+                backoff = None;
+                task::spawn(async move {
+                    if let Err(e) = socket.upgrade_to_socks5().await {
+                        warn!("{:#}", e);
+                    }
+                });
+            }
+            Err(e) => {
+                let delay = backoff.map_or(ACCEPT_BACKOFF.start, |delay: Duration| {
+                    (delay * 2).min(ACCEPT_BACKOFF.end)
+                });
+                error!("accept error = {:?}, retrying in {:?}", e, delay);
+                backoff = Some(delay);
+                task::sleep(delay).await;
+            }
+        }
+    }
+}
@@ -1,12 +1,21 @@
-#[forbid(unsafe_code)]
+#[cfg(feature = "metrics")]
+use crate::metrics::Metrics;
 use crate::read_exact;
+use crate::util::span::Span;
 use crate::util::target_addr::{read_address, TargetAddr, ToTargetAddr};
 use crate::{consts, AuthenticationMethod, ReplyError, Result, SocksError};
 use anyhow::Context;
 use async_std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+#[cfg(unix)]
+use async_std::os::unix::net::UnixStream;
 use futures::{task::Poll, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use std::fmt;
 use std::io;
+#[cfg(unix)]
+use std::path::Path;
 use std::pin::Pin;
+#[cfg(feature = "metrics")]
+use std::time::Instant;
 
 const MAX_ADDR_LEN: usize = 260;
 
@@ -15,11 +24,17 @@ pub struct Config {
     /// Avoid useless roundtrips if we don't need the Authentication layer
     /// make sure to also activate it on the server side.
     skip_auth: bool,
+    #[cfg(feature = "metrics")]
+    metrics: Option<Metrics>,
 }
 
 impl Default for Config {
     fn default() -> Self {
-        Config { skip_auth: false }
+        Config {
+            skip_auth: false,
+            #[cfg(feature = "metrics")]
+            metrics: None,
+        }
     }
 }
 
@@ -28,6 +43,13 @@ impl Config {
         self.skip_auth = value;
         self
     }
+
+    /// Record the handshakes and the replies of the proxies in these metrics.
+    #[cfg(feature = "metrics")]
+    pub fn set_metrics(&mut self, metrics: Metrics) -> &mut Self {
+        self.metrics = Some(metrics);
+        self
+    }
 }
 
 /// A SOCKS5 client.
@@ -66,8 +88,14 @@ where
 
         // Handshake Lifecycle
         if stream.config.skip_auth == false {
-            let methods = stream.send_version_and_methods(methods).await?;
-            stream.which_method_accepted(methods).await?;
+            let handshake = Span::handshake()
+                .instrument(stream.handshake(methods))
+                .await;
+            #[cfg(feature = "metrics")]
+            if let Some(metrics) = &stream.config.metrics {
+                metrics.record_client_handshake(&handshake);
+            }
+            handshake?;
         } else {
             debug!("skipping auth");
         }
@@ -80,10 +108,22 @@ where
 
         // Request Lifecycle
         info!("Requesting headers `{:?}`...", &self.target_addr);
+        #[cfg(feature = "metrics")]
+        let started = Instant::now();
         self.request_header().await?;
-        self.read_request_reply().await?;
+        let reply = self.read_request_reply().await;
+        #[cfg(feature = "metrics")]
+        if let Some(metrics) = &self.config.metrics {
+            metrics.record_client_reply(&reply, started);
+        }
 
-        Ok(())
+        reply
+    }
+
+    /// Negotiate the authentication method, and authenticate if needed.
+    async fn handshake(&mut self, methods: Vec<AuthenticationMethod>) -> Result<()> {
+        let methods = self.send_version_and_methods(methods).await?;
+        self.which_method_accepted(methods).await
     }
 
     /// Decide to whether or not, accept the authentication method
@@ -399,18 +439,105 @@ impl Socks5Stream<TcpStream> {
         T: ToSocketAddrs,
     {
         let socket = TcpStream::connect(&socks_server).await?;
-        info!("Connected @ {}", &socket.peer_addr()?);
+        let proxy = socket.peer_addr()?;
+        info!("Connected @ {}", &proxy);
+
+        Self::request_through(socket, proxy, target_addr, target_port, auth, config).await
+    }
+}
+
+/// Api if you want to connect to a SOCKS5 server listening on a Unix socket.
+#[cfg(unix)]
+impl Socks5Stream<UnixStream> {
+    /// Connects to a target server through a SOCKS5 proxy listening on this socket file.
+    pub async fn connect_unix<P>(
+        socks_server: P,
+        target_addr: String,
+        target_port: u16,
+        config: Config,
+    ) -> Result<Self>
+    where
+        P: AsRef<Path>,
+    {
+        Self::connect_unix_raw(
+            socks_server.as_ref(),
+            target_addr,
+            target_port,
+            None,
+            config,
+        )
+        .await
+    }
 
+    /// Connect with credentials
+    pub async fn connect_unix_with_password<P>(
+        socks_server: P,
+        target_addr: String,
+        target_port: u16,
+        username: String,
+        password: String,
+        config: Config,
+    ) -> Result<Self>
+    where
+        P: AsRef<Path>,
+    {
+        let auth = AuthenticationMethod::Password { username, password };
+
+        Self::connect_unix_raw(
+            socks_server.as_ref(),
+            target_addr,
+            target_port,
+            Some(auth),
+            config,
+        )
+        .await
+    }
+
+    async fn connect_unix_raw(
+        socks_server: &Path,
+        target_addr: String,
+        target_port: u16,
+        auth: Option<AuthenticationMethod>,
+        config: Config,
+    ) -> Result<Self> {
+        let socket = UnixStream::connect(socks_server)
+            .await
+            .with_context(|| format!("Can't connect to {}", socks_server.display()))?;
+        info!("Connected @ {}", socks_server.display());
+
+        let proxy = socks_server.display();
+        Self::request_through(socket, proxy, target_addr, target_port, auth, config).await
+    }
+}
+
+impl<S> Socks5Stream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    /// Handshake with the proxy connected with `socket`, then request the target.
+    async fn request_through<P: fmt::Display>(
+        socket: S,
+        proxy: P,
+        target_addr: String,
+        target_port: u16,
+        auth: Option<AuthenticationMethod>,
+        config: Config,
+    ) -> Result<Self> {
         // Specify the target, here domain name, dns will be resolved on the server side
         let target_addr = (target_addr.as_str(), target_port)
             .to_target_addr()
             .context("Can't convert address to TargetAddr format")?;
 
-        // upgrade the TcpStream to Socks5Stream
-        let mut socks_stream = Self::use_stream(socket, auth, config).await?;
-        socks_stream.request(target_addr).await?;
+        let span = Span::client(proxy);
+        span.record_target(&target_addr);
+        span.instrument(async move {
+            // upgrade the stream to Socks5Stream
+            let mut socks_stream = Self::use_stream(socket, auth, config).await?;
+            socks_stream.request(target_addr).await?;
 
-        Ok(socks_stream)
+            Ok(socks_stream)
+        })
+        .await
     }
 }
 
@@ -1,8 +1,14 @@
-#[forbid(unsafe_code)]
+#![forbid(unsafe_code)]
+#[cfg(not(feature = "tracing"))]
 #[macro_use]
 extern crate log;
+#[cfg(feature = "tracing")]
+#[macro_use]
+extern crate tracing;
 
 pub mod client;
+#[cfg(feature = "metrics")]
+pub mod metrics;
 pub mod server;
 pub mod util;
 
@@ -75,6 +81,37 @@ impl fmt::Display for AuthenticationMethod {
     }
 }
 
+/// SOCKS5 request command
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Command {
+    TcpConnect,
+    TcpBind,
+    UdpAssociate,
+}
+
+impl Command {
+    #[inline]
+    #[rustfmt::skip]
+    pub fn as_u8(self) -> u8 {
+        match self {
+            Command::TcpConnect   => consts::SOCKS5_CMD_TCP_CONNECT,
+            Command::TcpBind      => consts::SOCKS5_CMD_TCP_BIND,
+            Command::UdpAssociate => consts::SOCKS5_CMD_UDP_ASSOCIATE,
+        }
+    }
+
+    #[inline]
+    #[rustfmt::skip]
+    pub fn from_u8(code: u8) -> Option<Command> {
+        match code {
+            consts::SOCKS5_CMD_TCP_CONNECT   => Some(Command::TcpConnect),
+            consts::SOCKS5_CMD_TCP_BIND      => Some(Command::TcpBind),
+            consts::SOCKS5_CMD_UDP_ASSOCIATE => Some(Command::UdpAssociate),
+            _                                => None,
+        }
+    }
+}
+
 //impl Vec<AuthenticationMethod> {
 //    pub fn as_bytes(&self) -> &[u8] {
 //        self.iter().map(|l| l.as_u8()).collect()
@@ -108,6 +145,16 @@ pub enum SocksError {
     AuthenticationFailed(String),
     #[error("Authentication rejected `{0}`")]
     AuthenticationRejected(String),
+    #[error("Handshake not completed within {0:?}")]
+    HandshakeTimeout(std::time::Duration),
+    #[error("Limit of {0} reached")]
+    ConnectionLimitReached(String),
+    #[error("Server shutting down")]
+    ShuttingDown,
+    #[error("Session terminated")]
+    SessionTerminated,
+    #[error("Invalid PROXY protocol header: {0}")]
+    InvalidProxyHeader(String),
 
     #[error("Error with reply: {0}.")]
     ReplyError(#[from] ReplyError),
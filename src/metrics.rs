@@ -0,0 +1,377 @@
+//! Prometheus metrics of the server and the client, with the `metrics` feature.
+//!
+//! The same [`Metrics`] can be shared by servers (as a session observer) and clients, and
+//! rendered in the Prometheus text format, or served over HTTP.
+//!
+//! The bytes relayed by a session are counted once it's over: a long session doesn't show in
+//! `socks5_server_bytes_relayed_total` while it runs, see `server::registry` for the live
+//! counters.
+//!
+//! ```
+//! use fast_socks5::metrics::Metrics;
+//! use fast_socks5::{client, server};
+//!
+//! let metrics = Metrics::new();
+//!
+//! let mut config = server::Config::default();
+//! config.add_observer(metrics.clone());
+//!
+//! let mut client_config = client::Config::default();
+//! client_config.set_metrics(metrics.clone());
+//!
+//! // eg. in a `/metrics` handler, or `metrics.serve("127.0.0.1:9090")`
+//! println!("{}", metrics.render());
+//! ```
+use crate::server::observer::{AuthFailure, SessionContext, SessionObserver};
+use crate::server::stats::SessionStats;
+use crate::util::target_addr::TargetAddr;
+use crate::{Command, ReplyError, Result, SocksError};
+use async_std::io;
+use async_std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use async_std::task;
+use futures::{AsyncReadExt, AsyncWriteExt, StreamExt};
+use prometheus::{
+    exponential_buckets, Encoder, Histogram, HistogramOpts, IntCounterVec, IntGauge, Opts,
+    Registry, TextEncoder,
+};
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+/// Size limit of the HTTP requests of `serve()`
+const MAX_REQUEST_LEN: usize = 8 * 1024;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Label of a reply code, `None` being a success.
+fn reply_label(reply: Option<ReplyError>) -> &'static str {
+    match reply {
+        None => "succeeded",
+        Some(ReplyError::GeneralFailure) => "general_failure",
+        Some(ReplyError::ConnectionNotAllowed) => "connection_not_allowed",
+        Some(ReplyError::NetworkUnreachable) => "network_unreachable",
+        Some(ReplyError::HostUnreachable) => "host_unreachable",
+        Some(ReplyError::ConnectionRefused) => "connection_refused",
+        Some(ReplyError::TtlExpired) => "ttl_expired",
+        Some(ReplyError::CommandNotSupported) => "command_not_supported",
+        Some(ReplyError::AddressTypeNotSupported) => "address_type_not_supported",
+    }
+}
+
+fn auth_failure_label(reason: AuthFailure) -> &'static str {
+    match reason {
+        AuthFailure::Malformed => "malformed",
+        AuthFailure::InvalidCredentials => "invalid_credentials",
+        AuthFailure::LockedOut => "locked_out",
+        AuthFailure::Error => "error",
+    }
+}
+
+/// Id of the next `Metrics`, its clones share it
+static NEXT_METRICS_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Set on the sessions, ids of the `Metrics` which counted their handshake
+#[derive(Default)]
+struct HandshakeCounted(Vec<u64>);
+
+/// Every metric, cheap to clone.
+#[derive(Clone)]
+pub struct Metrics {
+    id: u64,
+    registry: Registry,
+    server_handshakes: IntCounterVec,
+    server_auth_failures: IntCounterVec,
+    server_replies: IntCounterVec,
+    server_active_sessions: IntGauge,
+    server_bytes_relayed: IntCounterVec,
+    server_connect_latency: Histogram,
+    server_session_duration: Histogram,
+    client_handshakes: IntCounterVec,
+    client_replies: IntCounterVec,
+    client_connect_latency: Histogram,
+}
+
+impl Metrics {
+    /// Metrics in a registry of their own.
+    pub fn new() -> Self {
+        Metrics::with_registry(Registry::new()).expect("Metrics registered twice")
+    }
+
+    /// Metrics registered in an existing registry, eg. the one of the application.
+    pub fn with_registry(registry: Registry) -> prometheus::Result<Self> {
+        let counter = |name: &str, help: &str, label: &str| -> prometheus::Result<IntCounterVec> {
+            let counter = IntCounterVec::new(Opts::new(name, help), &[label])?;
+            registry.register(Box::new(counter.clone()))?;
+            Ok(counter)
+        };
+        let histogram = |name: &str, help: &str, buckets: Vec<f64>| {
+            let histogram = Histogram::with_opts(HistogramOpts::new(name, help).buckets(buckets))?;
+            registry.register(Box::new(histogram.clone()))?;
+            Ok::<_, prometheus::Error>(histogram)
+        };
+        let latency_buckets = exponential_buckets(0.001, 2.0, 15)?;
+
+        let server_active_sessions =
+            IntGauge::new("socks5_server_active_sessions", "Sessions running.")?;
+        registry.register(Box::new(server_active_sessions.clone()))?;
+
+        Ok(Metrics {
+            id: NEXT_METRICS_ID.fetch_add(1, Ordering::Relaxed),
+            server_handshakes: counter(
+                "socks5_server_handshakes_total",
+                "Handshakes by outcome: success, auth_failure or error.",
+                "outcome",
+            )?,
+            server_auth_failures: counter(
+                "socks5_server_auth_failures_total",
+                "Authentication failures by reason.",
+                "reason",
+            )?,
+            server_replies: counter(
+                "socks5_server_replies_total",
+                "Replies to the requests by code.",
+                "code",
+            )?,
+            server_active_sessions,
+            server_bytes_relayed: counter(
+                "socks5_server_bytes_relayed_total",
+                "Bytes relayed by direction (upload: client to target), counted once the session closed.",
+                "direction",
+            )?,
+            server_connect_latency: histogram(
+                "socks5_server_connect_latency_seconds",
+                "Time to connect to the targets.",
+                latency_buckets.clone(),
+            )?,
+            server_session_duration: histogram(
+                "socks5_server_session_duration_seconds",
+                "Duration of the sessions, handshake included.",
+                exponential_buckets(0.1, 4.0, 9)?,
+            )?,
+            client_handshakes: counter(
+                "socks5_client_handshakes_total",
+                "Handshakes with the proxies by outcome: success, auth_failure or error.",
+                "outcome",
+            )?,
+            client_replies: counter(
+                "socks5_client_replies_total",
+                "Replies of the proxies to the requests by code.",
+                "code",
+            )?,
+            client_connect_latency: histogram(
+                "socks5_client_connect_latency_seconds",
+                "Time for the proxies to connect to the targets.",
+                latency_buckets,
+            )?,
+            registry,
+        })
+    }
+
+    pub fn registry(&self) -> &Registry {
+        &self.registry
+    }
+
+    /// Every metric of the registry, in the Prometheus text format.
+    pub fn render(&self) -> String {
+        let mut buffer = Vec::new();
+        if let Err(e) = TextEncoder::new().encode(&self.registry.gather(), &mut buffer) {
+            error!("Can't encode the metrics: {}", e);
+        }
+        String::from_utf8_lossy(&buffer).into_owned()
+    }
+
+    /// Serve `render()` over HTTP, until an error happens while accepting.
+    pub async fn serve<A: ToSocketAddrs>(&self, addr: A) -> io::Result<()> {
+        let listener = TcpListener::bind(addr).await?;
+        info!("Serving the metrics on {}", listener.local_addr()?);
+
+        let mut incoming = listener.incoming();
+        while let Some(stream) = incoming.next().await {
+            let metrics = self.clone();
+            let stream = stream?;
+            task::spawn(async move {
+                if let Err(e) = metrics.respond(stream).await {
+                    debug!("Can't serve the metrics: {}", e);
+                }
+            });
+        }
+        Ok(())
+    }
+
+    async fn respond(&self, mut stream: TcpStream) -> io::Result<()> {
+        let mut request = Vec::new();
+        let mut buffer = [0u8; 1024];
+        while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+            let n = io::timeout(REQUEST_TIMEOUT, stream.read(&mut buffer)).await?;
+            if n == 0 || request.len() + n > MAX_REQUEST_LEN {
+                return Ok(());
+            }
+            request.extend_from_slice(&buffer[..n]);
+        }
+
+        let request_line = String::from_utf8_lossy(&request);
+        let mut parts = request_line.split_whitespace();
+        let (status, content_type, body) = match (parts.next(), parts.next()) {
+            (Some("GET"), Some("/metrics")) | (Some("GET"), Some("/")) => (
+                "200 OK",
+                TextEncoder::new().format_type().to_string(),
+                self.render(),
+            ),
+            _ => ("404 Not Found", "text/plain".to_string(), String::new()),
+        };
+
+        let response = format!(
+            "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            status,
+            content_type,
+            body.len(),
+            body
+        );
+        stream.write_all(response.as_bytes()).await?;
+        stream.flush().await
+    }
+
+    fn count_server_handshake(&self, session: &mut SessionContext, outcome: &str) {
+        if session.get::<HandshakeCounted>().is_none() {
+            session.insert(HandshakeCounted::default());
+        }
+        let counted = session.get_mut::<HandshakeCounted>().unwrap();
+        if !counted.0.contains(&self.id) {
+            counted.0.push(self.id);
+            self.server_handshakes.with_label_values(&[outcome]).inc();
+        }
+    }
+
+    /// Outcome of a client handshake.
+    pub(crate) fn record_client_handshake<T>(&self, handshake: &Result<T>) {
+        let outcome = match handshake {
+            Ok(_) => "success",
+            Err(SocksError::AuthenticationRejected(_))
+            | Err(SocksError::AuthenticationFailed(_)) => "auth_failure",
+            Err(_) => "error",
+        };
+        self.client_handshakes.with_label_values(&[outcome]).inc();
+    }
+
+    /// Reply received by a client, `started` when the request was sent.
+    pub(crate) fn record_client_reply<T>(&self, reply: &Result<T>, started: Instant) {
+        match reply {
+            Ok(_) => {
+                self.client_replies
+                    .with_label_values(&[reply_label(None)])
+                    .inc();
+                self.client_connect_latency
+                    .observe(started.elapsed().as_secs_f64());
+            }
+            Err(SocksError::ReplyError(e)) => {
+                self.client_replies
+                    .with_label_values(&[reply_label(Some(*e))])
+                    .inc();
+            }
+            Err(_) => {}
+        }
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Metrics::new()
+    }
+}
+
+impl fmt::Debug for Metrics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Metrics").finish_non_exhaustive()
+    }
+}
+
+impl SessionObserver for Metrics {
+    fn on_accept(&self, _session: &mut SessionContext) {
+        self.server_active_sessions.inc();
+    }
+
+    fn on_auth_failure(
+        &self,
+        session: &mut SessionContext,
+        _username: Option<&str>,
+        reason: AuthFailure,
+    ) {
+        self.server_auth_failures
+            .with_label_values(&[auth_failure_label(reason)])
+            .inc();
+        self.count_server_handshake(session, "auth_failure");
+    }
+
+    fn on_request(&self, session: &mut SessionContext, _cmd: Command, _target: &TargetAddr) {
+        self.count_server_handshake(session, "success");
+    }
+
+    fn on_connected(&self, _session: &mut SessionContext, _addr: SocketAddr, latency: Duration) {
+        self.server_replies
+            .with_label_values(&[reply_label(None)])
+            .inc();
+        self.server_connect_latency.observe(latency.as_secs_f64());
+    }
+
+    fn on_connect_failed(&self, _session: &mut SessionContext, reply: ReplyError) {
+        self.server_replies
+            .with_label_values(&[reply_label(Some(reply))])
+            .inc();
+    }
+
+    fn on_close(&self, session: &mut SessionContext, stats: &SessionStats) {
+        self.count_server_handshake(session, "error");
+        self.server_active_sessions.dec();
+        self.server_bytes_relayed
+            .with_label_values(&["upload"])
+            .inc_by(stats.bytes_sent);
+        self.server_bytes_relayed
+            .with_label_values(&["download"])
+            .inc_by(stats.bytes_received);
+        self.server_session_duration
+            .observe(stats.duration.as_secs_f64());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Metrics;
+    use crate::server::observer::{AuthFailure, SessionContext, SessionObserver};
+    use crate::util::target_addr::TargetAddr;
+    use crate::{Command, ReplyError};
+
+    #[test]
+    fn test_render() {
+        let metrics = Metrics::new();
+        let mut session = SessionContext::new(1, None);
+        metrics.on_accept(&mut session);
+        metrics.on_auth_failure(&mut session, Some("bob"), AuthFailure::InvalidCredentials);
+        metrics.on_connect_failed(&mut session, ReplyError::ConnectionRefused);
+
+        let rendered = metrics.render();
+        assert!(rendered.contains("socks5_server_active_sessions 1"));
+        assert!(rendered
+            .contains("socks5_server_auth_failures_total{reason=\"invalid_credentials\"} 1"));
+        assert!(rendered.contains("socks5_server_handshakes_total{outcome=\"auth_failure\"} 1"));
+        assert!(rendered.contains("socks5_server_replies_total{code=\"connection_refused\"} 1"));
+    }
+
+    #[test]
+    fn test_several_metrics() {
+        let first = Metrics::new();
+        let second = Metrics::new();
+        let mut session = SessionContext::new(1, None);
+        let target = TargetAddr::Ip("127.0.0.1:80".parse().unwrap());
+        for metrics in [&first, &first.clone(), &second] {
+            metrics.on_request(&mut session, Command::TcpConnect, &target);
+            metrics.on_auth_failure(&mut session, None, AuthFailure::Malformed);
+        }
+
+        // Once per instance, the clones share theirs
+        let rendered = first.render();
+        assert!(rendered.contains("socks5_server_handshakes_total{outcome=\"success\"} 1"));
+        assert!(!rendered.contains("outcome=\"auth_failure\""));
+        let rendered = second.render();
+        assert!(rendered.contains("socks5_server_handshakes_total{outcome=\"success\"} 1"));
+    }
+}
@@ -1,44 +1,135 @@
-use crate::read_exact;
-use crate::util::target_addr::{read_address, TargetAddr};
-use crate::{consts, AuthenticationMethod, ReplyError, Result, SocksError};
+pub mod access_log;
+pub mod acl;
+#[cfg(unix)]
+pub mod activation;
+pub mod brute_force;
+#[cfg(feature = "htpasswd")]
+pub mod htpasswd;
+pub mod limits;
+pub mod observer;
+pub mod proxy_protocol;
+pub mod quota;
+pub mod registry;
+pub mod reload;
+pub mod shutdown;
+pub mod ssrf;
+pub mod stats;
+pub mod throttle;
+pub mod unix;
+
+use crate::read_exact_timeout;
+use crate::server::acl::{AccessControl, AclRequest, Action};
+use crate::server::brute_force::BruteForceProtection;
+use crate::server::limits::{
+    ConnectionLimits, ConnectionSlot, LimitExceeded, OverLimitAction, UserSlot,
+};
+use crate::server::observer::{AuthFailure, SessionContext, SessionObserver};
+use crate::server::proxy_protocol::ProxyProtocol;
+use crate::server::quota::{Quotas, SessionQuota};
+use crate::server::registry::{Registration, SessionId, SessionRegistry};
+use crate::server::reload::ConfigHandle;
+use crate::server::shutdown::{SessionGuard, ShutdownHandle};
+use crate::server::ssrf::SsrfProtection;
+use crate::server::stats::{SessionStats, Traffic};
+use crate::server::throttle::{BandwidthLimiter, Direction, SessionThrottle};
+use crate::server::unix::PeerCredentials;
+#[cfg(unix)]
+use crate::server::unix::{peer_credentials, BoundUnixListener, UnixListenerOptions};
+use crate::util::happy_eyeballs;
+use crate::util::signal::Signal;
+use crate::util::span::Span;
+use crate::util::stream::{ClientStream, HalfClose, HalfCloseFns, ShutdownOnClose};
+use crate::util::target_addr::{read_address_timeout, TargetAddr};
+use crate::{consts, AuthenticationMethod, Command, ReplyError, Result, SocksError};
 use anyhow::Context;
 use async_std::{
     future,
     net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs as AsyncToSocketAddrs},
     sync::Arc,
-    task::ready,
+    task,
     task::{Context as AsyncContext, Poll},
 };
 use futures::{
-    future::{Either, Future},
+    future::{Either, Fuse, Future, FutureExt},
     stream::Stream,
     AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt,
 };
+use std::any::Any;
+use std::fmt;
 use std::io;
 use std::net::ToSocketAddrs as StdToSocketAddrs;
+#[cfg(unix)]
+use std::os::unix::io::{AsFd, BorrowedFd, OwnedFd};
+#[cfg(unix)]
+use std::path::{Path, PathBuf};
 use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+pub use async_trait::async_trait;
 
 #[derive(Clone)]
 pub struct Config {
     /// Timeout of the command request
     request_timeout: u64,
+    /// Deadline for the whole handshake, from accept to request parsed
+    handshake_timeout: Option<Duration>,
+    /// Timeout of each read during the handshake
+    read_timeout: Option<Duration>,
+    /// Close the sessions without any traffic for this long
+    idle_timeout: Option<Duration>,
+    /// Close the sessions lasting longer than this
+    max_session_duration: Option<Duration>,
+    /// How long the remaining direction of a half-closed session is kept open
+    linger_timeout: Option<Duration>,
     /// Avoid useless roundtrips if we don't need the Authentication layer
     skip_auth: bool,
     /// Enable dns-resolving
     dns_resolve: bool,
     /// Enable command execution
     execute_command: bool,
-    auth: Option<Arc<dyn Authentication>>,
+    /// Delay between two connection attempts when the target resolves to several addresses
+    connection_attempt_delay: Duration,
+    auth: Option<Arc<dyn AsyncAuthentication>>,
+    brute_force_protection: Option<Arc<BruteForceProtection>>,
+    acl: Option<Arc<AccessControl>>,
+    ssrf_protection: Option<Arc<SsrfProtection>>,
+    bandwidth_limiter: Option<Arc<BandwidthLimiter>>,
+    connection_limits: Option<Arc<ConnectionLimits>>,
+    quotas: Option<Arc<Quotas>>,
+    /// Peers allowed to send a PROXY protocol header
+    proxy_protocol: Option<Arc<ProxyProtocol>>,
+    session_registry: Option<Arc<SessionRegistry>>,
+    on_session_end: Option<SessionEndCallback>,
+    observers: Vec<Arc<dyn SessionObserver>>,
 }
 
+type SessionEndCallback = Arc<dyn Fn(&SessionStats) + Send + Sync>;
+
 impl Default for Config {
     fn default() -> Self {
         Config {
             request_timeout: 10,
+            handshake_timeout: None,
+            read_timeout: None,
+            idle_timeout: None,
+            max_session_duration: None,
+            linger_timeout: Some(Duration::from_secs(30)),
             skip_auth: false,
             dns_resolve: true,
             execute_command: true,
+            connection_attempt_delay: happy_eyeballs::DEFAULT_ATTEMPT_DELAY,
             auth: None,
+            brute_force_protection: None,
+            acl: None,
+            ssrf_protection: None,
+            bandwidth_limiter: None,
+            connection_limits: None,
+            quotas: None,
+            proxy_protocol: None,
+            session_registry: None,
+            on_session_end: None,
+            observers: Vec::new(),
         }
     }
 }
@@ -60,6 +151,100 @@ impl Authentication for SimpleUserPassword {
     }
 }
 
+/// Credentials sent by the client, along with what is known about it.
+#[derive(Debug, Clone)]
+pub struct AuthRequest<'a> {
+    pub username: &'a str,
+    pub password: &'a str,
+    pub peer_addr: Option<SocketAddr>,
+    /// Credentials of the process of the client connected through a Unix socket.
+    pub peer_credentials: Option<PeerCredentials>,
+}
+
+/// Asynchronous version of [`Authentication`], for backends which have to do I/O, like a database
+/// or a remote service. Instead of a boolean, it returns the identity of the user, which is stored
+/// on the [`Socks5Socket`] for the rest of the session.
+///
+/// Every [`Authentication`] implements this trait.
+///
+/// ```
+/// use fast_socks5::server::{async_trait, AsyncAuthentication, AuthRequest, Identity};
+///
+/// struct Backend;
+///
+/// #[async_trait]
+/// impl AsyncAuthentication for Backend {
+///     async fn authenticate(&self, request: AuthRequest<'_>) -> fast_socks5::Result<Option<Identity>> {
+///         // eg. query the database
+///         if request.password == "secret" {
+///             let mut identity = Identity::new(request.username);
+///             identity.groups.push("staff".to_string());
+///             Ok(Some(identity))
+///         } else {
+///             Ok(None)
+///         }
+///     }
+/// }
+/// ```
+#[async_trait]
+pub trait AsyncAuthentication: Send + Sync {
+    /// Returns `Ok(None)` if the credentials are rejected. An error means the authentication
+    /// couldn't be performed, the client is rejected as well.
+    async fn authenticate(&self, request: AuthRequest<'_>) -> Result<Option<Identity>>;
+}
+
+#[async_trait]
+impl<T: Authentication + ?Sized> AsyncAuthentication for T {
+    async fn authenticate(&self, request: AuthRequest<'_>) -> Result<Option<Identity>> {
+        if Authentication::authenticate(self, request.username, request.password) {
+            Ok(Some(Identity::new(request.username)))
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+/// The authenticated user, as returned by [`AsyncAuthentication`].
+#[derive(Clone, Default)]
+pub struct Identity {
+    pub username: String,
+    /// Identifier of the user in the backend, if it differs from the username.
+    pub user_id: Option<String>,
+    pub groups: Vec<String>,
+    /// Anything else the application needs during the session (limits, plan, ...).
+    data: Option<Arc<dyn Any + Send + Sync>>,
+}
+
+impl Identity {
+    pub fn new<S: Into<String>>(username: S) -> Self {
+        Identity {
+            username: username.into(),
+            ..Default::default()
+        }
+    }
+
+    /// Attach application-defined data, retrieved with `data::<T>()`.
+    pub fn set_data<T: Any + Send + Sync>(&mut self, data: T) -> &mut Self {
+        self.data = Some(Arc::new(data));
+        self
+    }
+
+    pub fn data<T: Any + Send + Sync>(&self) -> Option<&T> {
+        self.data.as_ref()?.downcast_ref()
+    }
+}
+
+impl fmt::Debug for Identity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Identity")
+            .field("username", &self.username)
+            .field("user_id", &self.user_id)
+            .field("groups", &self.groups)
+            .field("data", &self.data.is_some())
+            .finish()
+    }
+}
+
 impl Config {
     /// How much time it should wait until the request timeout.
     pub fn set_request_timeout(&mut self, n: u64) -> &mut Self {
@@ -67,6 +252,61 @@ impl Config {
         self
     }
 
+    /// Deadline for the whole handshake: from the moment the connection is accepted, the client
+    /// has this much time to negotiate the method, authenticate and send its request.
+    /// Protects from clients keeping connections open by sending bytes very slowly (slowloris).
+    /// Disabled by default.
+    pub fn set_handshake_timeout(&mut self, timeout: Option<Duration>) -> &mut Self {
+        self.handshake_timeout = timeout;
+        self
+    }
+
+    /// How long to wait for each message of the client during the handshake.
+    /// Disabled by default.
+    pub fn set_read_timeout(&mut self, timeout: Option<Duration>) -> &mut Self {
+        self.read_timeout = timeout;
+        self
+    }
+
+    /// Close the relayed sessions once no byte has been transferred, in either direction,
+    /// for this long. Waiting for the bandwidth limiter doesn't count as idle. Disabled by
+    /// default.
+    pub fn set_idle_timeout(&mut self, timeout: Option<Duration>) -> &mut Self {
+        self.idle_timeout = timeout;
+        self
+    }
+
+    /// Close the relayed sessions after this long, whatever their activity.
+    /// Disabled by default.
+    pub fn set_max_session_duration(&mut self, duration: Option<Duration>) -> &mut Self {
+        self.max_session_duration = duration;
+        self
+    }
+
+    /// Once a side of a relayed session closed its connection, how long the other direction
+    /// is kept open to finish its transfer. `None` waits forever (or for the idle timeout).
+    /// Default is 30 seconds.
+    pub fn set_linger_timeout(&mut self, timeout: Option<Duration>) -> &mut Self {
+        self.linger_timeout = timeout;
+        self
+    }
+
+    /// Called with the statistics of every session, once `Socks5Socket::upgrade_to_socks5()` is
+    /// done with it, successfully or not.
+    pub fn set_session_end_callback<F>(&mut self, callback: F) -> &mut Self
+    where
+        F: Fn(&SessionStats) + Send + Sync + 'static,
+    {
+        self.on_session_end = Some(Arc::new(callback));
+        self
+    }
+
+    /// Notify this observer of the steps of every session, after the ones already added.
+    pub fn add_observer<O: SessionObserver + 'static>(&mut self, observer: O) -> &mut Self {
+        self.observers.push(Arc::new(observer));
+        self
+    }
+
     /// Skip the entire auth/handshake part, which means the server will directly wait for
     /// the command request.
     pub fn set_skip_auth(&mut self, value: bool) -> &mut Self {
@@ -74,10 +314,10 @@ impl Config {
         self
     }
 
-    /// Enable authentication
+    /// Enable authentication, with either an [`Authentication`] or an [`AsyncAuthentication`].
     /// 'static lifetime for Authentication avoid us to use `dyn Authentication`
     /// and set the Arc before calling the function.
-    pub fn set_authentication<T: Authentication + 'static>(
+    pub fn set_authentication<T: AsyncAuthentication + 'static>(
         &mut self,
         authentication: T,
     ) -> &mut Self {
@@ -85,6 +325,16 @@ impl Config {
         self
     }
 
+    /// Throttle the failed authentications and lock out the IPs and usernames guessing
+    /// passwords. Pass an `Arc` to keep a handle on it, eg. to list or lift the lockouts.
+    pub fn set_brute_force_protection<P: Into<Arc<BruteForceProtection>>>(
+        &mut self,
+        protection: P,
+    ) -> &mut Self {
+        self.brute_force_protection = Some(protection.into());
+        self
+    }
+
     /// Set whether or not to execute commands
     pub fn set_execute_command(&mut self, value: bool) -> &mut Self {
         self.execute_command = value;
@@ -96,97 +346,632 @@ impl Config {
         self.dns_resolve = value;
         self
     }
+
+    /// When a domain resolves to several addresses, how long to wait for a connection attempt
+    /// before racing it with the next address (Happy Eyeballs, RFC 8305). Default is 250ms.
+    pub fn set_connection_attempt_delay(&mut self, delay: Duration) -> &mut Self {
+        self.connection_attempt_delay = delay;
+        self
+    }
+
+    /// Filter the requests with a set of rules, evaluated right after the request has been read.
+    /// Denied requests are replied with `ConnectionNotAllowed`.
+    pub fn set_access_control(&mut self, acl: AccessControl) -> &mut Self {
+        self.acl = Some(Arc::new(acl));
+        self
+    }
+
+    /// Refuse to connect to internal networks. The addresses are checked right before
+    /// connecting, after the DNS resolution. Denied requests are replied with
+    /// `ConnectionNotAllowed`.
+    pub fn set_ssrf_protection(&mut self, protection: SsrfProtection) -> &mut Self {
+        self.ssrf_protection = Some(Arc::new(protection));
+        self
+    }
+
+    /// Throttle the relayed sessions. Keep a handle on the limiter (pass an `Arc`) to change
+    /// the limits while the server runs.
+    pub fn set_bandwidth_limiter<L: Into<Arc<BandwidthLimiter>>>(
+        &mut self,
+        limiter: L,
+    ) -> &mut Self {
+        self.bandwidth_limiter = Some(limiter.into());
+        self
+    }
+
+    /// Cap the number of concurrent sessions. Keep a handle on the limits (pass an `Arc`)
+    /// to query the current counts.
+    pub fn set_connection_limits<L: Into<Arc<ConnectionLimits>>>(
+        &mut self,
+        limits: L,
+    ) -> &mut Self {
+        self.connection_limits = Some(limits.into());
+        self
+    }
+
+    /// Enforce traffic quotas on the authenticated users. Keep a handle on the quotas (pass an
+    /// `Arc`) to query the usage of the users.
+    pub fn set_quotas<Q: Into<Arc<Quotas>>>(&mut self, quotas: Q) -> &mut Self {
+        self.quotas = Some(quotas.into());
+        self
+    }
+
+    /// Read the address of the clients from the PROXY protocol header sent by the trusted load
+    /// balancers, before the handshake.
+    pub fn set_proxy_protocol(&mut self, proxy_protocol: ProxyProtocol) -> &mut Self {
+        self.proxy_protocol = Some(Arc::new(proxy_protocol));
+        self
+    }
+
+    /// Keep track of the running sessions. Keep a handle on the registry (pass an `Arc`) to
+    /// list and terminate them.
+    pub fn set_session_registry<R: Into<Arc<SessionRegistry>>>(
+        &mut self,
+        registry: R,
+    ) -> &mut Self {
+        self.session_registry = Some(registry.into());
+        self
+    }
+}
+
+/// A listener of the server, with its own config.
+struct Listener {
+    acceptor: Acceptor,
+    label: Arc<str>,
+    config: ConfigHandle,
+}
+
+enum Acceptor {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(BoundUnixListener),
+}
+
+/// A client accepted, with its address if it connected through TCP, or its credentials if it
+/// connected through a Unix socket.
+type Accepted = (ClientStream, Option<SocketAddr>, Option<PeerCredentials>);
+
+impl Acceptor {
+    async fn accept(&self) -> io::Result<Accepted> {
+        match self {
+            Acceptor::Tcp(listener) => {
+                let (stream, peer_addr) = listener.accept().await?;
+                Ok((stream.into(), Some(peer_addr), None))
+            }
+            #[cfg(unix)]
+            Acceptor::Unix(bound) => {
+                let (stream, _) = bound.listener.accept().await?;
+                let credentials = peer_credentials(&stream).unwrap_or_else(|e| {
+                    warn!("Can't read the credentials of the client: {}", e);
+                    None
+                });
+                Ok((stream.into(), None, credentials))
+            }
+        }
+    }
+
+    #[cfg(unix)]
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        match self {
+            Acceptor::Tcp(listener) => listener.as_fd(),
+            Acceptor::Unix(bound) => bound.listener.as_fd(),
+        }
+    }
+
+    fn local_addr(&self) -> io::Result<ListenerAddr> {
+        match self {
+            Acceptor::Tcp(listener) => listener.local_addr().map(ListenerAddr::Tcp),
+            #[cfg(unix)]
+            Acceptor::Unix(bound) => bound.path().map(ListenerAddr::Unix),
+        }
+    }
+}
+
+/// Address a listener accepts the clients on.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ListenerAddr {
+    Tcp(SocketAddr),
+    /// Path of the socket file
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+impl fmt::Display for ListenerAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ListenerAddr::Tcp(addr) => addr.fmt(f),
+            #[cfg(unix)]
+            ListenerAddr::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
 }
 
-/// Wrapper of TcpListener
+/// Wrapper of TcpListener and UnixListener
 /// Useful if you don't use any existing TcpListener's streams.
+///
+/// A server can accept the clients of several listeners, each with its own config and a label
+/// telling them apart in the logs and the observers:
+///
+/// ```no_run
+/// # use fast_socks5::server::{Config, SimpleUserPassword, Socks5Server};
+/// # async fn run() -> std::io::Result<()> {
+/// let mut server = Socks5Server::new();
+/// server
+///     .add_listener("local", "127.0.0.1:1080", Config::default())
+///     .await?;
+///
+/// let mut config = Config::default();
+/// config.set_authentication(SimpleUserPassword {
+///     username: "admin".to_string(),
+///     password: "password".to_string(),
+/// });
+/// let public = server.add_listener("public", "0.0.0.0:1081", config).await?;
+/// // `public.replace(...)` to change the config of this listener only
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Default)]
 pub struct Socks5Server {
-    listener: TcpListener,
-    config: Arc<Config>,
+    listeners: Vec<Listener>,
+    /// Config of the listeners bound with `bind()`
+    config: ConfigHandle,
+    shutdown: ShutdownHandle,
 }
 
 impl Socks5Server {
+    /// A server without any listener, see `add_listener()`.
+    pub fn new() -> Self {
+        Socks5Server::default()
+    }
+
+    /// A server with a single listener, labelled with its address, using the config set with
+    /// `set_config()`.
     pub async fn bind<A: AsyncToSocketAddrs>(addr: A) -> io::Result<Socks5Server> {
         let listener = TcpListener::bind(&addr).await?;
-        let config = Arc::new(Config::default());
+        let mut server = Socks5Server::new();
+        server.listeners.push(Listener {
+            label: listener.local_addr()?.to_string().into(),
+            acceptor: Acceptor::Tcp(listener),
+            config: server.config.clone(),
+        });
+        Ok(server)
+    }
+
+    /// Accept the clients of another address with this config. Returns the handle to replace
+    /// the config of this listener.
+    pub async fn add_listener<S, A>(
+        &mut self,
+        label: S,
+        addr: A,
+        config: Config,
+    ) -> io::Result<ConfigHandle>
+    where
+        S: Into<String>,
+        A: AsyncToSocketAddrs,
+    {
+        let listener = TcpListener::bind(&addr).await?;
+        Ok(self.push_listener(label.into(), Acceptor::Tcp(listener), config))
+    }
+
+    /// Accept the clients connecting through a Unix socket with this config. Their peer
+    /// credentials are available to the authentication and the access control rules.
+    #[cfg(unix)]
+    pub async fn add_unix_listener<S, P>(
+        &mut self,
+        label: S,
+        path: P,
+        options: &UnixListenerOptions,
+        config: Config,
+    ) -> io::Result<ConfigHandle>
+    where
+        S: Into<String>,
+        P: AsRef<Path>,
+    {
+        let bound = options.bind(path.as_ref()).await?;
+        Ok(self.push_listener(label.into(), Acceptor::Unix(bound), config))
+    }
+
+    /// Accept the clients of a listening socket inherited from the parent process, instead of
+    /// binding one: see [`activation::listen_fds()`] for the sockets passed by systemd. Either a
+    /// TCP or a Unix socket, its file is left to the parent process.
+    #[cfg(unix)]
+    pub fn add_inherited_listener<S: Into<String>>(
+        &mut self,
+        label: S,
+        fd: OwnedFd,
+        config: Config,
+    ) -> io::Result<ConfigHandle> {
+        let acceptor = activation::acceptor(fd)?;
+        Ok(self.push_listener(label.into(), acceptor, config))
+    }
 
-        Ok(Socks5Server { listener, config })
+    fn push_listener(&mut self, label: String, acceptor: Acceptor, config: Config) -> ConfigHandle {
+        let config = ConfigHandle::new(config);
+        self.listeners.push(Listener {
+            acceptor,
+            label: label.into(),
+            config: config.clone(),
+        });
+        config
     }
 
-    /// Set a custom config
+    /// Label and address of each listener.
+    pub fn local_addrs(&self) -> io::Result<Vec<(&str, ListenerAddr)>> {
+        self.listeners
+            .iter()
+            .map(|listener| Ok((listener.label.as_ref(), listener.acceptor.local_addr()?)))
+            .collect()
+    }
+
+    /// Label and socket of each listener, eg. to pass them to the process replacing this one
+    /// without closing them.
+    #[cfg(unix)]
+    pub fn listener_fds(&self) -> Vec<(&str, BorrowedFd<'_>)> {
+        self.listeners
+            .iter()
+            .map(|listener| (listener.label.as_ref(), listener.acceptor.as_fd()))
+            .collect()
+    }
+
+    /// Handle to stop the server gracefully: `incoming()` ends, and the sessions it yielded are
+    /// given some time to end. Shared by all the listeners.
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    /// Set a custom config for the listeners bound with `bind()`, used by the clients accepted
+    /// from now on.
     pub fn set_config(&mut self, config: Config) {
-        self.config = Arc::new(config);
+        self.config.replace(config);
+    }
+
+    /// Handle to replace the config of the listeners bound with `bind()` while the server runs,
+    /// eg. on SIGHUP. The running sessions keep the config they were accepted with.
+    pub fn config_handle(&self) -> ConfigHandle {
+        self.config.clone()
     }
 
-    /// Can loop on `incoming().next()` to iterate over incoming connections.
+    /// Can loop on `incoming().next()` to iterate over incoming connections, of every listener.
     pub fn incoming(&self) -> Incoming<'_> {
-        Incoming(self, None)
+        Incoming {
+            server: self,
+            accepts: self.listeners.iter().map(|_| None).collect(),
+            stopping: Box::pin(self.shutdown.stopping()),
+            next: 0,
+        }
     }
 }
 
+type Accept<'a> = Pin<Box<dyn Future<Output = io::Result<Accepted>> + Send + Sync + 'a>>;
+
 /// `Incoming` implements [`futures::stream::Stream`].
-pub struct Incoming<'a>(
-    &'a Socks5Server,
-    Option<Pin<Box<dyn Future<Output = io::Result<(TcpStream, SocketAddr)>> + Send + Sync + 'a>>>,
-);
+pub struct Incoming<'a> {
+    server: &'a Socks5Server,
+    /// Accepts the next client of each listener
+    accepts: Vec<Option<Accept<'a>>>,
+    /// Resolves once the server is shutting down
+    stopping: Pin<Box<dyn Future<Output = ()> + Send + Sync + 'a>>,
+    /// Listener polled first, so that a busy listener doesn't starve the others
+    next: usize,
+}
 
 /// Iterator for each incoming stream connection
-/// this wrapper will convert async_std TcpStream into Socks5Socket.
+/// this wrapper will convert the accepted streams into Socks5Socket.
 impl<'a> Stream for Incoming<'a> {
-    type Item = Result<Socks5Socket<TcpStream>>;
+    type Item = Result<Socks5Socket<ClientStream>>;
 
     /// this code is mainly borrowed from [`Incoming::poll_next()` of `TcpListener`][tcpListener]
     /// [tcpListener]: https://docs.rs/async-std/1.8.0/async_std/net/struct.TcpListener.html#method.incoming
     fn poll_next(mut self: Pin<&mut Self>, cx: &mut AsyncContext<'_>) -> Poll<Option<Self::Item>> {
-        loop {
-            if self.1.is_none() {
-                self.1 = Some(Box::pin(self.0.listener.accept()));
-            }
+        let server = self.server;
+        if server.shutdown.is_shutting_down() || self.stopping.as_mut().poll(cx).is_ready() {
+            return Poll::Ready(None);
+        }
 
-            if let Some(f) = &mut self.1 {
-                // early returns if pending
-                let (socket, peer_addr) = ready!(f.as_mut().poll(cx))?;
-                self.1 = None;
+        let count = server.listeners.len();
+        for i in 0..count {
+            let index = (self.next + i) % count;
+            let listener = &server.listeners[index];
+            let accept =
+                self.accepts[index].get_or_insert_with(|| Box::pin(listener.acceptor.accept()));
 
-                let local_addr = socket.local_addr()?;
+            let accepted = match accept.as_mut().poll(cx) {
+                Poll::Ready(accepted) => accepted,
+                Poll::Pending => continue,
+            };
+            self.accepts[index] = None;
+            self.next = index + 1;
+            let (socket, peer_addr, credentials) = accepted?;
+
+            // Wrap the accepted stream into Socks5Socket
+            let mut socket = Socks5Socket::new(socket, listener.config.current());
+            socket
+                .set_listener(listener.label.clone())
+                .set_shutdown_handle(server.shutdown.clone())
+                .set_half_close(true);
+            if let Some(peer_addr) = peer_addr {
+                debug!(
+                    "incoming connection from peer {} ({})",
+                    peer_addr, listener.label
+                );
+                socket.set_peer_addr(peer_addr);
+            }
+            if let Some(credentials) = credentials {
                 debug!(
-                    "incoming connection from peer {} @ {}",
-                    &peer_addr, &local_addr
+                    "incoming connection from peer {} ({})",
+                    credentials, listener.label
                 );
+                socket.set_peer_credentials(credentials);
+            }
 
-                // Wrap the TcpStream into Socks5Socket
-                let socket = Socks5Socket::new(socket, self.0.config.clone());
+            return Poll::Ready(Some(Ok(socket)));
+        }
 
-                return Poll::Ready(Some(Ok(socket)));
-            }
+        if count == 0 {
+            return Poll::Ready(None);
         }
+        Poll::Pending
     }
 }
 
 /// Wrap TcpStream and contains Socks5 protocol implementation.
 pub struct Socks5Socket<T: AsyncRead + AsyncWrite + Unpin> {
     inner: T,
+    id: SessionId,
     config: Arc<Config>,
+    accepted_at: Instant,
+    peer_addr: Option<SocketAddr>,
+    /// Credentials of the client connected through a Unix socket
+    peer_credentials: Option<PeerCredentials>,
+    /// Label of the listener which accepted the client
+    listener: Option<Arc<str>>,
     auth: AuthenticationMethod,
+    identity: Option<Identity>,
+    cmd: Option<Command>,
     target_addr: Option<TargetAddr>,
+    /// Target as read from the request, before any DNS resolution
+    requested_target: Option<TargetAddr>,
+    /// Every address the target domain resolved to, filled by `resolve_dns()`
+    resolved_addrs: Vec<SocketAddr>,
+    remote_addr: Option<SocketAddr>,
+    handshake_duration: Option<Duration>,
+    connect_latency: Option<Duration>,
+    traffic: Arc<Traffic>,
+    closed_at: Option<Instant>,
+    close_reason: Option<CloseReason>,
+    /// Held while the session runs, to count it in the connection limits
+    connection_slot: Option<ConnectionSlot>,
+    user_slot: Option<UserSlot>,
+    /// Reply to the request with this error, the session being over a connection limit
+    over_limit_reply: Option<ReplyError>,
+    quota: Option<SessionQuota>,
+    shutdown: Option<ShutdownHandle>,
+    /// Counts the session as active, from the moment it's accepted
+    shutdown_guard: Option<SessionGuard>,
+    /// How the relay shuts down the write side of the client, `poll_close()` if `None`
+    half_close: Option<HalfCloseFns<T>>,
+    registration: Option<Registration>,
+    span: Span,
+    /// Data of the observers
+    context: SessionContext,
+}
+
+impl<T: AsyncRead + AsyncWrite + HalfClose + Unpin> Socks5Socket<T> {
+    /// Relay the half-closes to the client: once the target is done sending, only the write
+    /// side of the client is shut down, and the client can still send its data. Otherwise the
+    /// session ends as soon as the target closes, with `poll_close()` of the stream.
+    /// Already enabled on the sockets yielded by `Socks5Server::incoming()`.
+    pub fn set_half_close(&mut self, value: bool) -> &mut Self {
+        self.half_close = if value {
+            Some(HalfCloseFns::of())
+        } else {
+            None
+        };
+        self
+    }
 }
 
+/// Id of the next session
+static NEXT_SESSION_ID: AtomicU64 = AtomicU64::new(1);
+
 impl<T: AsyncRead + AsyncWrite + Unpin> Socks5Socket<T> {
     pub fn new(socket: T, config: Arc<Config>) -> Self {
         Socks5Socket {
             inner: socket,
+            id: NEXT_SESSION_ID.fetch_add(1, Ordering::Relaxed),
             config,
+            accepted_at: Instant::now(),
+            peer_addr: None,
+            peer_credentials: None,
+            listener: None,
             auth: AuthenticationMethod::None,
+            identity: None,
+            cmd: None,
             target_addr: None,
+            requested_target: None,
+            resolved_addrs: Vec::new(),
+            remote_addr: None,
+            handshake_duration: None,
+            connect_latency: None,
+            traffic: Arc::new(Traffic::default()),
+            closed_at: None,
+            close_reason: None,
+            connection_slot: None,
+            user_slot: None,
+            over_limit_reply: None,
+            quota: None,
+            shutdown: None,
+            shutdown_guard: None,
+            half_close: None,
+            registration: None,
+            span: Span::none(),
+            context: SessionContext::new(0, None),
         }
     }
 
+    /// Address of the client, used to match the source of the access control rules.
+    /// Already set on the sockets yielded by `Socks5Server::incoming()`.
+    pub fn set_peer_addr(&mut self, peer_addr: SocketAddr) -> &mut Self {
+        self.peer_addr = Some(peer_addr);
+        self
+    }
+
+    /// Credentials of the process of the client, for the clients connected through a Unix
+    /// socket, used by the authentication and the access control rules.
+    /// Already set on the sockets yielded by `Socks5Server::incoming()`.
+    pub fn set_peer_credentials(&mut self, credentials: PeerCredentials) -> &mut Self {
+        self.peer_credentials = Some(credentials);
+        self
+    }
+
+    /// Label of the listener which accepted the client, shown in the logs and the observers.
+    /// Already set on the sockets yielded by `Socks5Server::incoming()`.
+    pub fn set_listener(&mut self, label: Arc<str>) -> &mut Self {
+        self.listener = Some(label);
+        self
+    }
+
+    /// Let the session be stopped by this handle, which waits for it from now on: drop the
+    /// socket or run `upgrade_to_socks5()`.
+    /// Already set on the sockets yielded by `Socks5Server::incoming()`.
+    pub fn set_shutdown_handle(&mut self, handle: ShutdownHandle) -> &mut Self {
+        self.shutdown_guard = Some(handle.register());
+        self.shutdown = Some(handle);
+        self
+    }
+
     /// Process clients SOCKS requests
     /// This is the entry point where a whole request is processed.
     pub async fn upgrade_to_socks5(mut self) -> Result<Socks5Socket<T>> {
+        let proxy_header = self.read_proxy_header().await;
+        self.span = Span::session(self.id, self.peer_addr, self.listener.as_deref());
+        let span = self.span.clone();
+        span.instrument(self.run_session(proxy_header)).await
+    }
+
+    /// Replace the address of a trusted load balancer by the one of its client, from the PROXY
+    /// protocol header it sent.
+    async fn read_proxy_header(&mut self) -> Result<()> {
+        let (proxy_protocol, balancer) = match (&self.config.proxy_protocol, self.peer_addr) {
+            (Some(proxy_protocol), Some(peer_addr))
+                if proxy_protocol.is_trusted(peer_addr.ip()) =>
+            {
+                (proxy_protocol.clone(), peer_addr)
+            }
+            _ => return Ok(()),
+        };
+
+        let timeout = proxy_protocol.timeout();
+        let header = future::timeout(timeout, proxy_protocol::read_header(&mut self.inner))
+            .await
+            .unwrap_or_else(|_| {
+                Err(SocksError::InvalidProxyHeader(format!(
+                    "not received within {:?}",
+                    timeout
+                )))
+            });
+        match header {
+            Ok(Some(addrs)) => {
+                debug!("client {} proxied by {}", addrs.source, balancer);
+                self.peer_addr = Some(addrs.source);
+                Ok(())
+            }
+            Ok(None) => {
+                debug!("no client address in the PROXY header of {}", balancer);
+                Ok(())
+            }
+            Err(e) => {
+                debug!("Closing the connection of {}: {}", balancer, e);
+                let _ = self.inner.close().await;
+                Err(e)
+            }
+        }
+    }
+
+    /// Whole lifecycle of the session, inside its span. A session whose PROXY header was
+    /// rejected is closed right away, still reported to the observers.
+    async fn run_session(mut self, proxy_header: Result<()>) -> Result<Socks5Socket<T>> {
         trace!("upgrading to socks5...");
 
-        // Handshake
+        let _guard = self.shutdown_guard.take();
+        self.registration = self
+            .config
+            .session_registry
+            .as_ref()
+            .map(|registry| registry.register(self.id, self.peer_addr, self.traffic.clone()));
+
+        self.context = SessionContext::new(self.id, self.peer_addr)
+            .with_listener(self.listener.clone())
+            .with_peer_credentials(self.peer_credentials);
+        self.notify(|observer, session| observer.on_accept(session));
+
+        let result = match proxy_header {
+            Ok(()) => self.upgrade().await,
+            Err(e) => Err(e),
+        };
+        self.registration = None;
+        self.closed_at = Some(Instant::now());
+
+        let stats = self.stats();
+        if let Some(callback) = &self.config.on_session_end {
+            callback(&stats);
+        }
+        self.notify(|observer, session| observer.on_close(session, &stats));
+
+        result.map(|_| self)
+    }
+
+    async fn upgrade(&mut self) -> Result<()> {
+        self.acquire_connection_slot()?;
+
+        let handshake = match self.config.handshake_timeout {
+            Some(timeout) => {
+                let remaining = timeout.saturating_sub(self.accepted_at.elapsed());
+                match future::timeout(remaining, self.handshake_unless_stopped()).await {
+                    Ok(handshake) => handshake,
+                    Err(_) => {
+                        let _ = self.inner.close().await;
+                        return Err(SocksError::HandshakeTimeout(timeout));
+                    }
+                }
+            }
+            None => self.handshake_unless_stopped().await,
+        };
+        let result = match handshake {
+            Ok(()) => {
+                self.handshake_duration = Some(self.accepted_at.elapsed());
+                self.request().await
+            }
+            Err(e) => Err(e),
+        };
+
+        match result {
+            Ok(_) => {}
+            Err(SocksError::ReplyError(e)) => {
+                self.notify(|observer, session| observer.on_connect_failed(session, e));
+                // If a reply error has been returned, we send it to the client
+                self.reply(&e).await?;
+                Err(e)? // propagate the error to end this connection's task
+            }
+            // if any other errors has been detected, we simply end connection's task
+            Err(d) => return Err(d),
+        };
+
+        Ok(())
+    }
+
+    /// Run the handshake, unless the server is forced to shut down or the session is terminated
+    /// meanwhile.
+    async fn handshake_unless_stopped(&mut self) -> Result<()> {
+        let stop = stop_requested(
+            self.shutdown.clone(),
+            self.registration.as_ref().map(Registration::kill_signal),
+        );
+        unless_stopped(Span::handshake().instrument(self.handshake()), stop).await
+    }
+
+    /// Negotiate the authentication method, authenticate the client and read its request.
+    async fn handshake(&mut self) -> Result<()> {
         if self.config.skip_auth == false {
             let methods = self.get_methods().await?;
 
@@ -198,23 +983,76 @@ impl<T: AsyncRead + AsyncWrite + Unpin> Socks5Socket<T> {
                     username: credentials.0,
                     password: credentials.1,
                 };
+                self.acquire_user_slot()?;
+
+                if let Some(identity) = &self.identity {
+                    if let Some(registration) = &self.registration {
+                        registration.set_user(&identity.username);
+                    }
+                    self.span.record_user(&identity.username);
+                }
             }
         } else {
             debug!("skipping auth");
         }
 
-        match self.request().await {
-            Ok(_) => {}
-            Err(SocksError::ReplyError(e)) => {
-                // If a reply error has been returned, we send it to the client
-                self.reply(&e).await?;
-                Err(e)? // propagate the error to end this connection's task
-            }
-            // if any other errors has been detected, we simply end connection's task
-            Err(d) => return Err(d),
+        self.read_command().await?;
+        if let (Some(cmd), Some(target)) = (self.cmd, &self.requested_target) {
+            observer::notify(
+                &self.config.observers,
+                &mut self.context,
+                |observer, session| observer.on_request(session, cmd, target),
+            );
+        }
+        if let Some(reply) = self.over_limit_reply {
+            Err(reply)?;
+        }
+        self.check_access_control()
+    }
+
+    /// Count the session in the global and per-IP limits.
+    fn acquire_connection_slot(&mut self) -> Result<()> {
+        let limits = match &self.config.connection_limits {
+            Some(limits) => limits.clone(),
+            None => return Ok(()),
+        };
+
+        match limits.acquire(self.peer_addr.map(|addr| addr.ip())) {
+            Ok(slot) => self.connection_slot = Some(slot),
+            Err(exceeded) => self.over_limit(limits.over_limit_action(), exceeded)?,
+        }
+        Ok(())
+    }
+
+    /// Count the session in the limit of the authenticated user.
+    fn acquire_user_slot(&mut self) -> Result<()> {
+        let (limits, identity) = match (&self.config.connection_limits, &self.identity) {
+            (Some(limits), Some(identity)) => (limits.clone(), identity),
+            _ => return Ok(()),
         };
 
-        Ok(self)
+        match limits.acquire_user(&identity.username) {
+            Ok(slot) => self.user_slot = Some(slot),
+            Err(exceeded) => self.over_limit(limits.over_limit_action(), exceeded)?,
+        }
+        Ok(())
+    }
+
+    fn over_limit(&mut self, action: OverLimitAction, exceeded: LimitExceeded) -> Result<()> {
+        warn!(
+            "Session from {:?} over the connection limits: {}",
+            self.peer_addr, exceeded
+        );
+
+        match action {
+            OverLimitAction::Refuse => {
+                Err(SocksError::ConnectionLimitReached(exceeded.to_string()))
+            }
+            OverLimitAction::Reply(reply) => {
+                self.over_limit_reply = Some(reply);
+                Ok(())
+            }
+        }
     }
 
     /// Read the authentication method provided by the client.
@@ -230,7 +1068,8 @@ impl<T: AsyncRead + AsyncWrite + Unpin> Socks5Socket<T> {
         trace!("Socks5Socket: get_methods()");
         // read the first 2 bytes which contains the SOCKS version and the methods len()
         let [version, methods_len] =
-            read_exact!(self.inner, [0u8; 2]).context("Can't read methods")?;
+            read_exact_timeout!(self.inner, [0u8; 2], self.config.read_timeout)
+                .context("Can't read methods")?;
         debug!(
             "Handshake headers: [version: {version}, methods len: {len}]",
             version = version,
@@ -244,8 +1083,12 @@ impl<T: AsyncRead + AsyncWrite + Unpin> Socks5Socket<T> {
         // {METHODS available from the client}
         // eg. (non-auth) {0, 1}
         // eg. (auth)     {0, 1, 2}
-        let methods = read_exact!(self.inner, vec![0u8; methods_len as usize])
-            .context("Can't get methods.")?;
+        let methods = read_exact_timeout!(
+            self.inner,
+            vec![0u8; methods_len as usize],
+            self.config.read_timeout
+        )
+        .context("Can't get methods.")?;
         debug!("methods supported sent by the client: {:?}", &methods);
 
         // Return methods available
@@ -300,6 +1143,7 @@ impl<T: AsyncRead + AsyncWrite + Unpin> Socks5Socket<T> {
             .write(&[consts::SOCKS5_VERSION, method_supported])
             .await
             .context("Can't reply with method auth-none")?;
+        self.notify(|observer, session| observer.on_method_negotiated(session, method_supported));
         Ok(())
     }
 
@@ -309,7 +1153,8 @@ impl<T: AsyncRead + AsyncWrite + Unpin> Socks5Socket<T> {
     async fn authenticate(&mut self) -> Result<(String, String)> {
         trace!("Socks5Socket: authenticate()");
         let [version, user_len] =
-            read_exact!(self.inner, [0u8; 2]).context("Can't read user len")?;
+            read_exact_timeout!(self.inner, [0u8; 2], self.config.read_timeout)
+                .context("Can't read user len")?;
         debug!(
             "Auth: [version: {version}, user len: {len}]",
             version = version,
@@ -317,70 +1162,186 @@ impl<T: AsyncRead + AsyncWrite + Unpin> Socks5Socket<T> {
         );
 
         if user_len < 1 {
+            self.notify(|observer, session| {
+                observer.on_auth_failure(session, None, AuthFailure::Malformed)
+            });
             return Err(SocksError::AuthenticationFailed(format!(
                 "Username malformed ({} chars)",
                 user_len
             )));
         }
 
-        let username =
-            read_exact!(self.inner, vec![0u8; user_len as usize]).context("Can't get username.")?;
+        let username = read_exact_timeout!(
+            self.inner,
+            vec![0u8; user_len as usize],
+            self.config.read_timeout
+        )
+        .context("Can't get username.")?;
         debug!("username bytes: {:?}", &username);
 
-        let [pass_len] = read_exact!(self.inner, [0u8; 1]).context("Can't read pass len")?;
+        let [pass_len] = read_exact_timeout!(self.inner, [0u8; 1], self.config.read_timeout)
+            .context("Can't read pass len")?;
         debug!("Auth: [pass len: {len}]", len = pass_len,);
 
         if pass_len < 1 {
+            let username = String::from_utf8_lossy(&username);
+            observer::notify(
+                &self.config.observers,
+                &mut self.context,
+                |observer, session| {
+                    observer.on_auth_failure(session, Some(&username), AuthFailure::Malformed)
+                },
+            );
             return Err(SocksError::AuthenticationFailed(format!(
                 "Password malformed ({} chars)",
                 pass_len
             )));
         }
 
-        let password =
-            read_exact!(self.inner, vec![0u8; pass_len as usize]).context("Can't get password.")?;
+        let password = read_exact_timeout!(
+            self.inner,
+            vec![0u8; pass_len as usize],
+            self.config.read_timeout
+        )
+        .context("Can't get password.")?;
         debug!("password bytes: {:?}", &password);
 
         let username = String::from_utf8(username).context("Failed to convert username")?;
         let password = String::from_utf8(password).context("Failed to convert password")?;
         let auth = self.config.auth.as_ref().context("No auth module")?;
+        let brute_force = self.config.brute_force_protection.clone();
+        let peer_ip = self.peer_addr.map(|addr| addr.ip());
 
-        if auth.authenticate(&username, &password) {
-            self.inner
-                .write(&[1, consts::SOCKS5_REPLY_SUCCEEDED])
-                .await
-                .context("Can't reply auth success")?;
-        } else {
+        if let Some(key) = brute_force
+            .as_ref()
+            .and_then(|protection| protection.check(peer_ip, &username))
+        {
+            observer::notify(
+                &self.config.observers,
+                &mut self.context,
+                |observer, session| {
+                    observer.on_auth_failure(session, Some(&username), AuthFailure::LockedOut)
+                },
+            );
             self.inner
                 .write(&[1, consts::SOCKS5_AUTH_METHOD_NOT_ACCEPTABLE])
                 .await
                 .context("Can't reply with auth method not acceptable.")?;
 
             return Err(SocksError::AuthenticationRejected(format!(
-                "Authentication with username `{}`, rejected.",
-                username
+                "Authentication with username `{}`, rejected: {} is locked out.",
+                username, key
             )));
         }
 
+        let request = AuthRequest {
+            username: &username,
+            password: &password,
+            peer_addr: self.peer_addr,
+            peer_credentials: self.peer_credentials,
+        };
+
+        match auth.authenticate(request).await {
+            Ok(Some(identity)) => {
+                if let Some(protection) = &brute_force {
+                    protection.record_success(peer_ip, &username);
+                }
+                self.inner
+                    .write(&[1, consts::SOCKS5_REPLY_SUCCEEDED])
+                    .await
+                    .context("Can't reply auth success")?;
+                observer::notify(
+                    &self.config.observers,
+                    &mut self.context,
+                    |observer, session| observer.on_auth_success(session, &username),
+                );
+                self.identity = Some(identity);
+            }
+            outcome => {
+                let reason = match outcome {
+                    Err(_) => AuthFailure::Error,
+                    _ => AuthFailure::InvalidCredentials,
+                };
+                observer::notify(
+                    &self.config.observers,
+                    &mut self.context,
+                    |observer, session| observer.on_auth_failure(session, Some(&username), reason),
+                );
+                if let (Some(protection), Ok(None)) = (&brute_force, &outcome) {
+                    // slow down whoever is guessing passwords
+                    task::sleep(protection.record_failure(peer_ip, &username)).await;
+                }
+                self.inner
+                    .write(&[1, consts::SOCKS5_AUTH_METHOD_NOT_ACCEPTABLE])
+                    .await
+                    .context("Can't reply with auth method not acceptable.")?;
+
+                return Err(match outcome {
+                    Err(e) => SocksError::AuthenticationFailed(format!(
+                        "Authentication with username `{}` couldn't be performed: {:#}",
+                        username, e
+                    )),
+                    _ => SocksError::AuthenticationRejected(format!(
+                        "Authentication with username `{}`, rejected.",
+                        username
+                    )),
+                });
+            }
+        }
+
         info!("User `{}` logged successfully.", username);
 
         Ok((username, password))
     }
 
-    /// Wrapper to principally cover ReplyError types for both functions read & execute request.
+    /// Wrapper to principally cover ReplyError types for both functions resolve & execute request.
     async fn request(&mut self) -> Result<()> {
-        self.read_command().await?;
+        self.check_quota().await?;
+
+        // Until the success reply, the session stops without connecting the client
+        let stop = stop_requested(
+            self.shutdown.clone(),
+            self.registration.as_ref().map(Registration::kill_signal),
+        );
+        if let Some(outbound) = unless_stopped(self.resolve_and_connect(), stop).await? {
+            self.relay(outbound).await?;
+        }
+
+        Ok(())
+    }
 
+    /// Resolve the target, then connect to it unless the command isn't executed.
+    async fn resolve_and_connect(&mut self) -> Result<Option<TcpStream>> {
         if self.config.dns_resolve {
-            self.resolve_dns().await?;
+            Span::dns().instrument(self.resolve_dns()).await?;
         } else {
             debug!("Domain won't be resolved because `dns_resolve`'s config has been turned off.")
         }
 
         if self.config.execute_command {
-            self.execute_command().await?;
+            Ok(Some(self.execute_command().await?))
+        } else {
+            Ok(None)
         }
+    }
 
+    /// Deny the request if the quota of the user is exhausted.
+    async fn check_quota(&mut self) -> Result<()> {
+        let (quotas, identity) = match (&self.config.quotas, &self.identity) {
+            (Some(quotas), Some(identity)) => (quotas, identity),
+            _ => return Ok(()),
+        };
+
+        let (allowed, quota) = quotas.session(&identity.username).await.map_err(|e| {
+            error!("Can't check the quota of `{}`: {:#}", identity.username, e);
+            ReplyError::GeneralFailure
+        })?;
+        if !allowed {
+            info!("Quota of `{}` exhausted, request denied", identity.username);
+            Err(ReplyError::ConnectionNotAllowed)?;
+        }
+
+        self.quota = quota;
         Ok(())
     }
 
@@ -425,7 +1386,8 @@ impl<T: AsyncRead + AsyncWrite + Unpin> Socks5Socket<T> {
         // +----+-----+-------+------+----------+----------+
         //
         let [version, cmd, rsv, address_type] =
-            read_exact!(self.inner, [0u8; 4]).context("Malformed request")?;
+            read_exact_timeout!(self.inner, [0u8; 4], self.config.read_timeout)
+                .context("Malformed request")?;
         debug!(
             "Request: [version: {version}, command: {cmd}, rev: {rsv}, address_type: {address_type}]",
             version = version,
@@ -438,20 +1400,31 @@ impl<T: AsyncRead + AsyncWrite + Unpin> Socks5Socket<T> {
             return Err(SocksError::UnsupportedSocksVersion(version));
         }
 
-        if cmd != consts::SOCKS5_CMD_TCP_CONNECT {
-            return Err(ReplyError::CommandNotSupported)?;
+        match Command::from_u8(cmd) {
+            Some(Command::TcpConnect) => self.cmd = Some(Command::TcpConnect),
+            _ => return Err(ReplyError::CommandNotSupported)?,
         }
 
         // Guess address type
-        let target_addr = read_address(&mut self.inner, address_type)
-            .await
-            .map_err(|e| {
-                // print explicit error
-                error!("{:#}", e);
-                // then convert it to a reply
-                ReplyError::AddressTypeNotSupported
-            })?;
-
+        let target_addr =
+            read_address_timeout(&mut self.inner, address_type, self.config.read_timeout)
+                .await
+                .map_err(|e| {
+                    // a client too slow to send its address isn't worth a reply
+                    if is_timeout(&e) {
+                        return SocksError::Other(e);
+                    }
+                    // print explicit error
+                    error!("{:#}", e);
+                    // then convert it to a reply
+                    ReplyError::AddressTypeNotSupported.into()
+                })?;
+
+        if let Some(registration) = &self.registration {
+            registration.set_target(&target_addr);
+        }
+        self.span.record_target(&target_addr);
+        self.requested_target = Some(target_addr.clone());
         self.target_addr = Some(target_addr);
 
         debug!("Request target is {}", self.target_addr.as_ref().unwrap());
@@ -459,6 +1432,46 @@ impl<T: AsyncRead + AsyncWrite + Unpin> Socks5Socket<T> {
         Ok(())
     }
 
+    /// Evaluate the request against the access control rules, if any.
+    fn check_access_control(&self) -> Result<()> {
+        let acl = match &self.config.acl {
+            Some(acl) => acl,
+            None => return Ok(()),
+        };
+
+        let request = AclRequest {
+            source: self.peer_addr.map(|addr| addr.ip()),
+            peer_credentials: self.peer_credentials,
+            command: self.cmd.context("command empty")?,
+            target: self.target_addr.as_ref().context("target_addr empty")?,
+            user: self.identity.as_ref().map(|id| id.username.as_str()),
+            groups: self.identity.as_ref().map_or(&[], |id| &id.groups[..]),
+        };
+        let rule = acl.matching_rule(&request);
+
+        if acl.evaluate(&request) == Action::Deny {
+            let rule = match rule {
+                Some(index) => format!("rule #{}", index),
+                None => "default action".to_string(),
+            };
+
+            if acl.is_dry_run() {
+                warn!(
+                    "ACL dry-run: request from {:?} to {} would be denied by {}",
+                    request.source, request.target, rule
+                );
+            } else {
+                info!(
+                    "ACL: request from {:?} to {} denied by {}",
+                    request.source, request.target, rule
+                );
+                return Err(ReplyError::ConnectionNotAllowed)?;
+            }
+        }
+
+        Ok(())
+    }
+
     /// This function is public, it can be call manually on your own-willing
     /// if config flag has been turned off: `Config::dns_resolve == false`.
     pub async fn resolve_dns(&mut self) -> Result<()> {
@@ -466,7 +1479,16 @@ impl<T: AsyncRead + AsyncWrite + Unpin> Socks5Socket<T> {
         if let Some(target_addr) = self.target_addr.take() {
             // decide whether we have to resolve DNS or not
             self.target_addr = match target_addr {
-                TargetAddr::Domain(_, _) => Some(target_addr.resolve_dns().await?),
+                TargetAddr::Domain(ref domain, _) => {
+                    self.resolved_addrs = target_addr.resolve_dns_all().await?;
+                    let addrs = &self.resolved_addrs;
+                    observer::notify(
+                        &self.config.observers,
+                        &mut self.context,
+                        |observer, session| observer.on_dns_resolved(session, domain, addrs),
+                    );
+                    Some(TargetAddr::Ip(self.resolved_addrs[0]))
+                }
                 TargetAddr::Ip(_) => Some(target_addr),
             };
         }
@@ -474,43 +1496,87 @@ impl<T: AsyncRead + AsyncWrite + Unpin> Socks5Socket<T> {
         Ok(())
     }
 
-    /// Connect to the target address that the client wants,
-    /// then forward the data between them (client <=> target address).
-    async fn execute_command(&mut self) -> Result<()> {
-        // async-std's ToSocketAddrs doesn't supports external trait implementation
-        // @see https://github.com/async-rs/async-std/issues/539
-        let addr = self
-            .target_addr
-            .as_ref()
-            .context("target_addr empty")?
-            .to_socket_addrs()?
-            .next()
-            .context("unreachable")?;
+    /// Drop the addresses forbidden by the SSRF protection,
+    /// the request is denied if none is left.
+    fn filter_forbidden_addrs(&self, addrs: Vec<SocketAddr>) -> Result<Vec<SocketAddr>> {
+        let protection = match &self.config.ssrf_protection {
+            Some(protection) => protection,
+            None => return Ok(addrs),
+        };
+
+        let (allowed, forbidden): (Vec<_>, Vec<_>) = addrs
+            .into_iter()
+            .partition(|addr| protection.is_allowed(addr.ip()));
+
+        if !forbidden.is_empty() {
+            info!(
+                "SSRF protection: won't connect to forbidden address(es) {:?}",
+                forbidden
+            );
+        }
+
+        if allowed.is_empty() {
+            return Err(ReplyError::ConnectionNotAllowed)?;
+        }
+
+        Ok(allowed)
+    }
+
+    /// Connect to the target address that the client wants.
+    async fn execute_command(&mut self) -> Result<TcpStream> {
+        let addrs = if !self.resolved_addrs.is_empty() {
+            self.resolved_addrs.clone()
+        } else {
+            // async-std's ToSocketAddrs doesn't supports external trait implementation
+            // @see https://github.com/async-rs/async-std/issues/539
+            self.target_addr
+                .as_ref()
+                .context("target_addr empty")?
+                .to_socket_addrs()?
+                .collect()
+        };
+        let addrs = self.filter_forbidden_addrs(addrs)?;
 
         // TCP connect with timeout, to avoid memory leak for connection that takes forever
-        let outbound = match future::timeout(
-            std::time::Duration::from_secs(self.config.request_timeout),
-            TcpStream::connect(addr),
+        let connect_start = Instant::now();
+        let (outbound, addr) = match future::timeout(
+            Duration::from_secs(self.config.request_timeout),
+            Span::connect().instrument(happy_eyeballs::connect(
+                addrs,
+                self.config.connection_attempt_delay,
+            )),
         )
         .await
         {
-            Ok(e) => match e {
-                Ok(o) => o,
-                Err(e) => match e.kind() {
-                    // Match other TCP errors with ReplyError
-                    io::ErrorKind::ConnectionRefused => Err(ReplyError::ConnectionRefused)?,
-                    io::ErrorKind::ConnectionAborted => Err(ReplyError::ConnectionNotAllowed)?,
-                    io::ErrorKind::ConnectionReset => Err(ReplyError::ConnectionNotAllowed)?,
-                    io::ErrorKind::NotConnected => Err(ReplyError::NetworkUnreachable)?,
-                    _ => Err(e)?, // #[error("General failure")] ?
-                },
-            },
+            Ok(Ok(connected)) => connected,
+            // Every attempt failed, reply with the most meaningful error
+            Ok(Err(errors)) => {
+                let reply = errors
+                    .iter()
+                    .map(|(addr, err)| {
+                        debug!("Can't connect to {}: {}", addr, err);
+                        connect_error_to_reply(err)
+                    })
+                    .max_by_key(|reply| reply_error_rank(*reply))
+                    .unwrap_or(ReplyError::GeneralFailure);
+                Err(reply)?
+            }
             // Wrap timeout error in a proper ReplyError
             Err(_) => Err(ReplyError::TtlExpired)?,
         };
 
-        debug!("Connected to remote destination");
+        debug!("Connected to remote destination {}", addr);
+        let latency = connect_start.elapsed();
+        self.connect_latency = Some(latency);
+        self.remote_addr = Some(addr);
+        self.notify(|observer, session| observer.on_connected(session, addr, latency));
+
+        Ok(outbound)
+    }
 
+    /// Tell the client it's connected, then forward the data between them
+    /// (client <=> target address).
+    async fn relay(&mut self, outbound: TcpStream) -> Result<()> {
         // TODO: convert this to the real address
         self.inner
             .write(&[
@@ -532,7 +1598,38 @@ impl<T: AsyncRead + AsyncWrite + Unpin> Socks5Socket<T> {
 
         debug!("Wrote success");
 
-        transfer(&mut self.inner, outbound).await
+        let user = self.identity.as_ref().map(|id| id.username.as_str());
+        let relay = Relay {
+            activity: Activity::new(),
+            throttle: self
+                .config
+                .bandwidth_limiter
+                .as_ref()
+                .map(|limiter| limiter.session(user)),
+            quota: self.quota.take(),
+            shutdown: self.shutdown.clone(),
+            kill: self.registration.as_ref().map(Registration::kill_signal),
+        };
+        self.close_reason = Some(
+            transfer(
+                ShutdownOnClose::new(&mut self.inner, self.half_close),
+                outbound,
+                &self.config,
+                &self.traffic,
+                relay,
+            )
+            .await?,
+        );
+
+        Ok(())
+    }
+
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        self.peer_addr
+    }
+
+    pub fn cmd(&self) -> Option<Command> {
+        self.cmd
     }
 
     pub fn target_addr(&self) -> Option<&TargetAddr> {
@@ -542,45 +1639,416 @@ impl<T: AsyncRead + AsyncWrite + Unpin> Socks5Socket<T> {
     pub fn auth(&self) -> &AuthenticationMethod {
         &self.auth
     }
+
+    /// Why the relayed session ended, once it did.
+    pub fn close_reason(&self) -> Option<CloseReason> {
+        self.close_reason
+    }
+
+    /// The user, once authenticated.
+    pub fn identity(&self) -> Option<&Identity> {
+        self.identity.as_ref()
+    }
+
+    /// Call the observers of the config.
+    fn notify<F: Fn(&dyn SessionObserver, &mut SessionContext)>(&mut self, callback: F) {
+        observer::notify(&self.config.observers, &mut self.context, callback);
+    }
+
+    /// Unique within the process.
+    pub fn id(&self) -> SessionId {
+        self.id
+    }
+
+    /// Summary of the session so far, complete once `upgrade_to_socks5()` returned.
+    pub fn stats(&self) -> SessionStats {
+        let closed_at = self.closed_at.unwrap_or_else(Instant::now);
+
+        SessionStats {
+            id: self.id,
+            peer_addr: self.peer_addr,
+            listener: self.listener.as_deref().map(str::to_string),
+            user: self.identity.as_ref().map(|id| id.username.clone()),
+            target: self.requested_target.clone(),
+            remote_addr: self.remote_addr,
+            bytes_sent: self.traffic.sent(),
+            bytes_received: self.traffic.received(),
+            handshake_duration: self.handshake_duration,
+            connect_latency: self.connect_latency,
+            duration: closed_at.duration_since(self.accepted_at),
+            close_reason: self.close_reason,
+        }
+    }
+}
+
+/// The client didn't send the bytes within the read timeout.
+fn is_timeout(err: &anyhow::Error) -> bool {
+    err.chain().any(|cause| {
+        cause.downcast_ref::<io::Error>().map(io::Error::kind) == Some(io::ErrorKind::TimedOut)
+    })
+}
+
+/// Match TCP errors with ReplyError
+fn connect_error_to_reply(err: &io::Error) -> ReplyError {
+    match err.kind() {
+        io::ErrorKind::ConnectionRefused => ReplyError::ConnectionRefused,
+        io::ErrorKind::ConnectionAborted => ReplyError::ConnectionNotAllowed,
+        io::ErrorKind::ConnectionReset => ReplyError::ConnectionNotAllowed,
+        io::ErrorKind::NotConnected => ReplyError::NetworkUnreachable,
+        io::ErrorKind::NetworkUnreachable => ReplyError::NetworkUnreachable,
+        io::ErrorKind::HostUnreachable => ReplyError::HostUnreachable,
+        io::ErrorKind::TimedOut => ReplyError::TtlExpired,
+        _ => ReplyError::GeneralFailure,
+    }
+}
+
+/// When several connection attempts failed, the reply sent is the one telling the most about
+/// the target: a refused connection proves the host is up, while an unreachable network may
+/// only mean that this address family isn't routed.
+#[rustfmt::skip]
+fn reply_error_rank(reply: ReplyError) -> u8 {
+    match reply {
+        ReplyError::ConnectionRefused    => 5,
+        ReplyError::ConnectionNotAllowed => 4,
+        ReplyError::HostUnreachable      => 3,
+        ReplyError::NetworkUnreachable   => 2,
+        ReplyError::TtlExpired           => 1,
+        _                                => 0,
+    }
+}
+
+/// Why a relayed session ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CloseReason {
+    /// The client closed the connection.
+    ClientClosed,
+    /// The target closed the connection.
+    TargetClosed,
+    /// No byte has been relayed in either direction for `idle_timeout`.
+    IdleTimeout,
+    /// The session lasted `max_session_duration`.
+    MaxDurationReached,
+    /// One side closed, and the other one didn't within `linger_timeout`.
+    LingerTimeout,
+    /// The user has exhausted its traffic quota.
+    QuotaExceeded,
+    /// The server shut down before the session ended.
+    Shutdown,
+    /// The session has been terminated through the `SessionRegistry`.
+    Terminated,
+    /// I/O error on either side.
+    Error,
+}
+
+impl fmt::Display for CloseReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            CloseReason::ClientClosed => "client closed",
+            CloseReason::TargetClosed => "target closed",
+            CloseReason::IdleTimeout => "idle timeout",
+            CloseReason::MaxDurationReached => "max session duration reached",
+            CloseReason::LingerTimeout => "linger timeout",
+            CloseReason::QuotaExceeded => "quota exceeded",
+            CloseReason::Shutdown => "server shutdown",
+            CloseReason::Terminated => "terminated",
+            CloseReason::Error => "error",
+        })
+    }
+}
+
+/// Size of the buffer used to relay each direction.
+const RELAY_BUFFER_SIZE: usize = 8 * 1024;
+
+/// Last time bytes have been relayed, in either direction.
+struct Activity {
+    start: Instant,
+    /// Milliseconds since `start`
+    last: AtomicU64,
+    /// Directions waiting for the throttle, the session isn't idle meanwhile
+    throttled: AtomicUsize,
+}
+
+impl Activity {
+    fn new() -> Self {
+        Activity {
+            start: Instant::now(),
+            last: AtomicU64::new(0),
+            throttled: AtomicUsize::new(0),
+        }
+    }
+
+    fn touch(&self) {
+        let elapsed = self.start.elapsed().as_millis() as u64;
+        self.last.store(elapsed, Ordering::Relaxed);
+    }
+
+    /// Not idle until the guard is dropped.
+    fn throttled(&self) -> Throttled<'_> {
+        self.throttled.fetch_add(1, Ordering::Relaxed);
+        Throttled(self)
+    }
+
+    fn idle_for(&self) -> Duration {
+        if self.throttled.load(Ordering::Relaxed) > 0 {
+            return Duration::from_secs(0);
+        }
+        let last = Duration::from_millis(self.last.load(Ordering::Relaxed));
+        self.start.elapsed().saturating_sub(last)
+    }
+
+    /// Resolves once nothing happened for `timeout`, never if there is no timeout.
+    async fn idle(&self, timeout: Option<Duration>) {
+        let timeout = match timeout {
+            Some(timeout) => timeout,
+            None => return future::pending().await,
+        };
+
+        loop {
+            let idle = self.idle_for();
+            if idle >= timeout {
+                return;
+            }
+            task::sleep(timeout - idle).await;
+        }
+    }
+}
+
+/// See [`Activity::throttled()`].
+struct Throttled<'a>(&'a Activity);
+
+impl Drop for Throttled<'_> {
+    fn drop(&mut self) {
+        self.0.touch();
+        self.0.throttled.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// State of a session shared by both directions of the relay.
+struct Relay {
+    activity: Activity,
+    throttle: Option<SessionThrottle>,
+    quota: Option<SessionQuota>,
+    shutdown: Option<ShutdownHandle>,
+    kill: Option<Signal>,
+}
+
+impl Relay {
+    fn quota_exhausted(&self) -> bool {
+        self.quota
+            .as_ref()
+            .is_some_and(|quota| quota.is_exhausted())
+    }
+}
+
+/// Run `future`, unless `stop` resolves first.
+async fn unless_stopped<F, R>(future: F, stop: impl Future<Output = CloseReason>) -> Result<R>
+where
+    F: Future<Output = Result<R>>,
+{
+    futures::pin_mut!(future, stop);
+
+    match futures::future::select(future, stop).await {
+        Either::Left((result, _)) => result,
+        Either::Right((CloseReason::Terminated, _)) => Err(SocksError::SessionTerminated),
+        Either::Right(_) => Err(SocksError::ShuttingDown),
+    }
+}
+
+/// Resolves once the session has to stop, with `Shutdown` if the server is forced to shut down,
+/// or `Terminated` if the session is killed. Never without any handle.
+async fn stop_requested(shutdown: Option<ShutdownHandle>, kill: Option<Signal>) -> CloseReason {
+    let forced = async {
+        match shutdown {
+            Some(shutdown) => shutdown.forced().await,
+            None => future::pending().await,
+        }
+    };
+    let killed = async {
+        match kill {
+            Some(kill) => kill.wait().await,
+            None => future::pending().await,
+        }
+    };
+    futures::pin_mut!(forced, killed);
+
+    match futures::future::select(forced, killed).await {
+        Either::Left(_) => CloseReason::Shutdown,
+        Either::Right(_) => CloseReason::Terminated,
+    }
+}
+
+/// Copy data from the reader to the writer, counting the bytes relayed.
+/// Once the reader reaches EOF, the write side of the writer is shut down.
+/// Stops early, without shutting down the writer, when the quota is exhausted.
+async fn copy<R, W>(
+    mut reader: R,
+    mut writer: W,
+    counter: &AtomicU64,
+    direction: Direction,
+    relay: &Relay,
+) -> io::Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut buf = vec![0u8; RELAY_BUFFER_SIZE];
+
+    loop {
+        let n = reader.read(&mut buf).await?;
+        if n == 0 {
+            // forward the half-close, the peer may already be gone
+            if let Err(err) = writer.close().await {
+                debug!("Can't shut down the write side: {}", err);
+            }
+            return Ok(());
+        }
+        relay.activity.touch();
+
+        if let Some(throttle) = &relay.throttle {
+            let _throttled = relay.activity.throttled();
+            throttle.consume(direction, n).await;
+        }
+
+        writer.write_all(&buf[..n]).await?;
+        writer.flush().await?;
+        counter.fetch_add(n as u64, Ordering::Relaxed);
+        relay.activity.touch();
+
+        if let Some(quota) = &relay.quota {
+            if !quota.consume(n).await {
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// Resolves after `duration`, never if there is no duration.
+async fn sleep_for(duration: Option<Duration>) {
+    match duration {
+        Some(duration) => task::sleep(duration).await,
+        None => future::pending().await,
+    }
 }
 
 /// Copy data between two peers
 /// Using 2 different generators, because they could be different structs with same traits.
-async fn transfer<I, O>(mut inbound: I, outbound: O) -> Result<()>
+async fn transfer<I, O>(
+    inbound: ShutdownOnClose<'_, I>,
+    mut outbound: O,
+    config: &Config,
+    traffic: &Traffic,
+    relay: Relay,
+) -> Result<CloseReason>
 where
     I: AsyncRead + AsyncWrite + Unpin,
-    O: AsyncRead + AsyncWrite + Unpin,
+    O: AsyncRead + AsyncWrite + HalfClose + Unpin,
 {
     //TODO: use TcpStream.clone() https://github.com/async-rs/async-std/pull/689/files#diff-633608b66cafdfb86435918f3a48bea5R17
 
+    // Without a half-close, the client can't be told the target is done while still sending
+    let half_closed_client = inbound.can_half_close();
     //    let (mut ri, mut wi) = (&inbound, &inbound);
-    let (mut ri, mut wi) = futures::io::AsyncReadExt::split(&mut inbound);
+    let (mut ri, mut wi) = futures::io::AsyncReadExt::split(inbound);
     //    let (mut ro, mut wo) = (&outbound, &outbound);
-    let (mut ro, mut wo) = futures::io::AsyncReadExt::split(outbound);
-
-    // Exchange data
-    // For some reasons, futures::future::select does not work with async_std::io::copy() 🤔
-    let inbound_to_outbound = futures::io::copy(&mut ri, &mut wo);
-    let outbound_to_inbound = futures::io::copy(&mut ro, &mut wi);
+    let (mut ro, mut wo) = futures::io::AsyncReadExt::split(ShutdownOnClose::new(
+        &mut outbound,
+        Some(HalfCloseFns::of()),
+    ));
+
+    let reason = {
+        // Exchange data
+        let inbound_to_outbound = copy(
+            &mut ri,
+            &mut wo,
+            traffic.sent_counter(),
+            Direction::Upload,
+            &relay,
+        )
+        .fuse();
+        let outbound_to_inbound = copy(
+            &mut ro,
+            &mut wi,
+            traffic.received_counter(),
+            Direction::Download,
+            &relay,
+        )
+        .fuse();
+        let idle = relay.activity.idle(config.idle_timeout).fuse();
+        let lifetime = sleep_for(config.max_session_duration).fuse();
+        let linger = Fuse::terminated();
+        let stop = stop_requested(relay.shutdown.clone(), relay.kill.clone()).fuse();
+        futures::pin_mut!(
+            inbound_to_outbound,
+            outbound_to_inbound,
+            idle,
+            lifetime,
+            linger,
+            stop
+        );
 
-    // I've chosen `select` over `join` because the inbound (client) is more likely to leave the connection open for a while,
-    // while it's not necessarily as the other part (outbound, aka remote server) has closed the communication.
-    match futures::future::select(inbound_to_outbound, outbound_to_inbound).await {
-        Either::Left((Ok(data), _)) => {
-            info!("local closed -> remote target ({} bytes consumed)", data)
-        }
-        Either::Left((Err(err), _)) => {
-            error!("local closed -> remote target with error {:?}", err,)
-        }
-        Either::Right((Ok(data), _)) => {
-            info!("local <- remote target closed ({} bytes consumed)", data)
-        }
-        Either::Right((Err(err), _)) => {
-            error!("local <- remote target closed with error {:?}", err,)
+        // A side closing only shuts down the write side of the other one (half-close), the session
+        // ends once both directions are done. The remaining direction is given `linger_timeout` to
+        // finish, as the peers don't always close both sides of their connection.
+        let mut first_closed = None;
+        loop {
+            futures::select! {
+                res = inbound_to_outbound => match res {
+                    Ok(()) if relay.quota_exhausted() => break CloseReason::QuotaExceeded,
+                    Ok(()) => match first_closed {
+                        Some(reason) => break reason,
+                        None => {
+                            first_closed = Some(CloseReason::ClientClosed);
+                            linger.set(sleep_for(config.linger_timeout).fuse());
+                        }
+                    },
+                    Err(err) => {
+                        error!("local -> remote target failed with error {:?}", err);
+                        break CloseReason::Error;
+                    }
+                },
+                res = outbound_to_inbound => match res {
+                    Ok(()) if relay.quota_exhausted() => break CloseReason::QuotaExceeded,
+                    Ok(()) if !half_closed_client => break CloseReason::TargetClosed,
+                    Ok(()) => match first_closed {
+                        Some(reason) => break reason,
+                        None => {
+                            first_closed = Some(CloseReason::TargetClosed);
+                            linger.set(sleep_for(config.linger_timeout).fuse());
+                        }
+                    },
+                    Err(err) => {
+                        error!("local <- remote target failed with error {:?}", err);
+                        break CloseReason::Error;
+                    }
+                },
+                _ = linger => break CloseReason::LingerTimeout,
+                _ = idle => break CloseReason::IdleTimeout,
+                _ = lifetime => break CloseReason::MaxDurationReached,
+                reason = stop => break reason,
+            }
         }
     };
 
-    Ok(())
+    // The session is over: close the connection of the client too, the socket handed back to
+    // the caller must not keep it open
+    if let Ok(inbound) = ri.reunite(wi) {
+        if let Err(e) = inbound.close_both().await {
+            debug!("Can't close the connection of the client: {}", e);
+        }
+    }
+
+    if let Some(quota) = &relay.quota {
+        quota.report().await;
+    }
+
+    info!(
+        "session closed ({}): {} bytes sent to remote target, {} bytes received",
+        reason,
+        traffic.sent(),
+        traffic.received(),
+    );
+
+    Ok(reason)
 }
 
 /// Allow us to read directly from the struct
@@ -627,7 +2095,30 @@ where
 
 #[cfg(test)]
 mod test {
-    use crate::server::Socks5Server;
+    use crate::server::acl::{AccessControl, Action};
+    use crate::server::observer::{SessionContext, SessionObserver};
+    use crate::server::proxy_protocol::ProxyProtocol;
+    use crate::server::registry::{SessionFilter, SessionRegistry};
+    use crate::server::stats::SessionStats;
+    use crate::server::throttle::{BandwidthLimiter, Limit, Limits};
+    use crate::server::{
+        CloseReason, Config, ListenerAddr, SimpleUserPassword, Socks5Server, Socks5Socket,
+    };
+    use crate::util::target_addr::TargetAddr;
+    #[cfg(unix)]
+    use crate::util::testing::blackhole;
+    use crate::{Command, ReplyError, SocksError};
+    use async_std::future;
+    use async_std::net::{SocketAddr, TcpListener, TcpStream};
+    use async_std::sync::Arc;
+    use async_std::task::{self, JoinHandle};
+    use futures::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, StreamExt};
+    use std::io;
+    use std::net::Shutdown;
+    use std::pin::Pin;
+    use std::sync::Mutex;
+    use std::task::{Context, Poll};
+    use std::time::{Duration, Instant};
 
     #[async_std::test]
     async fn test_bind() {
@@ -636,4 +2127,710 @@ mod test {
             let _server = Socks5Server::bind("127.0.0.1:1080").await.unwrap();
         }.await;
     }
+
+    type Session = JoinHandle<crate::Result<Socks5Socket<TcpStream>>>;
+
+    /// A client connected to a session served with this config.
+    async fn session(config: Config) -> (TcpStream, Session) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (stream, peer_addr) = listener.accept().await.unwrap();
+        let mut socket = Socks5Socket::new(stream, Arc::new(config));
+        socket.set_peer_addr(peer_addr).set_half_close(true);
+        (client, task::spawn(socket.upgrade_to_socks5()))
+    }
+
+    /// Transport without `HalfClose`, like a TLS stream.
+    struct Opaque(TcpStream);
+
+    impl AsyncRead for Opaque {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut [u8],
+        ) -> Poll<io::Result<usize>> {
+            Pin::new(&mut self.0).poll_read(cx, buf)
+        }
+    }
+
+    impl AsyncWrite for Opaque {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            Pin::new(&mut self.0).poll_write(cx, buf)
+        }
+
+        fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.0).poll_flush(cx)
+        }
+
+        fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(self.0.shutdown(Shutdown::Both))
+        }
+    }
+
+    /// Negotiate no authentication, then ask to connect to `target`. Returns the reply code.
+    async fn connect(client: &mut TcpStream, target: SocketAddr) -> u8 {
+        client.write_all(&[5, 1, 0]).await.unwrap();
+        let mut method = [0u8; 2];
+        client.read_exact(&mut method).await.unwrap();
+        assert_eq!(method, [5, 0]);
+
+        let ip = match target {
+            SocketAddr::V4(addr) => addr.ip().octets(),
+            SocketAddr::V6(_) => unreachable!(),
+        };
+        let mut request = vec![5, 1, 0, 1];
+        request.extend_from_slice(&ip);
+        request.extend_from_slice(&target.port().to_be_bytes());
+        client.write_all(&request).await.unwrap();
+
+        let mut reply = [0u8; 10];
+        client.read_exact(&mut reply).await.unwrap();
+        reply[1]
+    }
+
+    #[async_std::test]
+    async fn test_denied_request_never_reaches_the_target() {
+        let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut config = Config::default();
+        config.set_access_control(AccessControl::new(Action::Deny));
+        let (mut client, session) = session(config).await;
+
+        let reply = connect(&mut client, target.local_addr().unwrap()).await;
+        assert_eq!(reply, ReplyError::ConnectionNotAllowed.as_u8());
+        assert!(session.await.is_err());
+        assert!(
+            future::timeout(Duration::from_millis(100), target.accept())
+                .await
+                .is_err(),
+            "the target was connected"
+        );
+    }
+
+    #[async_std::test]
+    async fn test_idle_session_closes_the_client() {
+        let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target_addr = target.local_addr().unwrap();
+        let accepted = task::spawn(async move { target.accept().await.unwrap() });
+        let mut config = Config::default();
+        config.set_idle_timeout(Some(Duration::from_millis(100)));
+        let (mut client, session) = session(config).await;
+
+        assert_eq!(connect(&mut client, target_addr).await, 0);
+        let _target = accepted.await;
+        // Still held by the caller, the socket must not keep the connection open
+        let socket = session.await.unwrap();
+        assert_eq!(socket.close_reason(), Some(CloseReason::IdleTimeout));
+
+        let mut buf = [0u8; 1];
+        let read = future::timeout(Duration::from_secs(1), client.read(&mut buf))
+            .await
+            .expect("the connection of the client is still open");
+        assert_eq!(read.unwrap(), 0);
+    }
+
+    #[async_std::test]
+    async fn test_max_session_duration() {
+        let target_addr = echo_target().await;
+        let mut config = Config::default();
+        config
+            .set_idle_timeout(Some(Duration::from_millis(100)))
+            .set_max_session_duration(Some(Duration::from_millis(300)));
+        let (mut client, session) = session(config).await;
+
+        assert_eq!(connect(&mut client, target_addr).await, 0);
+        let start = Instant::now();
+        // Never idle, until the server closes the session
+        let mut buf = [0u8; 1];
+        while start.elapsed() < Duration::from_secs(2) {
+            if client.write_all(b"x").await.is_err() {
+                break;
+            }
+            let read = future::timeout(Duration::from_secs(1), client.read(&mut buf))
+                .await
+                .expect("the session is idle");
+            if !matches!(read, Ok(1)) {
+                break;
+            }
+            task::sleep(Duration::from_millis(20)).await;
+        }
+        assert!(start.elapsed() >= Duration::from_millis(250));
+        assert!(start.elapsed() < Duration::from_secs(2));
+
+        let socket = session.await.unwrap();
+        assert_eq!(socket.close_reason(), Some(CloseReason::MaxDurationReached));
+    }
+
+    /// Target sending back what it receives, until the client closes.
+    async fn echo_target() -> SocketAddr {
+        let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target_addr = target.local_addr().unwrap();
+        task::spawn(async move {
+            let (target, _) = target.accept().await.unwrap();
+            let (mut reader, mut writer) = (&target, &target);
+            futures::io::copy(&mut reader, &mut writer).await.unwrap();
+        });
+        target_addr
+    }
+
+    #[async_std::test]
+    async fn test_session_end_callback() {
+        let target_addr = echo_target().await;
+        let ended = Arc::new(Mutex::new(Vec::new()));
+        let stats = ended.clone();
+        let mut config = Config::default();
+        config
+            .set_authentication(SimpleUserPassword {
+                username: "alice".to_string(),
+                password: "secret".to_string(),
+            })
+            .set_session_end_callback(move |session| stats.lock().unwrap().push(session.clone()));
+        let (mut client, session) = session(config).await;
+
+        client.write_all(&[5, 1, 2]).await.unwrap();
+        let mut method = [0u8; 2];
+        client.read_exact(&mut method).await.unwrap();
+        assert_eq!(method, [5, 2]);
+        client.write_all(b"\x01\x05alice\x06secret").await.unwrap();
+        let mut auth = [0u8; 2];
+        client.read_exact(&mut auth).await.unwrap();
+        assert_eq!(auth, [1, 0]);
+        let mut request = vec![5, 1, 0, 1, 127, 0, 0, 1];
+        request.extend_from_slice(&target_addr.port().to_be_bytes());
+        client.write_all(&request).await.unwrap();
+        let mut reply = [0u8; 10];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply[1], 0);
+
+        client.write_all(&[1u8; 1000]).await.unwrap();
+        let mut echoed = [0u8; 1000];
+        client.read_exact(&mut echoed).await.unwrap();
+        client.write_all(&[2u8; 500]).await.unwrap();
+        let mut echoed = [0u8; 500];
+        client.read_exact(&mut echoed).await.unwrap();
+        let client_addr = client.local_addr().unwrap();
+        client.shutdown(Shutdown::Write).unwrap();
+        let socket = session.await.unwrap();
+
+        let ended = ended.lock().unwrap();
+        assert_eq!(ended.len(), 1);
+        let stats = &ended[0];
+        assert_eq!(stats.id, socket.id());
+        assert_eq!(stats.peer_addr, Some(client_addr));
+        assert_eq!(stats.user.as_deref(), Some("alice"));
+        assert!(matches!(stats.target, Some(TargetAddr::Ip(addr)) if addr == target_addr));
+        assert_eq!(stats.remote_addr, Some(target_addr));
+        assert_eq!(stats.bytes_sent, 1500);
+        assert_eq!(stats.bytes_received, 1500);
+        let handshake = stats.handshake_duration.unwrap();
+        let connect = stats.connect_latency.unwrap();
+        assert!(handshake + connect <= stats.duration);
+        assert_eq!(stats.close_reason, Some(CloseReason::ClientClosed));
+    }
+
+    /// Observer writing down the steps of the sessions, and how many it saw of each session.
+    #[derive(Default, Clone)]
+    struct Recorder(Arc<Mutex<Vec<String>>>);
+
+    /// Steps seen so far, kept in the session context
+    struct Steps(u32);
+
+    impl Recorder {
+        fn record(&self, session: &mut SessionContext, step: String) {
+            let steps = session.get_mut::<Steps>().expect("not accepted");
+            steps.0 += 1;
+            self.0.lock().unwrap().push(step);
+        }
+    }
+
+    impl SessionObserver for Recorder {
+        fn on_accept(&self, session: &mut SessionContext) {
+            assert!(session.insert(Steps(0)).is_none());
+            self.record(session, "accept".to_string());
+        }
+
+        fn on_method_negotiated(&self, session: &mut SessionContext, method: u8) {
+            self.record(session, format!("method {}", method));
+        }
+
+        fn on_auth_success(&self, session: &mut SessionContext, username: &str) {
+            self.record(session, format!("auth {}", username));
+        }
+
+        fn on_request(&self, session: &mut SessionContext, _cmd: Command, target: &TargetAddr) {
+            self.record(session, format!("request {}", target));
+        }
+
+        fn on_dns_resolved(
+            &self,
+            session: &mut SessionContext,
+            domain: &str,
+            addrs: &[SocketAddr],
+        ) {
+            assert!(!addrs.is_empty());
+            self.record(session, format!("dns {}", domain));
+        }
+
+        fn on_connected(&self, session: &mut SessionContext, addr: SocketAddr, _latency: Duration) {
+            self.record(session, format!("connected {}", addr));
+        }
+
+        fn on_connect_failed(&self, session: &mut SessionContext, reply: ReplyError) {
+            self.record(session, format!("failed {}", reply.as_u8()));
+        }
+
+        fn on_close(&self, session: &mut SessionContext, stats: &SessionStats) {
+            let steps = session.get::<Steps>().map(|steps| steps.0);
+            let reason = stats.close_reason.map(|reason| reason.to_string());
+            self.0.lock().unwrap().push(format!(
+                "close {} after {:?} ({:?})",
+                session.id(),
+                steps,
+                reason
+            ));
+        }
+    }
+
+    #[async_std::test]
+    async fn test_observer() {
+        let target_addr = echo_target().await;
+        let recorder = Recorder::default();
+        let mut config = Config::default();
+        config
+            .set_authentication(SimpleUserPassword {
+                username: "alice".to_string(),
+                password: "secret".to_string(),
+            })
+            .add_observer(recorder.clone());
+        let (mut client, session) = session(config).await;
+
+        client.write_all(&[5, 1, 2]).await.unwrap();
+        let mut method = [0u8; 2];
+        client.read_exact(&mut method).await.unwrap();
+        client.write_all(b"\x01\x05alice\x06secret").await.unwrap();
+        let mut auth = [0u8; 2];
+        client.read_exact(&mut auth).await.unwrap();
+        let mut request = b"\x05\x01\x00\x03\x09localhost".to_vec();
+        request.extend_from_slice(&target_addr.port().to_be_bytes());
+        client.write_all(&request).await.unwrap();
+        let mut reply = [0u8; 10];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply[1], 0);
+        client.shutdown(Shutdown::Write).unwrap();
+        let id = session.await.unwrap().id();
+
+        assert_eq!(
+            *recorder.0.lock().unwrap(),
+            [
+                "accept".to_string(),
+                "method 2".to_string(),
+                "auth alice".to_string(),
+                format!("request localhost:{}", target_addr.port()),
+                "dns localhost".to_string(),
+                format!("connected {}", target_addr),
+                format!("close {} after Some(6) (Some(\"client closed\"))", id),
+            ]
+        );
+    }
+
+    #[async_std::test]
+    async fn test_invalid_proxy_header() {
+        let recorder = Recorder::default();
+        let ended = Arc::new(Mutex::new(Vec::new()));
+        let stats = ended.clone();
+        let mut proxy_protocol = ProxyProtocol::new();
+        proxy_protocol.add_trusted("127.0.0.0/8".parse().unwrap());
+        let mut config = Config::default();
+        config
+            .set_proxy_protocol(proxy_protocol)
+            .add_observer(recorder.clone())
+            .set_session_end_callback(move |session| stats.lock().unwrap().push(session.clone()));
+        let (mut client, session) = session(config).await;
+        let balancer = client.local_addr().unwrap();
+
+        // SOCKS right away, without the header
+        client.write_all(&[5, 1, 0]).await.unwrap();
+        assert!(matches!(
+            session.await,
+            Err(SocksError::InvalidProxyHeader(_))
+        ));
+
+        let steps = recorder.0.lock().unwrap();
+        assert_eq!(steps[0], "accept");
+        assert!(steps[1].ends_with("after Some(1) (None)"), "{}", steps[1]);
+        assert_eq!(steps.len(), 2);
+        let ended = ended.lock().unwrap();
+        assert_eq!(ended.len(), 1);
+        assert_eq!(ended[0].peer_addr, Some(balancer));
+        assert!(ended[0].target.is_none());
+    }
+
+    #[async_std::test]
+    async fn test_observer_refused_connect() {
+        let closed = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let closed_addr = closed.local_addr().unwrap();
+        drop(closed);
+        let recorder = Recorder::default();
+        let mut config = Config::default();
+        config.add_observer(recorder.clone());
+        let (mut client, session) = session(config).await;
+
+        let refused = ReplyError::ConnectionRefused.as_u8();
+        assert_eq!(connect(&mut client, closed_addr).await, refused);
+        assert!(matches!(
+            session.await,
+            Err(SocksError::ReplyError(ReplyError::ConnectionRefused))
+        ));
+
+        let steps = recorder.0.lock().unwrap();
+        assert_eq!(
+            steps[..4],
+            [
+                "accept".to_string(),
+                "method 0".to_string(),
+                format!("request {}", closed_addr),
+                format!("failed {}", refused),
+            ]
+        );
+        assert!(steps[4].ends_with("after Some(4) (None)"), "{}", steps[4]);
+        assert_eq!(steps.len(), 5);
+    }
+
+    #[async_std::test]
+    async fn test_half_closed_client_gets_the_response() {
+        let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target_addr = target.local_addr().unwrap();
+        // Replies once the whole request is received, more than a buffer of the relay
+        task::spawn(async move {
+            let (mut target, _) = target.accept().await.unwrap();
+            let mut request = Vec::new();
+            target.read_to_end(&mut request).await.unwrap();
+            target.write_all(&request.repeat(10_000)).await.unwrap();
+        });
+        let (mut client, session) = session(Config::default()).await;
+
+        assert_eq!(connect(&mut client, target_addr).await, 0);
+        client.write_all(b"ping").await.unwrap();
+        client.shutdown(Shutdown::Write).unwrap();
+        let mut response = Vec::new();
+        future::timeout(Duration::from_secs(5), client.read_to_end(&mut response))
+            .await
+            .expect("the target didn't see the end of the request")
+            .unwrap();
+        assert_eq!(response, b"ping".repeat(10_000));
+
+        let socket = session.await.unwrap();
+        assert_eq!(socket.close_reason(), Some(CloseReason::ClientClosed));
+    }
+
+    #[async_std::test]
+    async fn test_transport_without_half_close() {
+        let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target_addr = target.local_addr().unwrap();
+        task::spawn(async move {
+            let (mut target, _) = target.accept().await.unwrap();
+            target.write_all(b"hello").await.unwrap();
+        });
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (stream, _) = listener.accept().await.unwrap();
+        let socket = Socks5Socket::new(Opaque(stream), Arc::new(Config::default()));
+        let session = task::spawn(socket.upgrade_to_socks5());
+
+        assert_eq!(connect(&mut client, target_addr).await, 0);
+        // The target closing ends the session, with `poll_close()`
+        let mut response = Vec::new();
+        future::timeout(Duration::from_secs(5), client.read_to_end(&mut response))
+            .await
+            .expect("the connection of the client is still open")
+            .unwrap();
+        assert_eq!(response, b"hello");
+        let socket = session.await.unwrap();
+        assert_eq!(socket.close_reason(), Some(CloseReason::TargetClosed));
+    }
+
+    #[async_std::test]
+    async fn test_half_closed_target_drains() {
+        let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target_addr = target.local_addr().unwrap();
+        // Done sending first, still receiving
+        let received = task::spawn(async move {
+            let (mut target, _) = target.accept().await.unwrap();
+            target.write_all(b"hello").await.unwrap();
+            target.shutdown(Shutdown::Write).unwrap();
+            let mut received = Vec::new();
+            target.read_to_end(&mut received).await.unwrap();
+            received
+        });
+        let (mut client, session) = session(Config::default()).await;
+
+        assert_eq!(connect(&mut client, target_addr).await, 0);
+        let mut response = Vec::new();
+        future::timeout(Duration::from_secs(5), client.read_to_end(&mut response))
+            .await
+            .expect("the end of the response wasn't relayed")
+            .unwrap();
+        assert_eq!(response, b"hello");
+        client.write_all(b"bye").await.unwrap();
+        client.shutdown(Shutdown::Write).unwrap();
+        assert_eq!(received.await, b"bye");
+
+        let socket = session.await.unwrap();
+        assert_eq!(socket.close_reason(), Some(CloseReason::TargetClosed));
+    }
+
+    #[async_std::test]
+    async fn test_linger_timeout() {
+        let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target_addr = target.local_addr().unwrap();
+        let accepted = task::spawn(async move { target.accept().await.unwrap() });
+        let mut config = Config::default();
+        config.set_linger_timeout(Some(Duration::from_millis(100)));
+        let (mut client, session) = session(config).await;
+
+        assert_eq!(connect(&mut client, target_addr).await, 0);
+        // The target never closes its side
+        let _target = accepted.await;
+        client.shutdown(Shutdown::Write).unwrap();
+
+        let socket = future::timeout(Duration::from_secs(5), session)
+            .await
+            .expect("the session should end after the linger timeout")
+            .unwrap();
+        assert_eq!(socket.close_reason(), Some(CloseReason::LingerTimeout));
+        let mut buf = [0u8; 1];
+        assert_eq!(client.read(&mut buf).await.unwrap(), 0);
+    }
+
+    #[async_std::test]
+    async fn test_throttled_session_isnt_idle() {
+        let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target_addr = target.local_addr().unwrap();
+        let received = task::spawn(async move {
+            let (mut target, _) = target.accept().await.unwrap();
+            let mut received = Vec::new();
+            target.read_to_end(&mut received).await.unwrap();
+            received.len()
+        });
+        let limiter = BandwidthLimiter::default();
+        limiter.set_connection_limits(Limits {
+            upload: Some(Limit::new(1000, 1000)),
+            download: None,
+        });
+        let mut config = Config::default();
+        config
+            .set_idle_timeout(Some(Duration::from_millis(100)))
+            .set_bandwidth_limiter(limiter);
+        let (mut client, session) = session(config).await;
+
+        assert_eq!(connect(&mut client, target_addr).await, 0);
+        // Held back by the throttle for half a second, then idle once relayed
+        client.write_all(&[0u8; 1500]).await.unwrap();
+        assert_eq!(received.await, 1500);
+        session.await.unwrap();
+    }
+
+    #[async_std::test]
+    async fn test_shutdown() {
+        let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target_addr = target.local_addr().unwrap();
+        let server = Socks5Server::bind("127.0.0.1:0").await.unwrap();
+        let server_addr = match server.local_addrs().unwrap()[0].1 {
+            ListenerAddr::Tcp(addr) => addr,
+            _ => unreachable!(),
+        };
+        let handle = server.shutdown_handle();
+        let mut incoming = server.incoming();
+
+        let mut draining = TcpStream::connect(server_addr).await.unwrap();
+        let mut forced = TcpStream::connect(server_addr).await.unwrap();
+        let mut sockets = Vec::new();
+        for _ in 0..2 {
+            sockets.push(incoming.next().await.unwrap().unwrap());
+        }
+        // Counted as soon as they are accepted, before the sessions start
+        assert_eq!(handle.active_sessions(), 2);
+
+        let stopping = handle.clone();
+        let shutdown = task::spawn(async move { stopping.shutdown(Duration::from_secs(1)).await });
+        while !handle.is_shutting_down() {
+            task::yield_now().await;
+        }
+        assert!(incoming.next().await.is_none());
+        let sessions: Vec<_> = sockets
+            .into_iter()
+            .map(|socket| task::spawn(socket.upgrade_to_socks5()))
+            .collect();
+
+        // Still served while draining
+        assert_eq!(connect(&mut draining, target_addr).await, 0);
+        assert_eq!(connect(&mut forced, target_addr).await, 0);
+        let (target_draining, _) = target.accept().await.unwrap();
+        let (_target_forced, _) = target.accept().await.unwrap();
+        drop(draining);
+        drop(target_draining);
+
+        assert_eq!(shutdown.await, 1);
+        let mut reasons = Vec::new();
+        for session in sessions {
+            reasons.push(session.await.unwrap().close_reason().unwrap());
+        }
+        reasons.sort_by_key(|reason| *reason == CloseReason::Shutdown);
+        assert_ne!(reasons[0], CloseReason::Shutdown);
+        assert_eq!(reasons[1], CloseReason::Shutdown);
+        assert_eq!(handle.active_sessions(), 0);
+    }
+
+    #[async_std::test]
+    async fn test_several_listeners() {
+        let mut server = Socks5Server::new();
+        server
+            .add_listener("open", "127.0.0.1:0", Config::default())
+            .await
+            .unwrap();
+        let mut config = Config::default();
+        config.set_authentication(SimpleUserPassword {
+            username: "alice".to_string(),
+            password: "secret".to_string(),
+        });
+        server
+            .add_listener("password", "127.0.0.1:0", config)
+            .await
+            .unwrap();
+        let addrs: Vec<_> = server
+            .local_addrs()
+            .unwrap()
+            .into_iter()
+            .map(|(_, addr)| match addr {
+                ListenerAddr::Tcp(addr) => addr,
+                _ => unreachable!(),
+            })
+            .collect();
+        let handle = server.shutdown_handle();
+        let mut incoming = server.incoming();
+
+        // Both listeners have clients waiting, neither is starved
+        let mut clients = Vec::new();
+        for &addr in addrs.iter().chain(&addrs) {
+            clients.push(TcpStream::connect(addr).await.unwrap());
+        }
+        let mut sessions = Vec::new();
+        let mut labels = Vec::new();
+        for _ in 0..4 {
+            let socket = incoming.next().await.unwrap().unwrap();
+            labels.push(socket.listener.as_deref().unwrap().to_string());
+            sessions.push(socket);
+        }
+        assert_eq!(labels, ["open", "password", "open", "password"]);
+        assert_eq!(handle.active_sessions(), 4);
+
+        let mut methods = Vec::new();
+        let sessions: Vec<_> = sessions
+            .into_iter()
+            .map(|socket| task::spawn(socket.upgrade_to_socks5()))
+            .collect();
+        for mut client in clients {
+            client.write_all(&[5, 1, 0]).await.unwrap();
+            let mut method = [0u8; 2];
+            client.read_exact(&mut method).await.unwrap();
+            methods.push(method[1]);
+        }
+        // Each listener with its own config
+        assert_eq!(methods, [0, 0xff, 0, 0xff]);
+        for session in sessions {
+            let _ = session.await;
+        }
+
+        assert_eq!(handle.shutdown(Duration::from_secs(1)).await, 0);
+        assert!(incoming.next().await.is_none());
+    }
+
+    #[async_std::test]
+    async fn test_terminate_closes_both_sides() {
+        let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target_addr = target.local_addr().unwrap();
+        let accepted = task::spawn(async move { target.accept().await.unwrap().0 });
+        let registry = Arc::new(SessionRegistry::default());
+        let mut config = Config::default();
+        config.set_session_registry(registry.clone());
+        let (mut client, session) = session(config).await;
+
+        assert_eq!(connect(&mut client, target_addr).await, 0);
+        let mut target = accepted.await;
+        assert_eq!(registry.terminate(&SessionFilter::All), 1);
+
+        let socket = future::timeout(Duration::from_secs(5), session)
+            .await
+            .expect("the session should be terminated")
+            .unwrap();
+        assert_eq!(socket.close_reason(), Some(CloseReason::Terminated));
+        let mut buf = [0u8; 1];
+        assert_eq!(client.read(&mut buf).await.unwrap(), 0);
+        let read = future::timeout(Duration::from_secs(1), target.read(&mut buf))
+            .await
+            .expect("the connection of the target is still open");
+        assert_eq!(read.unwrap(), 0);
+    }
+
+    #[cfg(unix)]
+    #[async_std::test]
+    async fn test_terminate_while_connecting() {
+        let (target_addr, _target, _filler) = blackhole();
+        let registry = Arc::new(SessionRegistry::default());
+        let mut config = Config::default();
+        config.set_session_registry(registry.clone());
+        let (mut client, session) = session(config).await;
+
+        client.write_all(&[5, 1, 0]).await.unwrap();
+        let mut method = [0u8; 2];
+        client.read_exact(&mut method).await.unwrap();
+        let mut request = vec![5, 1, 0, 1, 127, 0, 0, 1];
+        request.extend_from_slice(&target_addr.port().to_be_bytes());
+        client.write_all(&request).await.unwrap();
+        // Connecting, for the request timeout
+        while registry
+            .list()
+            .first()
+            .and_then(|s| s.target.as_ref())
+            .is_none()
+        {
+            task::sleep(Duration::from_millis(10)).await;
+        }
+        task::sleep(Duration::from_millis(100)).await;
+        assert_eq!(registry.terminate(&SessionFilter::All), 1);
+
+        let result = future::timeout(Duration::from_secs(5), session)
+            .await
+            .expect("the session should be terminated");
+        assert!(matches!(result, Err(SocksError::SessionTerminated)));
+        // Closed without a reply
+        let mut reply = Vec::new();
+        client.read_to_end(&mut reply).await.unwrap();
+        assert!(reply.is_empty());
+    }
+
+    #[async_std::test]
+    async fn test_read_timeout_covers_the_address() {
+        let mut config = Config::default();
+        config.set_read_timeout(Some(Duration::from_millis(100)));
+        let (mut client, session) = session(config).await;
+
+        client.write_all(&[5, 1, 0]).await.unwrap();
+        let mut method = [0u8; 2];
+        client.read_exact(&mut method).await.unwrap();
+        // The header of the request, then nothing
+        client.write_all(&[5, 1, 0, 1, 127]).await.unwrap();
+
+        let result = future::timeout(Duration::from_secs(5), session)
+            .await
+            .expect("the session should time out");
+        assert!(result.is_err());
+    }
 }
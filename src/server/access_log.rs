@@ -0,0 +1,462 @@
+//! Access log, one record per session, written once the session is over.
+//!
+//! Records are formatted as JSON lines or in a format close to the common log format, and
+//! written to an [`AccessLogSink`], eg. a [`FileSink`] rotating the file once it grows too big.
+//!
+//! ```no_run
+//! use fast_socks5::server::access_log::{AccessLog, AccessLogFormat, FileSink};
+//! use fast_socks5::server::Config;
+//!
+//! # fn run() -> fast_socks5::Result<()> {
+//! let mut sink = FileSink::open("/var/log/socks5/access.log")?;
+//! sink.set_max_size(Some(100 * 1024 * 1024)).set_max_files(10);
+//!
+//! let mut config = Config::default();
+//! config.add_observer(AccessLog::new(AccessLogFormat::JsonLines, sink));
+//! # Ok(())
+//! # }
+//! ```
+use crate::server::observer::{SessionContext, SessionObserver};
+use crate::server::registry::SessionId;
+use crate::server::stats::SessionStats;
+use crate::server::CloseReason;
+use crate::util::date::{civil_from_days, SECONDS_PER_DAY};
+use crate::util::target_addr::TargetAddr;
+use crate::{consts, Command, ReplyError, Result};
+use anyhow::Context;
+use std::fmt::Write as _;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, LineWriter, Write};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+/// What happened during a session.
+#[derive(Debug, Clone)]
+pub struct AccessRecord {
+    /// When the session started.
+    pub time: SystemTime,
+    pub id: SessionId,
+    pub peer_addr: Option<SocketAddr>,
+    /// Label of the listener which accepted the client.
+    pub listener: Option<String>,
+    pub user: Option<String>,
+    pub cmd: Option<Command>,
+    /// Target as requested by the client.
+    pub target: Option<TargetAddr>,
+    /// Address the server connected to.
+    pub remote_addr: Option<SocketAddr>,
+    /// Reply code sent to the request, `None` if the session ended before.
+    pub reply: Option<u8>,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    pub duration: Duration,
+    pub close_reason: Option<CloseReason>,
+}
+
+/// Date and time, in UTC: `(year, month, day, hours, minutes, seconds)`.
+fn utc(time: SystemTime) -> (u64, u64, u64, u64, u64, u64) {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let (year, month, day) = civil_from_days(secs / SECONDS_PER_DAY);
+    let secs_of_day = secs % SECONDS_PER_DAY;
+
+    (
+        year,
+        month,
+        day,
+        secs_of_day / 3600,
+        secs_of_day / 60 % 60,
+        secs_of_day % 60,
+    )
+}
+
+fn cmd_name(cmd: Command) -> &'static str {
+    match cmd {
+        Command::TcpConnect => "CONNECT",
+        Command::TcpBind => "BIND",
+        Command::UdpAssociate => "UDP_ASSOCIATE",
+    }
+}
+
+/// JSON string, with the characters that need to be escaped.
+fn json_string(value: &str) -> String {
+    let mut json = String::with_capacity(value.len() + 2);
+    json.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\r' => json.push_str("\\r"),
+            '\t' => json.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(json, "\\u{:04x}", c as u32);
+            }
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
+
+fn json_or_null<T: ToString>(value: Option<T>) -> String {
+    value.map_or_else(
+        || "null".to_string(),
+        |value| json_string(&value.to_string()),
+    )
+}
+
+/// Text for the common log format, without spaces, quotes nor control characters (eg. the
+/// escape sequences of a terminal).
+fn common_field<T: ToString>(value: Option<T>) -> String {
+    match value {
+        Some(value) => value
+            .to_string()
+            .chars()
+            .map(|c| {
+                if c.is_whitespace() || c.is_control() || c == '"' {
+                    '_'
+                } else {
+                    c
+                }
+            })
+            .collect(),
+        None => "-".to_string(),
+    }
+}
+
+impl AccessRecord {
+    /// One JSON object, eg.
+    /// `{"time":"2024-05-01T12:00:00Z","session":1,"peer":"10.0.0.1:51000","user":"alice",...}`
+    pub fn to_json(&self) -> String {
+        let (year, month, day, hours, minutes, seconds) = utc(self.time);
+        format!(
+            "{{\"time\":\"{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z\",\"session\":{},\"peer\":{},\
+             \"listener\":{},\"user\":{},\"command\":{},\"target\":{},\"remote_addr\":{},\"reply\":{},\
+             \"bytes_sent\":{},\"bytes_received\":{},\"duration_ms\":{},\"close_reason\":{}}}",
+            year,
+            month,
+            day,
+            hours,
+            minutes,
+            seconds,
+            self.id,
+            json_or_null(self.peer_addr),
+            json_or_null(self.listener.as_ref()),
+            json_or_null(self.user.as_ref()),
+            json_or_null(self.cmd.map(cmd_name)),
+            json_or_null(self.target.as_ref()),
+            json_or_null(self.remote_addr),
+            self.reply
+                .map_or_else(|| "null".to_string(), |code| code.to_string()),
+            self.bytes_sent,
+            self.bytes_received,
+            self.duration.as_millis(),
+            json_or_null(self.close_reason),
+        )
+    }
+
+    /// Close to the common log format, with the bytes of both directions and the session
+    /// details appended, eg.
+    /// `10.0.0.1 - alice [01/May/2024:12:00:00 +0000] "CONNECT example.com:443" 0 512 4096 1500ms 93.184.216.34:443 client_closed 1`
+    pub fn to_common(&self) -> String {
+        let (year, month, day, hours, minutes, seconds) = utc(self.time);
+        format!(
+            "{} - {} [{:02}/{}/{:04}:{:02}:{:02}:{:02} +0000] \"{} {}\" {} {} {} {}ms {} {} {}",
+            common_field(self.peer_addr.map(|addr| addr.ip())),
+            common_field(self.user.as_ref()),
+            day,
+            MONTHS[month as usize - 1],
+            year,
+            hours,
+            minutes,
+            seconds,
+            self.cmd.map_or("-", cmd_name),
+            common_field(self.target.as_ref()),
+            common_field(self.reply),
+            self.bytes_sent,
+            self.bytes_received,
+            self.duration.as_millis(),
+            common_field(self.remote_addr),
+            common_field(self.close_reason),
+            self.id,
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccessLogFormat {
+    /// `AccessRecord::to_json()`
+    JsonLines,
+    /// `AccessRecord::to_common()`
+    Common,
+}
+
+/// Destination of the access log.
+///
+/// Called from the task of the session once it's over, a slow sink should hand the lines over
+/// to another thread.
+pub trait AccessLogSink: Send + Sync {
+    /// `line` is the record formatted as configured, without the trailing newline.
+    fn write(&self, record: &AccessRecord, line: &str) -> io::Result<()>;
+}
+
+/// The sink can be shared by several access logs, like the config of every listener.
+impl<S: AccessLogSink + ?Sized> AccessLogSink for Arc<S> {
+    fn write(&self, record: &AccessRecord, line: &str) -> io::Result<()> {
+        (**self).write(record, line)
+    }
+}
+
+/// Access log written to a file, rotated once it reaches a maximum size:
+/// `access.log` becomes `access.log.1`, `access.log.1` becomes `access.log.2`, etc.
+pub struct FileSink {
+    path: PathBuf,
+    max_size: Option<u64>,
+    max_files: usize,
+    file: Mutex<(LineWriter<File>, u64)>,
+}
+
+impl FileSink {
+    /// Append to the file, created if it doesn't exist.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let (file, size) =
+            Self::open_file(&path).with_context(|| format!("Can't open {}", path.display()))?;
+
+        Ok(FileSink {
+            path,
+            max_size: None,
+            max_files: 5,
+            file: Mutex::new((LineWriter::new(file), size)),
+        })
+    }
+
+    fn open_file(path: &Path) -> io::Result<(File, u64)> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let size = file.metadata()?.len();
+        Ok((file, size))
+    }
+
+    /// Rotate the file once it reaches this size in bytes, never by default.
+    pub fn set_max_size(&mut self, max_size: Option<u64>) -> &mut Self {
+        self.max_size = max_size;
+        self
+    }
+
+    /// Rotated files kept besides the current one, 5 by default.
+    pub fn set_max_files(&mut self, max_files: usize) -> &mut Self {
+        self.max_files = max_files;
+        self
+    }
+
+    fn rotated_path(&self, index: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{}", index));
+        path.into()
+    }
+
+    fn rotate(&self, file: &mut (LineWriter<File>, u64)) -> io::Result<()> {
+        file.0.flush()?;
+
+        if self.max_files == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            for index in (1..self.max_files).rev() {
+                let from = self.rotated_path(index);
+                if from.exists() {
+                    fs::rename(&from, self.rotated_path(index + 1))?;
+                }
+            }
+            fs::rename(&self.path, self.rotated_path(1))?;
+        }
+
+        let (new_file, size) = Self::open_file(&self.path)?;
+        *file = (LineWriter::new(new_file), size);
+        Ok(())
+    }
+}
+
+impl AccessLogSink for FileSink {
+    fn write(&self, _record: &AccessRecord, line: &str) -> io::Result<()> {
+        let mut file = self.file.lock().unwrap();
+
+        if let Some(max_size) = self.max_size {
+            if file.1 > 0 && file.1 + line.len() as u64 + 1 > max_size {
+                self.rotate(&mut file)?;
+            }
+        }
+
+        file.0.write_all(line.as_bytes())?;
+        file.0.write_all(b"\n")?;
+        file.1 += line.len() as u64 + 1;
+        Ok(())
+    }
+}
+
+/// Kept in the session context, shared by the access logs of the session
+#[derive(Default, Clone, Copy)]
+struct Pending {
+    time: Option<SystemTime>,
+    reply: Option<u8>,
+    cmd: Option<Command>,
+}
+
+/// Session observer writing the access log, see [`Config::add_observer()`].
+///
+/// [`Config::add_observer()`]: crate::server::Config::add_observer
+pub struct AccessLog {
+    format: AccessLogFormat,
+    sink: Box<dyn AccessLogSink>,
+}
+
+impl AccessLog {
+    pub fn new<S: AccessLogSink + 'static>(format: AccessLogFormat, sink: S) -> Self {
+        AccessLog {
+            format,
+            sink: Box::new(sink),
+        }
+    }
+
+    fn pending<'a>(&self, session: &'a mut SessionContext) -> &'a mut Pending {
+        if session.get::<Pending>().is_none() {
+            session.insert(Pending::default());
+        }
+        session.get_mut::<Pending>().unwrap()
+    }
+}
+
+impl SessionObserver for AccessLog {
+    fn on_accept(&self, session: &mut SessionContext) {
+        self.pending(session).time = Some(SystemTime::now());
+    }
+
+    fn on_request(&self, session: &mut SessionContext, cmd: Command, _target: &TargetAddr) {
+        self.pending(session).cmd = Some(cmd);
+    }
+
+    fn on_connected(&self, session: &mut SessionContext, _addr: SocketAddr, _latency: Duration) {
+        self.pending(session).reply = Some(consts::SOCKS5_REPLY_SUCCEEDED);
+    }
+
+    fn on_connect_failed(&self, session: &mut SessionContext, reply: ReplyError) {
+        self.pending(session).reply = Some(reply.as_u8());
+    }
+
+    fn on_close(&self, session: &mut SessionContext, stats: &SessionStats) {
+        let pending = session.get::<Pending>().copied().unwrap_or_default();
+        let record = AccessRecord {
+            time: pending
+                .time
+                .unwrap_or_else(|| SystemTime::now() - stats.duration),
+            id: stats.id,
+            peer_addr: stats.peer_addr,
+            listener: stats.listener.clone(),
+            user: stats.user.clone(),
+            cmd: pending.cmd,
+            target: stats.target.clone(),
+            remote_addr: stats.remote_addr,
+            reply: pending.reply,
+            bytes_sent: stats.bytes_sent,
+            bytes_received: stats.bytes_received,
+            duration: stats.duration,
+            close_reason: stats.close_reason,
+        };
+
+        let line = match self.format {
+            AccessLogFormat::JsonLines => record.to_json(),
+            AccessLogFormat::Common => record.to_common(),
+        };
+        if let Err(e) = self.sink.write(&record, &line) {
+            error!("Can't write the access log: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{AccessLogSink, AccessRecord, FileSink};
+    use crate::server::CloseReason;
+    use crate::util::target_addr::TargetAddr;
+    use crate::Command;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    fn record() -> AccessRecord {
+        AccessRecord {
+            time: UNIX_EPOCH + Duration::from_secs(1_714_564_800),
+            id: 7,
+            peer_addr: Some("10.0.0.1:51000".parse().unwrap()),
+            listener: Some("public".to_string()),
+            user: Some("al\"ice".to_string()),
+            cmd: Some(Command::TcpConnect),
+            target: Some(TargetAddr::Domain("example.com".to_string(), 443)),
+            remote_addr: None,
+            reply: Some(0),
+            bytes_sent: 512,
+            bytes_received: 4096,
+            duration: Duration::from_millis(1500),
+            close_reason: Some(CloseReason::ClientClosed),
+        }
+    }
+
+    #[test]
+    fn test_formats() {
+        assert_eq!(
+            record().to_json(),
+            "{\"time\":\"2024-05-01T12:00:00Z\",\"session\":7,\"peer\":\"10.0.0.1:51000\",\
+             \"listener\":\"public\",\"user\":\"al\\\"ice\",\"command\":\"CONNECT\",\"target\":\"example.com:443\",\
+             \"remote_addr\":null,\"reply\":0,\"bytes_sent\":512,\"bytes_received\":4096,\
+             \"duration_ms\":1500,\"close_reason\":\"client closed\"}"
+        );
+        assert_eq!(
+            record().to_common(),
+            "10.0.0.1 - al_ice [01/May/2024:12:00:00 +0000] \"CONNECT example.com:443\" 0 512 \
+             4096 1500ms - client_closed 7"
+        );
+    }
+
+    #[test]
+    fn test_control_characters() {
+        let mut record = record();
+        record.user = Some("a\u{1b}[2Jb\u{7f}c\u{9b}d\u{2028}e\0".to_string());
+        record.target = Some(TargetAddr::Domain("evil\r\n.com".to_string(), 443));
+        assert_eq!(
+            record.to_common(),
+            "10.0.0.1 - a_[2Jb_c_d_e_ [01/May/2024:12:00:00 +0000] \"CONNECT evil__.com:443\" 0 \
+             512 4096 1500ms - client_closed 7"
+        );
+    }
+
+    #[test]
+    fn test_rotation() {
+        let dir = std::env::temp_dir().join(format!("fast-socks5-access-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("access.log");
+
+        let mut sink = FileSink::open(&path).unwrap();
+        sink.set_max_size(Some(10)).set_max_files(2);
+        for line in &["first", "second", "third", "fourth"] {
+            sink.write(&record(), line).unwrap();
+        }
+
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "fourth\n");
+        assert_eq!(
+            std::fs::read_to_string(dir.join("access.log.1")).unwrap(),
+            "third\n"
+        );
+        assert_eq!(
+            std::fs::read_to_string(dir.join("access.log.2")).unwrap(),
+            "second\n"
+        );
+        assert!(!dir.join("access.log.3").exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
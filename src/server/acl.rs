@@ -0,0 +1,406 @@
+//! Access control for the requests received by the server.
+//!
+//! An [`AccessControl`] is an ordered list of [`Rule`]s, evaluated once the request has been read.
+//! The first rule matching the request decides whether it is allowed or denied, if none matches,
+//! the default action applies.
+//!
+//! ```
+//! use fast_socks5::server::acl::{AccessControl, Action, Rule};
+//!
+//! let mut no_ssh = Rule::deny();
+//! no_ssh.add_port_range(22..=22);
+//!
+//! let mut intranet = Rule::allow();
+//! intranet
+//!     .add_source("10.0.0.0/8".parse().unwrap())
+//!     .add_domain("*.corp.example.com".parse().unwrap());
+//!
+//! let mut acl = AccessControl::new(Action::Deny);
+//! acl.add_rule(no_ssh).add_rule(intranet);
+//! ```
+use crate::server::unix::PeerCredentials;
+use crate::util::target_addr::TargetAddr;
+use crate::Command;
+use ipnet::IpNet;
+use std::fmt;
+use std::net::IpAddr;
+use std::ops::RangeInclusive;
+use std::str::FromStr;
+
+/// What to do with a request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    Allow,
+    Deny,
+}
+
+/// Matches the domain name requested by the client, case-insensitively.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DomainPattern {
+    /// `example.com` matches only `example.com`.
+    Exact(String),
+    /// `.example.com` matches `example.com` and all of its subdomains.
+    Suffix(String),
+    /// `*` matches any sequence of characters and `?` a single one,
+    /// eg. `*.example.com` or `api-??.example.com`.
+    Glob(String),
+}
+
+impl DomainPattern {
+    pub fn matches(&self, domain: &str) -> bool {
+        let domain = normalize_domain(domain);
+
+        match self {
+            DomainPattern::Exact(exact) => domain == *exact,
+            DomainPattern::Suffix(suffix) => {
+                domain == *suffix
+                    || (domain.ends_with(suffix.as_str())
+                        && domain.as_bytes()[domain.len() - suffix.len() - 1] == b'.')
+            }
+            DomainPattern::Glob(glob) => glob_match(glob.as_bytes(), domain.as_bytes()),
+        }
+    }
+}
+
+impl FromStr for DomainPattern {
+    type Err = std::convert::Infallible;
+
+    /// A leading `.` makes it a suffix, `*` or `?` makes it a glob, otherwise it's an exact match.
+    fn from_str(pattern: &str) -> Result<Self, Self::Err> {
+        Ok(if let Some(suffix) = pattern.strip_prefix('.') {
+            DomainPattern::Suffix(normalize_domain(suffix))
+        } else if pattern.contains(['*', '?']) {
+            DomainPattern::Glob(normalize_domain(pattern))
+        } else {
+            DomainPattern::Exact(normalize_domain(pattern))
+        })
+    }
+}
+
+impl fmt::Display for DomainPattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DomainPattern::Exact(domain) | DomainPattern::Glob(domain) => f.write_str(domain),
+            DomainPattern::Suffix(suffix) => write!(f, ".{}", suffix),
+        }
+    }
+}
+
+/// Lowercase and strip the trailing dot of fully qualified names.
+fn normalize_domain(domain: &str) -> String {
+    domain.trim_end_matches('.').to_ascii_lowercase()
+}
+
+/// Iterative wildcard matching, backtracking only to the last `*` seen.
+fn glob_match(pattern: &[u8], text: &[u8]) -> bool {
+    let (mut p, mut t) = (0, 0);
+    let mut last_star: Option<(usize, usize)> = None;
+
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == b'?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == b'*' {
+            last_star = Some((p, t));
+            p += 1;
+        } else if let Some((star_p, star_t)) = last_star {
+            // let the last `*` swallow one more character
+            p = star_p + 1;
+            t = star_t + 1;
+            last_star = Some((star_p, star_t + 1));
+        } else {
+            return false;
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+/// A rule matches a request when every criterion set on it matches.
+/// Within a criterion, matching any of its values is enough. A criterion left empty matches
+/// everything.
+///
+/// IP destinations only match requests made to an IP address, and domains only match requests
+/// made to a domain name: the rules are evaluated before any DNS resolution.
+#[derive(Debug, Clone)]
+pub struct Rule {
+    action: Action,
+    sources: Vec<IpNet>,
+    destinations: Vec<IpNet>,
+    domains: Vec<DomainPattern>,
+    ports: Vec<RangeInclusive<u16>>,
+    commands: Vec<Command>,
+    users: Vec<String>,
+    groups: Vec<String>,
+    uids: Vec<u32>,
+    gids: Vec<u32>,
+}
+
+impl Rule {
+    pub fn new(action: Action) -> Self {
+        Rule {
+            action,
+            sources: Vec::new(),
+            destinations: Vec::new(),
+            domains: Vec::new(),
+            ports: Vec::new(),
+            commands: Vec::new(),
+            users: Vec::new(),
+            groups: Vec::new(),
+            uids: Vec::new(),
+            gids: Vec::new(),
+        }
+    }
+
+    pub fn allow() -> Self {
+        Self::new(Action::Allow)
+    }
+
+    pub fn deny() -> Self {
+        Self::new(Action::Deny)
+    }
+
+    pub fn action(&self) -> Action {
+        self.action
+    }
+
+    /// Match clients connecting from this network.
+    pub fn add_source(&mut self, network: IpNet) -> &mut Self {
+        self.sources.push(network);
+        self
+    }
+
+    /// Match requests to an IP address within this network.
+    pub fn add_destination(&mut self, network: IpNet) -> &mut Self {
+        self.destinations.push(network);
+        self
+    }
+
+    /// Match requests to a domain name.
+    pub fn add_domain(&mut self, pattern: DomainPattern) -> &mut Self {
+        self.domains.push(pattern);
+        self
+    }
+
+    /// Match requests to a destination port within this range, eg. `1..=1023`.
+    pub fn add_port_range(&mut self, ports: RangeInclusive<u16>) -> &mut Self {
+        self.ports.push(ports);
+        self
+    }
+
+    pub fn add_command(&mut self, command: Command) -> &mut Self {
+        self.commands.push(command);
+        self
+    }
+
+    /// Match requests made by this authenticated user.
+    pub fn add_user<S: Into<String>>(&mut self, username: S) -> &mut Self {
+        self.users.push(username.into());
+        self
+    }
+
+    /// Match requests made by an authenticated user belonging to this group.
+    pub fn add_group<S: Into<String>>(&mut self, group: S) -> &mut Self {
+        self.groups.push(group.into());
+        self
+    }
+
+    /// Match clients connected through a Unix socket, run by this user id.
+    pub fn add_uid(&mut self, uid: u32) -> &mut Self {
+        self.uids.push(uid);
+        self
+    }
+
+    /// Match clients connected through a Unix socket, run with this group id.
+    pub fn add_gid(&mut self, gid: u32) -> &mut Self {
+        self.gids.push(gid);
+        self
+    }
+
+    pub fn matches(&self, request: &AclRequest<'_>) -> bool {
+        self.matches_source(request)
+            && self.matches_destination(request)
+            && self.matches_port(request)
+            && (self.commands.is_empty() || self.commands.contains(&request.command))
+            && (self.users.is_empty()
+                || request
+                    .user
+                    .is_some_and(|user| self.users.iter().any(|u| u == user)))
+            && (self.groups.is_empty() || request.groups.iter().any(|g| self.groups.contains(g)))
+            && (self.uids.is_empty()
+                || request
+                    .peer_credentials
+                    .is_some_and(|peer| self.uids.contains(&peer.uid)))
+            && (self.gids.is_empty()
+                || request
+                    .peer_credentials
+                    .is_some_and(|peer| self.gids.contains(&peer.gid)))
+    }
+
+    fn matches_source(&self, request: &AclRequest<'_>) -> bool {
+        if self.sources.is_empty() {
+            return true;
+        }
+
+        match request.source {
+            Some(ip) => self.sources.iter().any(|net| net.contains(&ip)),
+            None => false,
+        }
+    }
+
+    fn matches_destination(&self, request: &AclRequest<'_>) -> bool {
+        if self.destinations.is_empty() && self.domains.is_empty() {
+            return true;
+        }
+
+        match request.target {
+            TargetAddr::Ip(addr) => self.destinations.iter().any(|net| net.contains(&addr.ip())),
+            TargetAddr::Domain(domain, _) => self.domains.iter().any(|p| p.matches(domain)),
+        }
+    }
+
+    fn matches_port(&self, request: &AclRequest<'_>) -> bool {
+        let port = match request.target {
+            TargetAddr::Ip(addr) => addr.port(),
+            TargetAddr::Domain(_, port) => *port,
+        };
+
+        self.ports.is_empty() || self.ports.iter().any(|range| range.contains(&port))
+    }
+}
+
+/// What the rules are evaluated against.
+#[derive(Debug, Clone)]
+pub struct AclRequest<'a> {
+    /// IP of the client, if known.
+    pub source: Option<IpAddr>,
+    /// Credentials of the process of the client connected through a Unix socket.
+    pub peer_credentials: Option<PeerCredentials>,
+    pub command: Command,
+    /// Destination as requested by the client.
+    pub target: &'a TargetAddr,
+    /// Username of the authenticated client.
+    pub user: Option<&'a str>,
+    /// Groups of the authenticated client.
+    pub groups: &'a [String],
+}
+
+/// First-match list of rules.
+#[derive(Debug, Clone)]
+pub struct AccessControl {
+    rules: Vec<Rule>,
+    default_action: Action,
+    dry_run: bool,
+}
+
+impl AccessControl {
+    /// `default_action` applies when no rule matches the request.
+    pub fn new(default_action: Action) -> Self {
+        AccessControl {
+            rules: Vec::new(),
+            default_action,
+            dry_run: false,
+        }
+    }
+
+    /// Append a rule, rules are evaluated in the order they have been added.
+    pub fn add_rule(&mut self, rule: Rule) -> &mut Self {
+        self.rules.push(rule);
+        self
+    }
+
+    /// In dry-run mode, requests that would be denied are only logged, and let through.
+    /// Useful to try out a new ruleset on production traffic.
+    pub fn set_dry_run(&mut self, value: bool) -> &mut Self {
+        self.dry_run = value;
+        self
+    }
+
+    pub fn is_dry_run(&self) -> bool {
+        self.dry_run
+    }
+
+    /// Index of the first rule matching the request.
+    pub fn matching_rule(&self, request: &AclRequest<'_>) -> Option<usize> {
+        self.rules.iter().position(|rule| rule.matches(request))
+    }
+
+    pub fn evaluate(&self, request: &AclRequest<'_>) -> Action {
+        match self.matching_rule(request) {
+            Some(index) => self.rules[index].action,
+            None => self.default_action,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn request<'a>(target: &'a TargetAddr, user: Option<&'a str>) -> AclRequest<'a> {
+        AclRequest {
+            source: Some("10.1.2.3".parse().unwrap()),
+            peer_credentials: None,
+            command: Command::TcpConnect,
+            target,
+            user,
+            groups: &[],
+        }
+    }
+
+    #[test]
+    fn test_domain_patterns() {
+        let exact: DomainPattern = "Example.com.".parse().unwrap();
+        assert!(exact.matches("example.com"));
+        assert!(!exact.matches("www.example.com"));
+
+        let suffix: DomainPattern = ".example.com".parse().unwrap();
+        assert!(suffix.matches("example.com"));
+        assert!(suffix.matches("a.b.EXAMPLE.com"));
+        assert!(!suffix.matches("badexample.com"));
+
+        let glob: DomainPattern = "api-??.*.example.com".parse().unwrap();
+        assert!(glob.matches("api-01.eu.example.com"));
+        assert!(!glob.matches("api-1.eu.example.com"));
+        assert!(!glob.matches("api-01.example.com"));
+    }
+
+    #[test]
+    fn test_first_match() {
+        let mut no_ssh = Rule::deny();
+        no_ssh.add_port_range(22..=22);
+        let mut lan = Rule::allow();
+        lan.add_source("10.0.0.0/8".parse().unwrap());
+        let mut admin_only = Rule::allow();
+        admin_only.add_user("admin");
+
+        let mut acl = AccessControl::new(Action::Deny);
+        acl.add_rule(no_ssh).add_rule(lan);
+
+        let ssh = TargetAddr::Domain("host".to_string(), 22);
+        let https = TargetAddr::Ip("1.1.1.1:443".parse().unwrap());
+        assert_eq!(acl.evaluate(&request(&ssh, None)), Action::Deny);
+        assert_eq!(acl.evaluate(&request(&https, None)), Action::Allow);
+
+        let mut outsider = request(&https, None);
+        outsider.source = Some("192.168.1.1".parse().unwrap());
+        assert_eq!(acl.evaluate(&outsider), Action::Deny);
+
+        acl.add_rule(admin_only);
+        outsider.user = Some("admin");
+        assert_eq!(acl.evaluate(&outsider), Action::Allow);
+
+        let mut sidecar = Rule::allow();
+        sidecar.add_uid(1000);
+        acl.add_rule(sidecar);
+        let mut local = request(&https, None);
+        local.source = None;
+        assert_eq!(acl.evaluate(&local), Action::Deny);
+        local.peer_credentials = Some(PeerCredentials {
+            uid: 1000,
+            gid: 1000,
+            pid: Some(42),
+        });
+        assert_eq!(acl.evaluate(&local), Action::Allow);
+    }
+}
@@ -0,0 +1,216 @@
+//! Listening sockets inherited from the parent process instead of bound by the server: systemd
+//! socket activation, privileged ports without running as root, or a new version of the server
+//! taking over the sockets of the old one without refusing any client.
+//!
+//! The sockets are passed with the protocol of systemd: starting from the file descriptor 3,
+//! `LISTEN_FDS` of them, named by `LISTEN_FDNAMES` (`FileDescriptorName=` of the socket unit),
+//! for the process `LISTEN_PID`.
+//!
+//! The file descriptors are only listed, the caller takes them over:
+//!
+//! ```no_run
+//! # use fast_socks5::server::activation::listen_fds;
+//! # use fast_socks5::server::{Config, Socks5Server};
+//! # use std::os::unix::io::{FromRawFd, OwnedFd};
+//! # fn run() -> std::io::Result<()> {
+//! let mut server = Socks5Server::new();
+//! for socket in listen_fds()? {
+//!     // Safety: first thing in `main()`, nothing else owns the inherited sockets yet
+//!     let fd = unsafe { OwnedFd::from_raw_fd(socket.fd) };
+//!     server.add_inherited_listener(socket.name, fd, Config::default())?;
+//! }
+//! # Ok(())
+//! # }
+//! ```
+use crate::server::unix::BoundUnixListener;
+use crate::server::Acceptor;
+use rustix::io::{fcntl_setfd, FdFlags};
+use rustix::net::{getsockname, sockopt, AddressFamily, SocketType};
+use std::env;
+use std::io;
+use std::os::unix::io::{OwnedFd, RawFd};
+
+/// First file descriptor passed by systemd, the following ones are contiguous.
+pub const LISTEN_FDS_START: RawFd = 3;
+
+/// A socket passed by the parent process.
+#[derive(Debug)]
+pub struct InheritedFd {
+    /// `unknown` if the parent process didn't name it
+    pub name: String,
+    /// Not owned yet, see `listen_fds()`
+    pub fd: RawFd,
+}
+
+/// List the sockets passed by systemd, or any parent process following its protocol. Empty if
+/// none were passed to this process.
+///
+/// The variables are removed from the environment so that the child processes don't take the
+/// same sockets, hence only the first call returns them.
+///
+/// The caller takes the file descriptors over with `OwnedFd::from_raw_fd()`, which is only sound
+/// if nothing else in the process owns them: call it at the start of `main()`, before any file
+/// or socket is opened.
+pub fn listen_fds() -> io::Result<Vec<InheritedFd>> {
+    let pid = match env::var("LISTEN_PID") {
+        Ok(pid) => pid,
+        Err(_) => return Ok(Vec::new()),
+    };
+    let fds = env::var("LISTEN_FDS").unwrap_or_default();
+    let names = env::var("LISTEN_FDNAMES").ok();
+    for var in &["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+        env::remove_var(var);
+    }
+
+    let sockets = parse(&pid, &fds, names.as_deref(), std::process::id())?;
+    Ok(sockets
+        .into_iter()
+        .map(|(fd, name)| {
+            debug!("Inherited the socket {} (fd {})", name, fd);
+            InheritedFd { name, fd }
+        })
+        .collect())
+}
+
+/// File descriptors and names passed in the variables, none if they are meant for another
+/// process.
+fn parse(
+    pid: &str,
+    fds: &str,
+    names: Option<&str>,
+    own_pid: u32,
+) -> io::Result<Vec<(RawFd, String)>> {
+    let pid: u32 = pid
+        .parse()
+        .map_err(|_| invalid(format!("invalid LISTEN_PID `{}`", pid)))?;
+    if pid != own_pid {
+        debug!("Ignoring the sockets passed to the process {}", pid);
+        return Ok(Vec::new());
+    }
+
+    let count: RawFd = fds
+        .parse()
+        .ok()
+        .filter(|count| *count >= 0 && *count <= RawFd::MAX - LISTEN_FDS_START)
+        .ok_or_else(|| invalid(format!("invalid LISTEN_FDS `{}`", fds)))?;
+    let names: Vec<String> = match names {
+        Some(names) => names.split(':').map(str::to_string).collect(),
+        None => vec!["unknown".to_string(); count as usize],
+    };
+    if names.len() != count as usize {
+        return Err(invalid(format!(
+            "{} names in LISTEN_FDNAMES for {} sockets",
+            names.len(),
+            count
+        )));
+    }
+
+    Ok((LISTEN_FDS_START..LISTEN_FDS_START + count)
+        .zip(names)
+        .collect())
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
+
+/// Accept the clients of this socket, if it's a listening TCP or Unix socket.
+pub(super) fn acceptor(fd: OwnedFd) -> io::Result<Acceptor> {
+    // Inherited, it isn't closed on exec yet
+    fcntl_setfd(&fd, FdFlags::CLOEXEC)?;
+    if sockopt::socket_type(&fd)? != SocketType::STREAM {
+        return Err(invalid(format!("{:?} isn't a stream socket", fd)));
+    }
+    // Apple platforms can't tell, accepting will fail instead
+    #[cfg(not(target_vendor = "apple"))]
+    if !sockopt::socket_acceptconn(&fd)? {
+        return Err(invalid(format!("{:?} isn't listening", fd)));
+    }
+
+    match getsockname(&fd)?.address_family() {
+        AddressFamily::INET | AddressFamily::INET6 => {
+            let listener = std::net::TcpListener::from(fd);
+            Ok(Acceptor::Tcp(listener.into()))
+        }
+        AddressFamily::UNIX => {
+            let listener = std::os::unix::net::UnixListener::from(fd);
+            Ok(Acceptor::Unix(BoundUnixListener::inherited(
+                listener.into(),
+            )))
+        }
+        family => Err(invalid(format!(
+            "{:?} has an unsupported address family {:?}",
+            fd, family
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{parse, LISTEN_FDS_START};
+    use crate::server::{Config, ListenerAddr, Socks5Server};
+    use async_std::os::unix::net::UnixStream;
+    use async_std::task;
+    use futures::StreamExt;
+    use std::os::unix::io::OwnedFd;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(parse("42", "2", None, 41).unwrap(), vec![]);
+        assert_eq!(
+            parse("42", "2", Some("socks:admin"), 42).unwrap(),
+            vec![
+                (LISTEN_FDS_START, "socks".to_string()),
+                (LISTEN_FDS_START + 1, "admin".to_string())
+            ]
+        );
+        assert_eq!(
+            parse("42", "1", None, 42).unwrap(),
+            vec![(LISTEN_FDS_START, "unknown".to_string())]
+        );
+        assert!(parse("42", "2", Some("socks"), 42).is_err());
+        assert!(parse("42", "-1", None, 42).is_err());
+        assert!(parse("me", "1", None, 42).is_err());
+    }
+
+    #[test]
+    fn test_inherited_listener() {
+        task::block_on(async {
+            let mut server = Socks5Server::new();
+            let tcp = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            let tcp_addr = tcp.local_addr().unwrap();
+            server
+                .add_inherited_listener("tcp", OwnedFd::from(tcp), Config::default())
+                .unwrap();
+
+            let path = std::env::temp_dir()
+                .join(format!("fast-socks5-inherited-{}.sock", std::process::id()));
+            let unix = std::os::unix::net::UnixListener::bind(&path).unwrap();
+            server
+                .add_inherited_listener("unix", OwnedFd::from(unix), Config::default())
+                .unwrap();
+
+            let not_listening = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+            assert!(server
+                .add_inherited_listener("udp", OwnedFd::from(not_listening), Config::default())
+                .is_err());
+
+            assert_eq!(
+                server.local_addrs().unwrap(),
+                vec![
+                    ("tcp", ListenerAddr::Tcp(tcp_addr)),
+                    ("unix", ListenerAddr::Unix(path.clone()))
+                ]
+            );
+
+            let _client = UnixStream::connect(&path).await.unwrap();
+            let socket = server.incoming().next().await.unwrap().unwrap();
+            assert!(socket.peer_credentials.is_some() || !cfg!(target_os = "linux"));
+
+            // The file belongs to the parent process
+            drop(server);
+            assert!(path.exists());
+            std::fs::remove_file(&path).unwrap();
+        });
+    }
+}
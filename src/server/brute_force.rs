@@ -0,0 +1,475 @@
+//! Throttling of the username/password authentication.
+//!
+//! Failed attempts are counted per source IP and per username. Each failure delays the reply
+//! exponentially, and once too many failures happened, the IP or the username is locked out for
+//! a while: its attempts are rejected without even checking the password.
+use ipnet::IpNet;
+use std::collections::HashMap;
+use std::fmt;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// The expired entries are purged at most this often.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// What failures are counted against.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum LockoutKey {
+    Ip(IpAddr),
+    Username(String),
+}
+
+impl fmt::Display for LockoutKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LockoutKey::Ip(ip) => write!(f, "IP {}", ip),
+            LockoutKey::Username(username) => write!(f, "username `{}`", username),
+        }
+    }
+}
+
+/// Emitted when an IP or a username gets locked out.
+#[derive(Debug, Clone)]
+pub struct LockoutEvent {
+    pub key: LockoutKey,
+    /// Consecutive failures which triggered the lockout.
+    pub failures: u32,
+    pub duration: Duration,
+}
+
+struct Entry {
+    failures: u32,
+    last_failure: Instant,
+    locked_until: Option<Instant>,
+}
+
+impl Entry {
+    fn is_expired(&self, now: Instant, window: Duration) -> bool {
+        match self.locked_until {
+            Some(until) => until <= now,
+            None => now.duration_since(self.last_failure) >= window,
+        }
+    }
+}
+
+type LockoutListener = Arc<dyn Fn(&LockoutEvent) + Send + Sync>;
+
+struct Entries {
+    map: HashMap<LockoutKey, Entry>,
+    last_prune: Instant,
+}
+
+impl Entries {
+    /// Forget the expired entries, then the oldest ones until a tenth of the room is free, so
+    /// that it doesn't happen on every new key.
+    fn make_room(&mut self, now: Instant, window: Duration, max_entries: usize) {
+        if now.duration_since(self.last_prune) >= PRUNE_INTERVAL {
+            self.map.retain(|_, entry| !entry.is_expired(now, window));
+            self.last_prune = now;
+        }
+        if self.map.len() < max_entries {
+            return;
+        }
+
+        self.map.retain(|_, entry| !entry.is_expired(now, window));
+        self.last_prune = now;
+        let target = max_entries - max_entries / 10 - 1;
+        if self.map.len() > target {
+            // The lockouts go last
+            let mut oldest: Vec<_> = self
+                .map
+                .iter()
+                .map(|(key, entry)| {
+                    let locked = entry.locked_until.is_some_and(|until| until > now);
+                    (locked, entry.last_failure, key.clone())
+                })
+                .collect();
+            oldest.sort_unstable_by_key(|(locked, last_failure, _)| (*locked, *last_failure));
+            let evicted = self.map.len() - target;
+            warn!(
+                "{} failed authentications tracked, forgetting the {} oldest",
+                self.map.len(),
+                evicted
+            );
+            for (_, _, key) in oldest.into_iter().take(evicted) {
+                self.map.remove(&key);
+            }
+        }
+    }
+}
+
+pub struct BruteForceProtection {
+    max_failures: u32,
+    base_delay: Duration,
+    max_delay: Duration,
+    lockout_duration: Duration,
+    failure_window: Duration,
+    allowlist: Vec<IpNet>,
+    max_entries: usize,
+    on_lockout: Option<LockoutListener>,
+    entries: Mutex<Entries>,
+}
+
+impl Default for BruteForceProtection {
+    fn default() -> Self {
+        BruteForceProtection {
+            max_failures: 5,
+            base_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(10),
+            lockout_duration: Duration::from_secs(15 * 60),
+            failure_window: Duration::from_secs(15 * 60),
+            allowlist: Vec::new(),
+            max_entries: 100_000,
+            on_lockout: None,
+            entries: Mutex::new(Entries {
+                map: HashMap::new(),
+                last_prune: Instant::now(),
+            }),
+        }
+    }
+}
+
+impl BruteForceProtection {
+    /// Consecutive failures before a lockout. Default is 5.
+    pub fn set_max_failures(&mut self, n: u32) -> &mut Self {
+        self.max_failures = n;
+        self
+    }
+
+    /// The reply to the n-th failure is delayed by `base * 2^(n-1)`, up to `max`.
+    /// Default is 500ms, up to 10 seconds.
+    pub fn set_delay(&mut self, base: Duration, max: Duration) -> &mut Self {
+        self.base_delay = base;
+        self.max_delay = max;
+        self
+    }
+
+    /// Default is 15 minutes.
+    pub fn set_lockout_duration(&mut self, duration: Duration) -> &mut Self {
+        self.lockout_duration = duration;
+        self
+    }
+
+    /// Failures are forgotten after this much time without a new one. Default is 15 minutes.
+    pub fn set_failure_window(&mut self, window: Duration) -> &mut Self {
+        self.failure_window = window;
+        self
+    }
+
+    /// Clients from this network are never throttled nor locked out.
+    pub fn add_allowed(&mut self, network: IpNet) -> &mut Self {
+        self.allowlist.push(network);
+        self
+    }
+
+    /// IPs and usernames tracked at most, the oldest ones are forgotten beyond, the lockouts
+    /// last. Default is 100 000.
+    pub fn set_max_entries(&mut self, max_entries: usize) -> &mut Self {
+        self.max_entries = max_entries.max(1);
+        self
+    }
+
+    /// Called every time an IP or a username gets locked out.
+    pub fn on_lockout<F>(&mut self, listener: F) -> &mut Self
+    where
+        F: Fn(&LockoutEvent) + Send + Sync + 'static,
+    {
+        self.on_lockout = Some(Arc::new(listener));
+        self
+    }
+
+    fn is_allowed(&self, ip: Option<IpAddr>) -> bool {
+        ip.is_some_and(|ip| self.allowlist.iter().any(|net| net.contains(&ip)))
+    }
+
+    fn keys(ip: Option<IpAddr>, username: &str) -> impl Iterator<Item = LockoutKey> {
+        ip.map(LockoutKey::Ip)
+            .into_iter()
+            .chain(Some(LockoutKey::Username(username.to_string())))
+    }
+
+    /// If the IP or the username is locked out, returns which one.
+    pub fn check(&self, ip: Option<IpAddr>, username: &str) -> Option<LockoutKey> {
+        if self.is_allowed(ip) {
+            return None;
+        }
+
+        let now = Instant::now();
+        let entries = self.entries.lock().unwrap();
+
+        Self::keys(ip, username).find(|key| {
+            entries
+                .map
+                .get(key)
+                .and_then(|entry| entry.locked_until)
+                .is_some_and(|until| until > now)
+        })
+    }
+
+    /// Count a failed attempt, returns how long to wait before replying to the client.
+    pub fn record_failure(&self, ip: Option<IpAddr>, username: &str) -> Duration {
+        if self.is_allowed(ip) {
+            return Duration::from_secs(0);
+        }
+
+        let now = Instant::now();
+        let mut events = Vec::new();
+        let mut failures = 0;
+        {
+            let mut entries = self.entries.lock().unwrap();
+
+            for key in Self::keys(ip, username) {
+                if !entries.map.contains_key(&key) {
+                    entries.make_room(now, self.failure_window, self.max_entries);
+                }
+                let entry = entries.map.entry(key.clone()).or_insert(Entry {
+                    failures: 0,
+                    last_failure: now,
+                    locked_until: None,
+                });
+                if entry.is_expired(now, self.failure_window) {
+                    entry.failures = 0;
+                    entry.locked_until = None;
+                }
+
+                entry.failures += 1;
+                entry.last_failure = now;
+                failures = failures.max(entry.failures);
+
+                if entry.failures >= self.max_failures && entry.locked_until.is_none() {
+                    entry.locked_until = Some(now + self.lockout_duration);
+                    events.push(LockoutEvent {
+                        key,
+                        failures: entry.failures,
+                        duration: self.lockout_duration,
+                    });
+                }
+            }
+        }
+
+        for event in events {
+            warn!(
+                "{} locked out for {:?} after {} failed authentications",
+                event.key, event.duration, event.failures
+            );
+            if let Some(listener) = &self.on_lockout {
+                listener(&event);
+            }
+        }
+
+        let factor = 2u32.saturating_pow(failures.saturating_sub(1));
+        self.base_delay
+            .checked_mul(factor)
+            .map_or(self.max_delay, |delay| delay.min(self.max_delay))
+    }
+
+    /// Forget the failures of the IP and the username.
+    pub fn record_success(&self, ip: Option<IpAddr>, username: &str) {
+        let mut entries = self.entries.lock().unwrap();
+        for key in Self::keys(ip, username) {
+            entries.map.remove(&key);
+        }
+    }
+
+    /// IPs and usernames currently locked out, with the remaining lockout time.
+    pub fn lockouts(&self) -> Vec<(LockoutKey, Duration)> {
+        let now = Instant::now();
+        let entries = self.entries.lock().unwrap();
+
+        entries
+            .map
+            .iter()
+            .filter_map(|(key, entry)| match entry.locked_until {
+                Some(until) if until > now => Some((key.clone(), until - now)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Lift the lockout of an IP or a username, and forget its failures.
+    pub fn unlock(&self, key: &LockoutKey) {
+        self.entries.lock().unwrap().map.remove(key);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{BruteForceProtection, LockoutKey, PRUNE_INTERVAL};
+    use std::net::IpAddr;
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    fn ip(ip: &str) -> Option<IpAddr> {
+        Some(ip.parse().unwrap())
+    }
+
+    #[test]
+    fn test_delay() {
+        let mut protection = BruteForceProtection::default();
+        protection
+            .set_max_failures(100)
+            .set_delay(Duration::from_millis(100), Duration::from_millis(500));
+
+        let delays: Vec<_> = (0..5)
+            .map(|_| protection.record_failure(ip("10.0.0.1"), "alice"))
+            .collect();
+        assert_eq!(delays, [100, 200, 400, 500, 500].map(Duration::from_millis));
+        // Counted per username as well
+        assert_eq!(
+            protection.record_failure(ip("10.0.0.2"), "alice"),
+            Duration::from_millis(500)
+        );
+        assert_eq!(
+            protection.record_failure(ip("10.0.0.2"), "bob"),
+            Duration::from_millis(200)
+        );
+
+        // Doesn't overflow
+        for _ in 0..100 {
+            protection.record_failure(ip("10.0.0.3"), "carol");
+        }
+        assert_eq!(
+            protection.record_failure(ip("10.0.0.3"), "carol"),
+            Duration::from_millis(500)
+        );
+    }
+
+    #[test]
+    fn test_lockout() {
+        let lockouts = Arc::new(AtomicU32::new(0));
+        let counter = lockouts.clone();
+        let mut protection = BruteForceProtection::default();
+        protection.set_max_failures(3).on_lockout(move |event| {
+            assert_eq!(event.failures, 3);
+            counter.fetch_add(1, Ordering::Relaxed);
+        });
+
+        for _ in 0..2 {
+            protection.record_failure(ip("10.0.0.1"), "alice");
+            assert_eq!(protection.check(ip("10.0.0.1"), "alice"), None);
+        }
+        protection.record_failure(ip("10.0.0.1"), "alice");
+        // Both the IP and the username
+        assert_eq!(lockouts.load(Ordering::Relaxed), 2);
+        assert_eq!(protection.lockouts().len(), 2);
+
+        assert_eq!(
+            protection.check(ip("10.0.0.1"), "bob"),
+            Some(LockoutKey::Ip("10.0.0.1".parse().unwrap()))
+        );
+        assert_eq!(
+            protection.check(ip("10.0.0.2"), "alice"),
+            Some(LockoutKey::Username("alice".to_string()))
+        );
+        assert_eq!(protection.check(ip("10.0.0.2"), "bob"), None);
+
+        protection.unlock(&LockoutKey::Username("alice".to_string()));
+        assert_eq!(protection.check(ip("10.0.0.2"), "alice"), None);
+        assert!(protection.check(ip("10.0.0.1"), "alice").is_some());
+        // Its failures are forgotten too
+        assert_eq!(
+            protection.record_failure(ip("10.0.0.2"), "alice"),
+            Duration::from_millis(500)
+        );
+    }
+
+    #[test]
+    fn test_allowlist() {
+        let mut protection = BruteForceProtection::default();
+        protection
+            .set_max_failures(1)
+            .add_allowed("10.0.0.0/8".parse().unwrap());
+
+        for _ in 0..3 {
+            assert_eq!(
+                protection.record_failure(ip("10.0.0.1"), "alice"),
+                Duration::from_secs(0)
+            );
+        }
+        assert_eq!(protection.check(ip("10.0.0.1"), "alice"), None);
+        assert!(protection.lockouts().is_empty());
+
+        protection.record_failure(ip("192.168.0.1"), "alice");
+        assert!(protection.check(ip("192.168.0.1"), "alice").is_some());
+        // Even if the username is locked out
+        assert_eq!(protection.check(ip("10.0.0.1"), "alice"), None);
+    }
+
+    #[test]
+    fn test_max_entries() {
+        let mut protection = BruteForceProtection::default();
+        protection.set_max_failures(2).set_max_entries(10);
+        protection.record_failure(None, "alice");
+        protection.record_failure(None, "alice");
+
+        // Spraying usernames
+        for i in 0..100 {
+            protection.record_failure(None, &format!("user{}", i));
+            assert!(protection.entries.lock().unwrap().map.len() <= 10);
+        }
+        assert!(protection.check(None, "alice").is_some());
+        // The most recent ones are still tracked
+        assert_eq!(
+            protection.record_failure(None, "user99"),
+            Duration::from_secs(1)
+        );
+        assert_eq!(
+            protection.record_failure(None, "user0"),
+            Duration::from_millis(500)
+        );
+    }
+
+    #[test]
+    fn test_prune() {
+        let mut protection = BruteForceProtection::default();
+        protection.set_failure_window(Duration::from_millis(10));
+        for i in 0..5 {
+            protection.record_failure(None, &format!("user{}", i));
+        }
+        thread::sleep(Duration::from_millis(20));
+        protection.record_failure(None, "alice");
+        assert_eq!(protection.entries.lock().unwrap().map.len(), 6);
+
+        // Once the interval passed
+        {
+            let mut entries = protection.entries.lock().unwrap();
+            entries.last_prune = entries.last_prune.checked_sub(PRUNE_INTERVAL).unwrap();
+        }
+        protection.record_failure(None, "bob");
+        let entries = protection.entries.lock().unwrap();
+        let mut keys: Vec<_> = entries.map.keys().map(ToString::to_string).collect();
+        keys.sort();
+        assert_eq!(keys, ["username `alice`", "username `bob`"]);
+    }
+
+    #[test]
+    fn test_expiry() {
+        let mut protection = BruteForceProtection::default();
+        protection
+            .set_max_failures(2)
+            .set_failure_window(Duration::from_millis(50))
+            .set_lockout_duration(Duration::from_millis(50));
+
+        protection.record_failure(ip("10.0.0.1"), "alice");
+        thread::sleep(Duration::from_millis(60));
+        // The first failure is forgotten
+        assert_eq!(
+            protection.record_failure(ip("10.0.0.1"), "alice"),
+            Duration::from_millis(500)
+        );
+        assert_eq!(protection.check(ip("10.0.0.1"), "alice"), None);
+
+        protection.record_failure(ip("10.0.0.1"), "alice");
+        assert!(protection.check(ip("10.0.0.1"), "alice").is_some());
+        thread::sleep(Duration::from_millis(60));
+        assert_eq!(protection.check(ip("10.0.0.1"), "alice"), None);
+        assert!(protection.lockouts().is_empty());
+        assert_eq!(
+            protection.record_failure(ip("10.0.0.1"), "alice"),
+            Duration::from_millis(500)
+        );
+    }
+}
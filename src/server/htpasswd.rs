@@ -0,0 +1,230 @@
+//! Users stored in an htpasswd-style file, one `username:hash` per line.
+//!
+//! Supported hashes are bcrypt (`$2y$`, `$2b$`, `$2a$`), argon2 (`$argon2id$`, `$argon2i$`,
+//! `$argon2d$`), SHA-crypt (`$5$`, `$6$`) and MD5-crypt (`$1$`). Such a file can be generated with
+//! `htpasswd -B -c users.htpasswd admin` or `mkpasswd -m sha-512`.
+//!
+//! The file is reloaded automatically when it changes, a new version with an error is ignored
+//! and the previous users are kept.
+use crate::server::{async_trait, AsyncAuthentication, AuthRequest, Identity};
+use crate::{Result, SocksError};
+use anyhow::Context;
+use argon2::{password_hash::PasswordHash, Argon2, PasswordVerifier};
+use async_std::{fs, task};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant, SystemTime};
+use subtle::ConstantTimeEq;
+
+const SUPPORTED_HASHES: &[&str] = &[
+    "$2y$",
+    "$2b$",
+    "$2a$",
+    "$argon2id$",
+    "$argon2i$",
+    "$argon2d$",
+    "$5$",
+    "$6$",
+    "$1$",
+];
+
+type Users = Arc<HashMap<String, String>>;
+
+struct State {
+    users: Users,
+    /// Modification time & length of the file loaded
+    version: Option<(SystemTime, u64)>,
+    last_check: Instant,
+}
+
+/// [`AsyncAuthentication`] backed by an htpasswd file.
+pub struct HtpasswdFile {
+    path: PathBuf,
+    state: RwLock<State>,
+    check_interval: Duration,
+}
+
+impl HtpasswdFile {
+    /// Load the users from the file, fails if the file can't be read or contains an error.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let content = std::fs::read_to_string(&path)
+            .with_context(|| format!("Can't read {}", path.display()))?;
+        let metadata = std::fs::metadata(&path)?;
+
+        Ok(HtpasswdFile {
+            state: RwLock::new(State {
+                users: Arc::new(parse(&content)?),
+                version: metadata.modified().ok().map(|m| (m, metadata.len())),
+                last_check: Instant::now(),
+            }),
+            path,
+            check_interval: Duration::from_secs(1),
+        })
+    }
+
+    /// How often the file is checked for changes, at most. Default is 1 second.
+    pub fn set_check_interval(&mut self, interval: Duration) -> &mut Self {
+        self.check_interval = interval;
+        self
+    }
+
+    /// Number of users loaded.
+    pub fn len(&self) -> usize {
+        self.state.read().unwrap().users.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Reload the file if it has been modified since it was loaded, then return the users.
+    async fn users(&self) -> Users {
+        {
+            let state = self.state.read().unwrap();
+            if state.last_check.elapsed() < self.check_interval {
+                return state.users.clone();
+            }
+        }
+        self.state.write().unwrap().last_check = Instant::now();
+
+        let version = match fs::metadata(&self.path).await {
+            Ok(metadata) => metadata.modified().ok().map(|m| (m, metadata.len())),
+            Err(e) => {
+                error!("Can't check {} for changes: {}", self.path.display(), e);
+                return self.state.read().unwrap().users.clone();
+            }
+        };
+
+        if version == self.state.read().unwrap().version {
+            return self.state.read().unwrap().users.clone();
+        }
+
+        let reloaded = match fs::read_to_string(&self.path).await {
+            Ok(content) => parse(&content),
+            Err(e) => Err(e.into()),
+        };
+
+        let mut state = self.state.write().unwrap();
+        state.version = version;
+        match reloaded {
+            Ok(users) => {
+                info!("{} reloaded, {} users", self.path.display(), users.len());
+                state.users = Arc::new(users);
+            }
+            Err(e) => error!(
+                "Can't reload {}, keeping the previous users: {:#}",
+                self.path.display(),
+                e
+            ),
+        }
+
+        state.users.clone()
+    }
+}
+
+#[async_trait]
+impl AsyncAuthentication for HtpasswdFile {
+    async fn authenticate(&self, request: AuthRequest<'_>) -> Result<Option<Identity>> {
+        let users = self.users().await;
+        let username = request.username.to_string();
+        let password = request.password.to_string();
+
+        // hashing is CPU bound (on purpose), keep it off the executor
+        let valid = task::spawn_blocking(move || match users.get(&username) {
+            Some(hash) => verify(&password, hash),
+            None => {
+                // don't reveal whether the user exists by answering faster
+                if let Some(hash) = users.values().next() {
+                    verify(&password, hash);
+                }
+                false
+            }
+        })
+        .await;
+
+        Ok(if valid {
+            Some(Identity::new(request.username))
+        } else {
+            None
+        })
+    }
+}
+
+fn parse(content: &str) -> Result<HashMap<String, String>> {
+    let mut users = HashMap::new();
+
+    for (number, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let error =
+            |reason: &str| SocksError::Other(anyhow::anyhow!("line {}: {}", number + 1, reason));
+        let (username, hash) = line
+            .split_once(':')
+            .ok_or_else(|| error("expected `username:hash`"))?;
+
+        if username.is_empty() {
+            return Err(error("empty username"));
+        }
+        if !SUPPORTED_HASHES
+            .iter()
+            .any(|prefix| hash.starts_with(prefix))
+        {
+            return Err(error("unsupported hash format"));
+        }
+        if users
+            .insert(username.to_string(), hash.to_string())
+            .is_some()
+        {
+            return Err(error("duplicated username"));
+        }
+    }
+
+    Ok(users)
+}
+
+fn verify(password: &str, hash: &str) -> bool {
+    if hash.starts_with("$argon2") {
+        return match PasswordHash::new(hash) {
+            Ok(hash) => Argon2::default()
+                .verify_password(password.as_bytes(), &hash)
+                .is_ok(),
+            Err(_) => false,
+        };
+    }
+
+    match pwhash::unix::crypt(password, hash) {
+        Ok(computed) => computed.as_bytes().ct_eq(hash.as_bytes()).into(),
+        Err(_) => false,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{parse, verify};
+
+    #[test]
+    fn test_verify() {
+        let users = parse(&format!(
+            "# comment\nalice:{}\n\nbob:{}\n",
+            pwhash::bcrypt::hash("alice-pw").unwrap(),
+            pwhash::sha512_crypt::hash("bob-pw").unwrap(),
+        ))
+        .unwrap();
+
+        assert!(verify("alice-pw", &users["alice"]));
+        assert!(!verify("bob-pw", &users["alice"]));
+        assert!(verify("bob-pw", &users["bob"]));
+        assert!(!verify("", &users["bob"]));
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let err = parse("alice:$6$salt$hash\nbob:plaintext\n").unwrap_err();
+        assert_eq!(err.to_string(), "line 2: unsupported hash format");
+    }
+}
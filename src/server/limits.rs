@@ -0,0 +1,232 @@
+//! Caps on the number of concurrent sessions, globally, per client IP and per user.
+//!
+//! A session holds its slots from the beginning of `upgrade_to_socks5()` until the
+//! `Socks5Socket` is dropped. The per-user slot is only taken once the client authenticated.
+use crate::ReplyError;
+use std::collections::HashMap;
+use std::fmt;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+
+/// What to do with a session exceeding a limit.
+#[derive(Debug, Clone, Copy)]
+pub enum OverLimitAction {
+    /// Close the connection right away, before the handshake when possible.
+    Refuse,
+    /// Read the request, then answer it with this reply,
+    /// eg. `GeneralFailure` or `ConnectionNotAllowed`.
+    Reply(ReplyError),
+}
+
+/// The limit a session exceeded.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LimitExceeded {
+    Global(usize),
+    PerIp(IpAddr, usize),
+    PerUser(String, usize),
+}
+
+impl fmt::Display for LimitExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LimitExceeded::Global(max) => write!(f, "{} sessions in total", max),
+            LimitExceeded::PerIp(ip, max) => write!(f, "{} sessions from {}", max, ip),
+            LimitExceeded::PerUser(user, max) => write!(f, "{} sessions of `{}`", max, user),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Counts {
+    total: usize,
+    per_ip: HashMap<IpAddr, usize>,
+    per_user: HashMap<String, usize>,
+}
+
+/// Decrement a count, and forget the key once it reaches zero.
+fn release<K: std::hash::Hash + Eq>(counts: &mut HashMap<K, usize>, key: &K) {
+    if let Some(count) = counts.get_mut(key) {
+        *count -= 1;
+        if *count == 0 {
+            counts.remove(key);
+        }
+    }
+}
+
+/// Limits on the concurrent sessions, unlimited by default.
+pub struct ConnectionLimits {
+    max_total: Option<usize>,
+    max_per_ip: Option<usize>,
+    max_per_user: Option<usize>,
+    action: OverLimitAction,
+    counts: Mutex<Counts>,
+}
+
+impl Default for ConnectionLimits {
+    fn default() -> Self {
+        ConnectionLimits {
+            max_total: None,
+            max_per_ip: None,
+            max_per_user: None,
+            action: OverLimitAction::Reply(ReplyError::ConnectionNotAllowed),
+            counts: Mutex::new(Counts::default()),
+        }
+    }
+}
+
+impl ConnectionLimits {
+    pub fn set_max_connections(&mut self, max: Option<usize>) -> &mut Self {
+        self.max_total = max;
+        self
+    }
+
+    pub fn set_max_connections_per_ip(&mut self, max: Option<usize>) -> &mut Self {
+        self.max_per_ip = max;
+        self
+    }
+
+    /// Only applies to authenticated clients.
+    pub fn set_max_connections_per_user(&mut self, max: Option<usize>) -> &mut Self {
+        self.max_per_user = max;
+        self
+    }
+
+    /// Default is to reply `ConnectionNotAllowed`.
+    pub fn set_over_limit_action(&mut self, action: OverLimitAction) -> &mut Self {
+        self.action = action;
+        self
+    }
+
+    pub fn over_limit_action(&self) -> OverLimitAction {
+        self.action
+    }
+
+    /// Number of sessions running.
+    pub fn active(&self) -> usize {
+        self.counts.lock().unwrap().total
+    }
+
+    pub fn active_for_ip(&self, ip: IpAddr) -> usize {
+        let counts = self.counts.lock().unwrap();
+        counts.per_ip.get(&ip).copied().unwrap_or(0)
+    }
+
+    pub fn active_for_user(&self, user: &str) -> usize {
+        let counts = self.counts.lock().unwrap();
+        counts.per_user.get(user).copied().unwrap_or(0)
+    }
+
+    /// Take a slot for a new session, from this IP if known.
+    pub(crate) fn acquire(
+        self: &Arc<Self>,
+        ip: Option<IpAddr>,
+    ) -> Result<ConnectionSlot, LimitExceeded> {
+        let mut counts = self.counts.lock().unwrap();
+
+        if let Some(max) = self.max_total {
+            if counts.total >= max {
+                return Err(LimitExceeded::Global(max));
+            }
+        }
+        if let (Some(max), Some(ip)) = (self.max_per_ip, ip) {
+            if counts.per_ip.get(&ip).copied().unwrap_or(0) >= max {
+                return Err(LimitExceeded::PerIp(ip, max));
+            }
+        }
+
+        counts.total += 1;
+        if let Some(ip) = ip {
+            *counts.per_ip.entry(ip).or_insert(0) += 1;
+        }
+
+        Ok(ConnectionSlot {
+            limits: self.clone(),
+            ip,
+        })
+    }
+
+    /// Take a slot for a session of this user.
+    pub(crate) fn acquire_user(self: &Arc<Self>, user: &str) -> Result<UserSlot, LimitExceeded> {
+        let mut counts = self.counts.lock().unwrap();
+        let count = counts.per_user.entry(user.to_string()).or_insert(0);
+
+        if let Some(max) = self.max_per_user {
+            if *count >= max {
+                if *count == 0 {
+                    counts.per_user.remove(user);
+                }
+                return Err(LimitExceeded::PerUser(user.to_string(), max));
+            }
+        }
+        *count += 1;
+
+        Ok(UserSlot {
+            limits: self.clone(),
+            user: user.to_string(),
+        })
+    }
+}
+
+/// Counted in the global and per-IP sessions until dropped.
+pub(crate) struct ConnectionSlot {
+    limits: Arc<ConnectionLimits>,
+    ip: Option<IpAddr>,
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        let mut counts = self.limits.counts.lock().unwrap();
+        counts.total -= 1;
+        if let Some(ip) = &self.ip {
+            release(&mut counts.per_ip, ip);
+        }
+    }
+}
+
+/// Counted in the sessions of the user until dropped.
+pub(crate) struct UserSlot {
+    limits: Arc<ConnectionLimits>,
+    user: String,
+}
+
+impl Drop for UserSlot {
+    fn drop(&mut self) {
+        let mut counts = self.limits.counts.lock().unwrap();
+        release(&mut counts.per_user, &self.user);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{ConnectionLimits, LimitExceeded};
+    use std::sync::Arc;
+
+    #[test]
+    fn test_slots() {
+        let mut limits = ConnectionLimits::default();
+        limits
+            .set_max_connections(Some(3))
+            .set_max_connections_per_ip(Some(2))
+            .set_max_connections_per_user(Some(1));
+        let limits = Arc::new(limits);
+        let ip = "10.0.0.1".parse().unwrap();
+
+        let first = limits.acquire(Some(ip)).unwrap();
+        let _second = limits.acquire(Some(ip)).unwrap();
+        assert_eq!(
+            limits.acquire(Some(ip)).err(),
+            Some(LimitExceeded::PerIp(ip, 2))
+        );
+        let _third = limits.acquire(None).unwrap();
+        assert_eq!(limits.acquire(None).err(), Some(LimitExceeded::Global(3)));
+
+        drop(first);
+        assert_eq!(limits.active(), 2);
+        assert_eq!(limits.active_for_ip(ip), 1);
+
+        let alice = limits.acquire_user("alice").unwrap();
+        assert!(limits.acquire_user("alice").is_err());
+        drop(alice);
+        assert_eq!(limits.active_for_user("alice"), 0);
+    }
+}
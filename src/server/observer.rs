@@ -0,0 +1,196 @@
+//! Hooks into the lifecycle of the sessions, for metrics, audit or alerting.
+//!
+//! Every callback receives the [`SessionContext`] of the session, where an observer can keep its
+//! own data from one callback to another, eg. a timer started on accept.
+//!
+//! ```
+//! use fast_socks5::server::observer::{SessionContext, SessionObserver};
+//! use fast_socks5::server::stats::SessionStats;
+//! use fast_socks5::server::Config;
+//! use fast_socks5::ReplyError;
+//! use std::time::Instant;
+//!
+//! struct Audit;
+//!
+//! impl SessionObserver for Audit {
+//!     fn on_accept(&self, session: &mut SessionContext) {
+//!         session.insert(Instant::now());
+//!     }
+//!
+//!     fn on_connect_failed(&self, session: &mut SessionContext, reply: ReplyError) {
+//!         let started: Option<&Instant> = session.get();
+//!         println!("session {} failed after {:?}: {}", session.id(), started, reply);
+//!     }
+//!
+//!     fn on_close(&self, session: &mut SessionContext, stats: &SessionStats) {
+//!         println!("session {} closed: {:?}", session.id(), stats);
+//!     }
+//! }
+//!
+//! let mut config = Config::default();
+//! config.add_observer(Audit);
+//! ```
+use crate::server::registry::SessionId;
+use crate::server::stats::SessionStats;
+use crate::server::unix::PeerCredentials;
+use crate::util::target_addr::TargetAddr;
+use crate::{Command, ReplyError};
+use std::any::{Any, TypeId};
+use std::collections::HashMap;
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Why an authentication failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuthFailure {
+    /// Empty username or password.
+    Malformed,
+    /// Wrong credentials.
+    InvalidCredentials,
+    /// Refused by the brute force protection.
+    LockedOut,
+    /// The authentication backend failed.
+    Error,
+}
+
+impl fmt::Display for AuthFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            AuthFailure::Malformed => "malformed",
+            AuthFailure::InvalidCredentials => "invalid credentials",
+            AuthFailure::LockedOut => "locked out",
+            AuthFailure::Error => "error",
+        })
+    }
+}
+
+/// A session as seen by the observers, with the data they attached to it.
+pub struct SessionContext {
+    id: SessionId,
+    peer_addr: Option<SocketAddr>,
+    listener: Option<Arc<str>>,
+    peer_credentials: Option<PeerCredentials>,
+    data: HashMap<TypeId, Box<dyn Any + Send + Sync>>,
+}
+
+impl SessionContext {
+    pub(crate) fn new(id: SessionId, peer_addr: Option<SocketAddr>) -> Self {
+        SessionContext {
+            id,
+            peer_addr,
+            listener: None,
+            peer_credentials: None,
+            data: HashMap::new(),
+        }
+    }
+
+    pub(crate) fn with_listener(mut self, listener: Option<Arc<str>>) -> Self {
+        self.listener = listener;
+        self
+    }
+
+    pub fn id(&self) -> SessionId {
+        self.id
+    }
+
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        self.peer_addr
+    }
+
+    pub(crate) fn with_peer_credentials(mut self, credentials: Option<PeerCredentials>) -> Self {
+        self.peer_credentials = credentials;
+        self
+    }
+
+    /// Credentials of the process of the client connected through a Unix socket.
+    pub fn peer_credentials(&self) -> Option<PeerCredentials> {
+        self.peer_credentials
+    }
+
+    /// Label of the listener which accepted the client, see `Socks5Server::add_listener()`.
+    pub fn listener(&self) -> Option<&str> {
+        self.listener.as_deref()
+    }
+
+    /// Attach a value to the session, one per type. Returns the previous value of this type.
+    pub fn insert<D: Any + Send + Sync>(&mut self, value: D) -> Option<D> {
+        self.data
+            .insert(TypeId::of::<D>(), Box::new(value))
+            .and_then(|previous| previous.downcast().ok())
+            .map(|previous| *previous)
+    }
+
+    pub fn get<D: Any + Send + Sync>(&self) -> Option<&D> {
+        self.data
+            .get(&TypeId::of::<D>())
+            .and_then(|value| value.downcast_ref())
+    }
+
+    pub fn get_mut<D: Any + Send + Sync>(&mut self) -> Option<&mut D> {
+        self.data
+            .get_mut(&TypeId::of::<D>())
+            .and_then(|value| value.downcast_mut())
+    }
+
+    pub fn remove<D: Any + Send + Sync>(&mut self) -> Option<D> {
+        self.data
+            .remove(&TypeId::of::<D>())
+            .and_then(|value| value.downcast().ok())
+            .map(|value| *value)
+    }
+}
+
+/// Notified of the steps of every session, see [`Config::add_observer()`].
+///
+/// The callbacks are called from the task running the session, they shouldn't block.
+/// Each one does nothing by default.
+///
+/// [`Config::add_observer()`]: crate::server::Config::add_observer
+pub trait SessionObserver: Send + Sync {
+    /// The session starts, see `Socks5Socket::upgrade_to_socks5()`.
+    fn on_accept(&self, _session: &mut SessionContext) {}
+
+    /// The server chose this authentication method, eg. `consts::SOCKS5_AUTH_METHOD_PASSWORD`.
+    fn on_method_negotiated(&self, _session: &mut SessionContext, _method: u8) {}
+
+    fn on_auth_success(&self, _session: &mut SessionContext, _username: &str) {}
+
+    /// The username is unknown when it couldn't be read.
+    fn on_auth_failure(
+        &self,
+        _session: &mut SessionContext,
+        _username: Option<&str>,
+        _reason: AuthFailure,
+    ) {
+    }
+
+    /// The request of the client has been read, the target is the one requested.
+    fn on_request(&self, _session: &mut SessionContext, _cmd: Command, _target: &TargetAddr) {}
+
+    fn on_dns_resolved(&self, _session: &mut SessionContext, _domain: &str, _addrs: &[SocketAddr]) {
+    }
+
+    fn on_connected(&self, _session: &mut SessionContext, _addr: SocketAddr, _latency: Duration) {}
+
+    /// The request has been answered with an error instead of connecting: the target is
+    /// unreachable, or the request is denied (access control, limits, quota, ...).
+    fn on_connect_failed(&self, _session: &mut SessionContext, _reply: ReplyError) {}
+
+    /// The session is over, successfully or not.
+    fn on_close(&self, _session: &mut SessionContext, _stats: &SessionStats) {}
+}
+
+/// Call every observer, in the order they were added.
+pub(crate) fn notify<F>(
+    observers: &[Arc<dyn SessionObserver>],
+    session: &mut SessionContext,
+    callback: F,
+) where
+    F: Fn(&dyn SessionObserver, &mut SessionContext),
+{
+    for observer in observers {
+        callback(observer.as_ref(), session);
+    }
+}
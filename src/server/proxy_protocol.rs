@@ -0,0 +1,333 @@
+//! PROXY protocol (v1 and v2) of HAProxy, for a server behind a TCP load balancer.
+//!
+//! The balancer sends a header with the address of the client before the SOCKS handshake, which
+//! replaces its own address for the logs, the access control rules, the limits and the
+//! observers. The header is only read from the trusted networks, and required from them: a
+//! missing or malformed header closes the connection. The other clients are served as usual, a
+//! header sent by them fails the handshake.
+//!
+//! ```
+//! # use fast_socks5::server::proxy_protocol::ProxyProtocol;
+//! # use fast_socks5::server::Config;
+//! let mut proxy_protocol = ProxyProtocol::new();
+//! proxy_protocol.add_trusted("10.0.0.0/24".parse().unwrap());
+//!
+//! let mut config = Config::default();
+//! config.set_proxy_protocol(proxy_protocol);
+//! ```
+use crate::{Result, SocksError};
+use futures::{AsyncRead, AsyncReadExt};
+use ipnet::IpNet;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+
+/// First bytes of a v1 header.
+const V1_PREFIX: &[u8] = b"PROXY ";
+/// Longest v1 header, with the final CRLF.
+const V1_MAX_LEN: usize = 107;
+/// Signature starting a v2 header.
+const V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+
+/// Which peers may send a PROXY protocol header.
+#[derive(Debug, Clone)]
+pub struct ProxyProtocol {
+    trusted: Vec<IpNet>,
+    timeout: Duration,
+}
+
+impl Default for ProxyProtocol {
+    fn default() -> Self {
+        ProxyProtocol {
+            trusted: Vec::new(),
+            timeout: Duration::from_secs(5),
+        }
+    }
+}
+
+impl ProxyProtocol {
+    /// Nothing trusted, the networks of the balancers have to be added with `add_trusted()`.
+    pub fn new() -> Self {
+        ProxyProtocol::default()
+    }
+
+    /// Read the header of the peers from this network.
+    pub fn add_trusted(&mut self, network: IpNet) -> &mut Self {
+        self.trusted.push(network);
+        self
+    }
+
+    /// How long a trusted peer has to send its header. Default is 5 seconds.
+    pub fn set_timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    pub fn is_trusted(&self, ip: IpAddr) -> bool {
+        // IPv4 peers of a dual-stack socket are seen as IPv4-mapped IPv6 addresses
+        let ip = ip.to_canonical();
+        self.trusted.iter().any(|network| network.contains(&ip))
+    }
+}
+
+/// Addresses of the connection between the client and the balancer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProxiedAddrs {
+    pub source: SocketAddr,
+    pub destination: SocketAddr,
+}
+
+/// Read a v1 or v2 header, without reading any byte past it. `None` if the header doesn't
+/// carry the addresses of a client: health checks of the balancer (`LOCAL`), or `UNKNOWN`
+/// protocols.
+pub async fn read_header<R: AsyncRead + Unpin>(stream: &mut R) -> Result<Option<ProxiedAddrs>> {
+    // A client which isn't sending a header may not send 6 bytes before a reply
+    let mut prefix = [0u8; 6];
+    stream.read_exact(&mut prefix[..1]).await?;
+    if prefix[0] != V1_PREFIX[0] && prefix[0] != V2_SIGNATURE[0] {
+        return Err(invalid("missing header"));
+    }
+    stream.read_exact(&mut prefix[1..]).await?;
+    if prefix == V1_PREFIX {
+        read_v1(stream).await
+    } else if prefix == V2_SIGNATURE[..6] {
+        read_v2(stream).await
+    } else {
+        Err(invalid("missing header"))
+    }
+}
+
+fn invalid<S: Into<String>>(message: S) -> SocksError {
+    SocksError::InvalidProxyHeader(message.into())
+}
+
+/// `PROXY TCP4 192.0.2.1 198.51.100.1 56324 1080\r\n`, after `PROXY `.
+async fn read_v1<R: AsyncRead + Unpin>(stream: &mut R) -> Result<Option<ProxiedAddrs>> {
+    let mut line = Vec::new();
+    loop {
+        let mut byte = [0u8; 1];
+        stream.read_exact(&mut byte).await?;
+        line.push(byte[0]);
+        if line.ends_with(b"\r\n") {
+            break;
+        }
+        if V1_PREFIX.len() + line.len() >= V1_MAX_LEN {
+            return Err(invalid("v1 header too long"));
+        }
+    }
+    let line = std::str::from_utf8(&line[..line.len() - 2])
+        .map_err(|_| invalid("v1 header isn't ASCII"))?;
+
+    let fields: Vec<&str> = line.split(' ').collect();
+    match fields.as_slice() {
+        ["UNKNOWN", ..] => Ok(None),
+        [protocol @ "TCP4", source, destination, source_port, destination_port]
+        | [protocol @ "TCP6", source, destination, source_port, destination_port] => {
+            let ip = |field: &str| -> Result<IpAddr> {
+                let ip = match *protocol {
+                    "TCP4" => field.parse::<Ipv4Addr>().map(IpAddr::V4),
+                    _ => field.parse::<Ipv6Addr>().map(IpAddr::V6),
+                };
+                ip.map_err(|_| invalid(format!("invalid {} address `{}`", protocol, field)))
+            };
+            Ok(Some(ProxiedAddrs {
+                source: SocketAddr::new(ip(source)?, port(source_port)?),
+                destination: SocketAddr::new(ip(destination)?, port(destination_port)?),
+            }))
+        }
+        _ => Err(invalid(format!("invalid v1 header `{}`", line))),
+    }
+}
+
+fn port(field: &str) -> Result<u16> {
+    let valid = !field.is_empty()
+        && field.bytes().all(|byte| byte.is_ascii_digit())
+        && (field == "0" || !field.starts_with('0'));
+    field
+        .parse()
+        .ok()
+        .filter(|_| valid)
+        .ok_or_else(|| invalid(format!("invalid port `{}`", field)))
+}
+
+/// Binary header, after the first 6 bytes of the signature.
+async fn read_v2<R: AsyncRead + Unpin>(stream: &mut R) -> Result<Option<ProxiedAddrs>> {
+    let mut header = [0u8; 10];
+    stream.read_exact(&mut header).await?;
+    if header[..6] != V2_SIGNATURE[6..] {
+        return Err(invalid("invalid v2 signature"));
+    }
+    let (version, command) = (header[6] >> 4, header[6] & 0x0f);
+    let family = header[7] >> 4;
+    let len = u16::from_be_bytes([header[8], header[9]]) as usize;
+    if version != 2 {
+        return Err(invalid(format!("unsupported version {}", version)));
+    }
+
+    // The addresses, followed by TLVs which aren't used
+    let mut payload = vec![0u8; len];
+    stream.read_exact(&mut payload).await?;
+
+    match command {
+        // Health check of the balancer itself
+        0x0 => return Ok(None),
+        0x1 => {}
+        _ => return Err(invalid(format!("unsupported command {}", command))),
+    }
+    let addrs = match family {
+        // AF_INET
+        0x1 => {
+            let addrs = payload
+                .get(..12)
+                .ok_or_else(|| invalid("v2 header too short for IPv4 addresses"))?;
+            let ip = |at: usize| {
+                let mut octets = [0u8; 4];
+                octets.copy_from_slice(&addrs[at..at + 4]);
+                IpAddr::from(octets)
+            };
+            ProxiedAddrs {
+                source: SocketAddr::new(ip(0), u16::from_be_bytes([addrs[8], addrs[9]])),
+                destination: SocketAddr::new(ip(4), u16::from_be_bytes([addrs[10], addrs[11]])),
+            }
+        }
+        // AF_INET6
+        0x2 => {
+            let addrs = payload
+                .get(..36)
+                .ok_or_else(|| invalid("v2 header too short for IPv6 addresses"))?;
+            let ip = |at: usize| {
+                let mut octets = [0u8; 16];
+                octets.copy_from_slice(&addrs[at..at + 16]);
+                IpAddr::from(octets)
+            };
+            ProxiedAddrs {
+                source: SocketAddr::new(ip(0), u16::from_be_bytes([addrs[32], addrs[33]])),
+                destination: SocketAddr::new(ip(16), u16::from_be_bytes([addrs[34], addrs[35]])),
+            }
+        }
+        // AF_UNSPEC, AF_UNIX: no IP address to replace the peer's
+        0x0 | 0x3 => return Ok(None),
+        _ => return Err(invalid(format!("unsupported address family {}", family))),
+    };
+    Ok(Some(addrs))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{read_header, ProxiedAddrs, ProxyProtocol, V2_SIGNATURE};
+    use async_std::task;
+    use futures::io::Cursor;
+    use futures::AsyncReadExt;
+
+    fn parse(header: &[u8]) -> crate::Result<Option<ProxiedAddrs>> {
+        task::block_on(read_header(&mut Cursor::new(header)))
+    }
+
+    fn addrs(source: &str, destination: &str) -> Option<ProxiedAddrs> {
+        Some(ProxiedAddrs {
+            source: source.parse().unwrap(),
+            destination: destination.parse().unwrap(),
+        })
+    }
+
+    fn v2(command: u8, family: u8, payload: &[u8]) -> Vec<u8> {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.push(0x20 | command);
+        header.push(family << 4 | 0x1);
+        header.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+        header.extend_from_slice(payload);
+        header
+    }
+
+    #[test]
+    fn test_v1() {
+        assert_eq!(
+            parse(b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 1080\r\n").unwrap(),
+            addrs("192.0.2.1:56324", "198.51.100.1:1080")
+        );
+        assert_eq!(
+            parse(b"PROXY TCP6 2001:db8::1 2001:db8::2 56324 1080\r\n").unwrap(),
+            addrs("[2001:db8::1]:56324", "[2001:db8::2]:1080")
+        );
+        assert_eq!(parse(b"PROXY UNKNOWN whatever\r\n").unwrap(), None);
+
+        // The SOCKS handshake following the header is left untouched
+        let mut stream = Cursor::new(b"PROXY UNKNOWN\r\n\x05\x01\x00".to_vec());
+        task::block_on(read_header(&mut stream)).unwrap();
+        let mut rest = Vec::new();
+        task::block_on(stream.read_to_end(&mut rest)).unwrap();
+        assert_eq!(rest, b"\x05\x01\x00");
+
+        for malformed in &[
+            &b"\x05\x01\x00"[..],
+            b"PROXY TCP4 192.0.2.1 198.51.100.1 56324\r\n",
+            b"PROXY TCP4 2001:db8::1 2001:db8::2 56324 1080\r\n",
+            b"PROXY TCP4 192.0.2.1 198.51.100.1 056324 1080\r\n",
+            b"PROXY TCP4 192.0.2.1 198.51.100.1 65536 1080\r\n",
+            b"PROXY TCP4  192.0.2.1 198.51.100.1 56324 1080\r\n",
+            b"PROXY UDP4 192.0.2.1 198.51.100.1 56324 1080\r\n",
+            b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 1080\n",
+        ] {
+            assert!(parse(malformed).is_err(), "{:?}", malformed);
+        }
+        let mut too_long = b"PROXY UNKNOWN ".to_vec();
+        too_long.extend_from_slice(&[b'a'; 100]);
+        too_long.extend_from_slice(b"\r\n");
+        assert!(parse(&too_long).is_err());
+    }
+
+    #[test]
+    fn test_v2() {
+        let ipv4 = [192, 0, 2, 1, 198, 51, 100, 1, 0xdc, 0x04, 0x04, 0x38];
+        assert_eq!(
+            parse(&v2(0x1, 0x1, &ipv4)).unwrap(),
+            addrs("192.0.2.1:56324", "198.51.100.1:1080")
+        );
+
+        let mut ipv6 = Vec::new();
+        ipv6.extend_from_slice(
+            &"2001:db8::1"
+                .parse::<std::net::Ipv6Addr>()
+                .unwrap()
+                .octets(),
+        );
+        ipv6.extend_from_slice(
+            &"2001:db8::2"
+                .parse::<std::net::Ipv6Addr>()
+                .unwrap()
+                .octets(),
+        );
+        ipv6.extend_from_slice(&[0xdc, 0x04, 0x04, 0x38]);
+        // followed by a TLV
+        ipv6.extend_from_slice(&[0x04, 0x00, 0x01, 0x00]);
+        assert_eq!(
+            parse(&v2(0x1, 0x2, &ipv6)).unwrap(),
+            addrs("[2001:db8::1]:56324", "[2001:db8::2]:1080")
+        );
+
+        assert_eq!(parse(&v2(0x0, 0x0, &[])).unwrap(), None);
+        assert_eq!(parse(&v2(0x1, 0x3, &[0; 216])).unwrap(), None);
+
+        assert!(parse(&v2(0x1, 0x1, &ipv4[..8])).is_err());
+        assert!(parse(&v2(0x2, 0x1, &ipv4)).is_err());
+        assert!(parse(&v2(0x1, 0x4, &ipv4)).is_err());
+        let mut version = v2(0x1, 0x1, &ipv4);
+        version[12] = 0x11;
+        assert!(parse(&version).is_err());
+        let mut signature = v2(0x1, 0x1, &ipv4);
+        signature[11] = b'X';
+        assert!(parse(&signature).is_err());
+    }
+
+    #[test]
+    fn test_trusted() {
+        let mut proxy_protocol = ProxyProtocol::new();
+        proxy_protocol.add_trusted("10.0.0.0/24".parse().unwrap());
+        assert!(proxy_protocol.is_trusted("10.0.0.7".parse().unwrap()));
+        assert!(proxy_protocol.is_trusted("::ffff:10.0.0.7".parse().unwrap()));
+        assert!(!proxy_protocol.is_trusted("10.0.1.7".parse().unwrap()));
+    }
+}
@@ -0,0 +1,445 @@
+//! Traffic quotas of the authenticated users.
+//!
+//! A user can relay up to `limit` bytes, both directions included, within each window of its
+//! [`Quota`]. Once exhausted, its running sessions are closed and its new requests are replied
+//! with `ConnectionNotAllowed`, until the next window starts.
+//!
+//! The usage is kept by a [`QuotaStore`]: [`MemoryQuotaStore`] forgets everything on restart,
+//! while [`FileQuotaStore`] persists it to a file.
+//!
+//! The sessions report their traffic to the store every [`REPORT_THRESHOLD`] bytes, a quota can
+//! be exceeded by about this amount per session.
+use crate::server::async_trait;
+use crate::util::date::{civil_from_days, days_from_civil, SECONDS_PER_DAY};
+use crate::{Result, SocksError};
+use anyhow::Context;
+use async_std::fs;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Bytes a session relays before reporting them to the store.
+pub const REPORT_THRESHOLD: u64 = 64 * 1024;
+
+/// Period after which the usage is reset. Windows are computed in UTC.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QuotaWindow {
+    /// Starting at midnight.
+    Daily,
+    /// Starting on the first day of the month, at midnight.
+    Monthly,
+    /// Consecutive windows of this length, starting at the UNIX epoch.
+    Fixed(Duration),
+}
+
+impl QuotaWindow {
+    /// Start of the window containing `time`, in seconds since the UNIX epoch.
+    pub fn start(&self, time: SystemTime) -> u64 {
+        let secs = time
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+
+        match self {
+            QuotaWindow::Daily => secs - secs % SECONDS_PER_DAY,
+            QuotaWindow::Monthly => {
+                let (year, month, _) = civil_from_days(secs / SECONDS_PER_DAY);
+                days_from_civil(year, month, 1) * SECONDS_PER_DAY
+            }
+            QuotaWindow::Fixed(length) => secs - secs % length.as_secs().max(1),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Quota {
+    /// Bytes allowed per window, upload and download included.
+    pub limit: u64,
+    pub window: QuotaWindow,
+}
+
+/// Keeps the usage of the users, for their current window.
+#[async_trait]
+pub trait QuotaStore: Send + Sync {
+    /// Bytes used by the user in the window starting at `window_start`.
+    async fn usage(&self, user: &str, window_start: u64) -> Result<u64>;
+
+    /// Add bytes to the usage of the user in the window starting at `window_start`,
+    /// returns the new usage.
+    async fn add_usage(&self, user: &str, window_start: u64, bytes: u64) -> Result<u64>;
+}
+
+/// Usage kept in memory, lost on restart.
+#[derive(Default)]
+pub struct MemoryQuotaStore {
+    /// Start of the current window and bytes used, per user
+    usage: Mutex<HashMap<String, (u64, u64)>>,
+}
+
+impl MemoryQuotaStore {
+    fn get(&self, user: &str, window_start: u64) -> u64 {
+        match self.usage.lock().unwrap().get(user) {
+            Some(&(start, bytes)) if start == window_start => bytes,
+            _ => 0,
+        }
+    }
+
+    fn add(&self, user: &str, window_start: u64, bytes: u64) -> u64 {
+        let mut usage = self.usage.lock().unwrap();
+        let entry = usage.entry(user.to_string()).or_insert((window_start, 0));
+        if entry.0 != window_start {
+            // a new window started
+            *entry = (window_start, 0);
+        }
+        entry.1 += bytes;
+        entry.1
+    }
+}
+
+#[async_trait]
+impl QuotaStore for MemoryQuotaStore {
+    async fn usage(&self, user: &str, window_start: u64) -> Result<u64> {
+        Ok(self.get(user, window_start))
+    }
+
+    async fn add_usage(&self, user: &str, window_start: u64, bytes: u64) -> Result<u64> {
+        Ok(self.add(user, window_start, bytes))
+    }
+}
+
+/// Usage persisted to a file, one `window_start bytes username` per line. The backslashes and
+/// the line breaks of the usernames are escaped as `\\`, `\n` and `\r`.
+///
+/// The file is rewritten at most once per save interval, and by `save()`, which should be called
+/// before the server stops.
+pub struct FileQuotaStore {
+    path: PathBuf,
+    memory: MemoryQuotaStore,
+    save_interval: Duration,
+    last_save: Mutex<Instant>,
+    /// Held while saving, the saves share the temporary file
+    saving: async_std::sync::Mutex<()>,
+}
+
+impl FileQuotaStore {
+    /// Load the usage from the file, which is created later if it doesn't exist.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let memory = MemoryQuotaStore::default();
+
+        match std::fs::read_to_string(&path) {
+            Ok(content) => *memory.usage.lock().unwrap() = parse(&content)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => {
+                return Err(e).with_context(|| format!("Can't read {}", path.display()))?;
+            }
+        }
+
+        Ok(FileQuotaStore {
+            path,
+            memory,
+            save_interval: Duration::from_secs(10),
+            last_save: Mutex::new(Instant::now()),
+            saving: async_std::sync::Mutex::new(()),
+        })
+    }
+
+    /// Default is 10 seconds.
+    pub fn set_save_interval(&mut self, interval: Duration) -> &mut Self {
+        self.save_interval = interval;
+        self
+    }
+
+    /// Write the usage to the file.
+    pub async fn save(&self) -> Result<()> {
+        let _saving = self.saving.lock().await;
+        *self.last_save.lock().unwrap() = Instant::now();
+
+        let mut content = String::new();
+        for (user, (start, bytes)) in self.memory.usage.lock().unwrap().iter() {
+            content.push_str(&format!("{} {} {}\n", start, bytes, escape(user)));
+        }
+
+        // replace the file at once, a crash can't leave it half written
+        let tmp = self.path.with_extension("tmp");
+        fs::write(&tmp, content)
+            .await
+            .with_context(|| format!("Can't write {}", tmp.display()))?;
+        fs::rename(&tmp, &self.path)
+            .await
+            .with_context(|| format!("Can't replace {}", self.path.display()))?;
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl QuotaStore for FileQuotaStore {
+    async fn usage(&self, user: &str, window_start: u64) -> Result<u64> {
+        Ok(self.memory.get(user, window_start))
+    }
+
+    async fn add_usage(&self, user: &str, window_start: u64, bytes: u64) -> Result<u64> {
+        let usage = self.memory.add(user, window_start, bytes);
+
+        let due = self.last_save.lock().unwrap().elapsed() >= self.save_interval;
+        if due {
+            // the usage is counted anyway, it will be saved next time
+            if let Err(e) = self.save().await {
+                error!("Can't save the quota usage: {:#}", e);
+            }
+        }
+
+        Ok(usage)
+    }
+}
+
+fn parse(content: &str) -> Result<HashMap<String, (u64, u64)>> {
+    let mut usage = HashMap::new();
+
+    for (number, line) in content.lines().enumerate() {
+        let error = || SocksError::Other(anyhow::anyhow!("line {}: invalid usage", number + 1));
+        let mut fields = line.splitn(3, ' ');
+        let start = fields
+            .next()
+            .and_then(|f| f.parse().ok())
+            .ok_or_else(error)?;
+        let bytes = fields
+            .next()
+            .and_then(|f| f.parse().ok())
+            .ok_or_else(error)?;
+        let user = fields
+            .next()
+            .filter(|f| !f.is_empty())
+            .and_then(unescape)
+            .ok_or_else(error)?;
+
+        usage.insert(user, (start, bytes));
+    }
+
+    Ok(usage)
+}
+
+/// Keep the username on its line.
+fn escape(user: &str) -> String {
+    let mut escaped = String::with_capacity(user.len());
+    for c in user.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn unescape(field: &str) -> Option<String> {
+    let mut user = String::with_capacity(field.len());
+    let mut chars = field.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next()? {
+                '\\' => user.push('\\'),
+                'n' => user.push('\n'),
+                'r' => user.push('\r'),
+                _ => return None,
+            },
+            c => user.push(c),
+        }
+    }
+    Some(user)
+}
+
+/// Quotas of the users, and where their usage is kept.
+pub struct Quotas {
+    store: Arc<dyn QuotaStore>,
+    default_quota: Option<Quota>,
+    user_quotas: HashMap<String, Option<Quota>>,
+}
+
+impl Quotas {
+    /// No quota until `set_default_quota()` or `set_user_quota()` are called.
+    pub fn new(store: Arc<dyn QuotaStore>) -> Self {
+        Quotas {
+            store,
+            default_quota: None,
+            user_quotas: HashMap::new(),
+        }
+    }
+
+    /// Quota of the users without their own.
+    pub fn set_default_quota(&mut self, quota: Option<Quota>) -> &mut Self {
+        self.default_quota = quota;
+        self
+    }
+
+    /// Quota of a specific user, `None` for unlimited.
+    pub fn set_user_quota(&mut self, user: &str, quota: Option<Quota>) -> &mut Self {
+        self.user_quotas.insert(user.to_string(), quota);
+        self
+    }
+
+    pub fn quota_of(&self, user: &str) -> Option<Quota> {
+        match self.user_quotas.get(user) {
+            Some(quota) => *quota,
+            None => self.default_quota,
+        }
+    }
+
+    /// Bytes used by the user in its current window.
+    pub async fn usage(&self, user: &str) -> Result<u64> {
+        match self.quota_of(user) {
+            Some(quota) => {
+                let window_start = quota.window.start(SystemTime::now());
+                self.store.usage(user, window_start).await
+            }
+            None => Ok(0),
+        }
+    }
+
+    /// Whether the user can start a new session, and its accounting if it has a quota.
+    pub(crate) async fn session(
+        self: &Arc<Self>,
+        user: &str,
+    ) -> Result<(bool, Option<SessionQuota>)> {
+        let quota = match self.quota_of(user) {
+            Some(quota) => quota,
+            None => return Ok((true, None)),
+        };
+
+        let usage = self.usage(user).await?;
+        let session = SessionQuota {
+            quotas: self.clone(),
+            user: user.to_string(),
+            quota,
+            pending: AtomicU64::new(0),
+            exhausted: AtomicBool::new(usage >= quota.limit),
+        };
+
+        Ok((usage < quota.limit, Some(session)))
+    }
+}
+
+/// Reports the traffic of a session.
+pub(crate) struct SessionQuota {
+    quotas: Arc<Quotas>,
+    user: String,
+    quota: Quota,
+    /// Bytes not reported yet
+    pending: AtomicU64,
+    exhausted: AtomicBool,
+}
+
+impl SessionQuota {
+    pub(crate) fn is_exhausted(&self) -> bool {
+        self.exhausted.load(Ordering::Relaxed)
+    }
+
+    /// Count bytes relayed, returns `false` once the quota is exhausted.
+    pub(crate) async fn consume(&self, bytes: usize) -> bool {
+        let pending = self.pending.fetch_add(bytes as u64, Ordering::Relaxed) + bytes as u64;
+        if pending >= REPORT_THRESHOLD {
+            self.report().await;
+        }
+
+        !self.is_exhausted()
+    }
+
+    /// Report the pending bytes to the store.
+    pub(crate) async fn report(&self) {
+        let bytes = self.pending.swap(0, Ordering::Relaxed);
+        if bytes == 0 {
+            return;
+        }
+
+        let window_start = self.quota.window.start(SystemTime::now());
+        match self
+            .quotas
+            .store
+            .add_usage(&self.user, window_start, bytes)
+            .await
+        {
+            Ok(usage) => {
+                if usage >= self.quota.limit && !self.exhausted.swap(true, Ordering::Relaxed) {
+                    info!("Quota of `{}` exhausted ({} bytes)", self.user, usage);
+                }
+            }
+            Err(e) => {
+                error!("Can't report the usage of `{}`: {:#}", self.user, e);
+                // try again on the next report
+                self.pending.fetch_add(bytes, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_windows() {
+        // 2024-02-29 13:20:00 UTC
+        let time = UNIX_EPOCH + Duration::from_secs(1_709_212_800);
+        assert_eq!(QuotaWindow::Daily.start(time), 1_709_164_800);
+        // 2024-02-01 00:00:00 UTC
+        assert_eq!(QuotaWindow::Monthly.start(time), 1_706_745_600);
+        assert_eq!(
+            QuotaWindow::Fixed(Duration::from_secs(3600)).start(time),
+            1_709_211_600
+        );
+    }
+
+    #[test]
+    fn test_parse() {
+        let usage = parse("1706745600 1024 alice\n1706745600 0 bob smith\n").unwrap();
+        assert_eq!(usage["alice"], (1_706_745_600, 1024));
+        assert_eq!(usage["bob smith"], (1_706_745_600, 0));
+
+        let err = parse("1706745600 1024 alice\nbob\n").unwrap_err();
+        assert_eq!(err.to_string(), "line 2: invalid usage");
+        let err = parse("1706745600 1024 alice\\\n").unwrap_err();
+        assert_eq!(err.to_string(), "line 1: invalid usage");
+    }
+
+    #[async_std::test]
+    async fn test_concurrent_saves() {
+        let path = std::env::temp_dir().join(format!("fast-socks5-quota-{}", std::process::id()));
+        let store = FileQuotaStore::open(&path).unwrap();
+        store.add_usage("alice", 1_706_745_600, 1024).await.unwrap();
+
+        let saves: Vec<_> = (0..10).map(|_| store.save()).collect();
+        for result in futures::future::join_all(saves).await {
+            result.unwrap();
+        }
+        let saved = FileQuotaStore::open(&path).unwrap();
+        assert_eq!(saved.usage("alice", 1_706_745_600).await.unwrap(), 1024);
+        assert!(!path.with_extension("tmp").exists());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[async_std::test]
+    async fn test_usernames_round_trip() {
+        let path =
+            std::env::temp_dir().join(format!("fast-socks5-quota-names-{}", std::process::id()));
+        let store = FileQuotaStore::open(&path).unwrap();
+        let users = ["alice\n0 0 mallory", "bob\r", "carol\\n", "dave\\"];
+        for user in &users {
+            store.add_usage(user, 1_706_745_600, 1024).await.unwrap();
+        }
+        store.save().await.unwrap();
+
+        let saved = FileQuotaStore::open(&path).unwrap();
+        for user in &users {
+            assert_eq!(saved.usage(user, 1_706_745_600).await.unwrap(), 1024);
+        }
+        assert_eq!(saved.usage("mallory", 0).await.unwrap(), 0);
+        assert_eq!(saved.memory.usage.lock().unwrap().len(), users.len());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,201 @@
+//! Registry of the running sessions, to see who is connected where, and terminate sessions.
+//!
+//! ```
+//! use fast_socks5::server::registry::{SessionFilter, SessionRegistry};
+//! use fast_socks5::server::Config;
+//! use std::sync::Arc;
+//!
+//! let registry = Arc::new(SessionRegistry::default());
+//! let mut config = Config::default();
+//! config.set_session_registry(registry.clone());
+//!
+//! // later on
+//! for session in registry.filter(&SessionFilter::User("mallory".to_string())) {
+//!     println!("{} -> {:?}", session.id, session.target);
+//! }
+//! registry.terminate(&SessionFilter::User("mallory".to_string()));
+//! ```
+use crate::server::stats::Traffic;
+use crate::util::signal::Signal;
+use crate::util::target_addr::TargetAddr;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+/// Unique within the process, see `Socks5Socket::id()`.
+pub type SessionId = u64;
+
+/// Snapshot of a running session.
+#[derive(Debug, Clone)]
+pub struct SessionInfo {
+    pub id: SessionId,
+    pub peer_addr: Option<SocketAddr>,
+    /// Username, once authenticated.
+    pub user: Option<String>,
+    /// Destination as requested by the client, once read.
+    pub target: Option<TargetAddr>,
+    pub started_at: SystemTime,
+    /// Bytes relayed from the client to the target so far.
+    pub bytes_sent: u64,
+    /// Bytes relayed from the target to the client so far.
+    pub bytes_received: u64,
+}
+
+/// Selects sessions of the registry.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SessionFilter {
+    All,
+    Id(SessionId),
+    User(String),
+    /// Sessions of clients connecting from this IP.
+    Ip(IpAddr),
+}
+
+impl SessionFilter {
+    fn matches(&self, id: SessionId, entry: &Entry) -> bool {
+        match self {
+            SessionFilter::All => true,
+            SessionFilter::Id(wanted) => id == *wanted,
+            SessionFilter::User(user) => entry.user.as_ref() == Some(user),
+            SessionFilter::Ip(ip) => entry.peer_addr.map(|addr| addr.ip()) == Some(*ip),
+        }
+    }
+}
+
+struct Entry {
+    peer_addr: Option<SocketAddr>,
+    user: Option<String>,
+    target: Option<TargetAddr>,
+    started_at: SystemTime,
+    traffic: Arc<Traffic>,
+    kill: Signal,
+}
+
+impl Entry {
+    fn info(&self, id: SessionId) -> SessionInfo {
+        SessionInfo {
+            id,
+            peer_addr: self.peer_addr,
+            user: self.user.clone(),
+            target: self.target.clone(),
+            started_at: self.started_at,
+            bytes_sent: self.traffic.sent(),
+            bytes_received: self.traffic.received(),
+        }
+    }
+}
+
+/// Sessions are registered from the beginning until the end of `upgrade_to_socks5()`.
+#[derive(Default)]
+pub struct SessionRegistry {
+    sessions: Mutex<HashMap<SessionId, Entry>>,
+}
+
+impl SessionRegistry {
+    /// Number of sessions running.
+    pub fn len(&self) -> usize {
+        self.sessions.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn get(&self, id: SessionId) -> Option<SessionInfo> {
+        let sessions = self.sessions.lock().unwrap();
+        sessions.get(&id).map(|entry| entry.info(id))
+    }
+
+    /// Sessions matching the filter, oldest first.
+    pub fn filter(&self, filter: &SessionFilter) -> Vec<SessionInfo> {
+        let sessions = self.sessions.lock().unwrap();
+        let mut matching: Vec<_> = sessions
+            .iter()
+            .filter(|(id, entry)| filter.matches(**id, entry))
+            .map(|(id, entry)| entry.info(*id))
+            .collect();
+        matching.sort_by_key(|info| info.id);
+        matching
+    }
+
+    pub fn list(&self) -> Vec<SessionInfo> {
+        self.filter(&SessionFilter::All)
+    }
+
+    /// Close the sessions matching the filter, returns how many there were.
+    /// Sessions still in the handshake, or connecting to their target, are closed as well,
+    /// without replying to their request.
+    pub fn terminate(&self, filter: &SessionFilter) -> usize {
+        let sessions = self.sessions.lock().unwrap();
+        let mut terminated = 0;
+        for (id, entry) in sessions.iter() {
+            if filter.matches(*id, entry) && !entry.kill.is_fired() {
+                info!("Terminating session {}", id);
+                entry.kill.fire();
+                terminated += 1;
+            }
+        }
+        terminated
+    }
+
+    pub(crate) fn register(
+        self: &Arc<Self>,
+        id: SessionId,
+        peer_addr: Option<SocketAddr>,
+        traffic: Arc<Traffic>,
+    ) -> Registration {
+        let kill = Signal::new();
+        self.sessions.lock().unwrap().insert(
+            id,
+            Entry {
+                peer_addr,
+                user: None,
+                target: None,
+                started_at: SystemTime::now(),
+                traffic,
+                kill: kill.clone(),
+            },
+        );
+
+        Registration {
+            registry: self.clone(),
+            id,
+            kill,
+        }
+    }
+}
+
+/// Keeps a session in the registry until dropped.
+pub(crate) struct Registration {
+    registry: Arc<SessionRegistry>,
+    id: SessionId,
+    kill: Signal,
+}
+
+impl Registration {
+    fn update<F: FnOnce(&mut Entry)>(&self, f: F) {
+        if let Some(entry) = self.registry.sessions.lock().unwrap().get_mut(&self.id) {
+            f(entry);
+        }
+    }
+
+    pub(crate) fn set_user(&self, user: &str) {
+        self.update(|entry| entry.user = Some(user.to_string()));
+    }
+
+    pub(crate) fn set_target(&self, target: &TargetAddr) {
+        self.update(|entry| entry.target = Some(target.clone()));
+    }
+
+    /// Fired when the session is terminated.
+    pub(crate) fn kill_signal(&self) -> Signal {
+        self.kill.clone()
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        self.registry.sessions.lock().unwrap().remove(&self.id);
+    }
+}
@@ -0,0 +1,118 @@
+//! Configuration of a running server, replaced without dropping the sessions.
+//!
+//! Each session keeps the [`Config`] it was accepted with until it ends, a new configuration is
+//! only used by the clients accepted after it has been swapped in.
+//!
+//! ```no_run
+//! # use fast_socks5::server::{Config, Socks5Server};
+//! # async fn run() -> fast_socks5::Result<()> {
+//! let server = Socks5Server::bind("127.0.0.1:1080").await?;
+//! let handle = server.config_handle();
+//!
+//! // eg. on SIGHUP, the previous configuration is kept if the new one can't be loaded
+//! handle.reload(|| {
+//!     let mut config = Config::default();
+//!     config.set_request_timeout(5);
+//!     Ok(config)
+//! })?;
+//! # Ok(())
+//! # }
+//! ```
+use crate::server::Config;
+use crate::Result;
+use std::sync::{Arc, RwLock};
+
+struct Current {
+    config: Arc<Config>,
+    /// Incremented on each reload
+    generation: u64,
+}
+
+/// Swaps the configuration of a server, can be cloned and sent to another task.
+#[derive(Clone)]
+pub struct ConfigHandle {
+    current: Arc<RwLock<Current>>,
+}
+
+impl ConfigHandle {
+    pub fn new(config: Config) -> Self {
+        ConfigHandle {
+            current: Arc::new(RwLock::new(Current {
+                config: Arc::new(config),
+                generation: 0,
+            })),
+        }
+    }
+
+    /// Configuration of the next clients.
+    pub fn current(&self) -> Arc<Config> {
+        self.current.read().unwrap().config.clone()
+    }
+
+    /// Number of times the configuration has been replaced.
+    pub fn generation(&self) -> u64 {
+        self.current.read().unwrap().generation
+    }
+
+    /// Use this configuration for the next clients, the running sessions keep theirs.
+    /// Returns the previous configuration.
+    pub fn replace(&self, config: Config) -> Arc<Config> {
+        let mut current = self.current.write().unwrap();
+        current.generation += 1;
+        debug!("Configuration replaced (generation {})", current.generation);
+        std::mem::replace(&mut current.config, Arc::new(config))
+    }
+
+    /// Load a new configuration and swap it in. If `load` fails, the error is logged and
+    /// returned, and the running configuration is kept.
+    pub fn reload<F>(&self, load: F) -> Result<()>
+    where
+        F: FnOnce() -> Result<Config>,
+    {
+        match load() {
+            Ok(config) => {
+                self.replace(config);
+                info!("Configuration reloaded");
+                Ok(())
+            }
+            Err(e) => {
+                error!(
+                    "Can't reload the configuration, keeping the current one: {:#}",
+                    e
+                );
+                Err(e)
+            }
+        }
+    }
+}
+
+impl Default for ConfigHandle {
+    fn default() -> Self {
+        ConfigHandle::new(Config::default())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::ConfigHandle;
+    use crate::server::Config;
+    use crate::SocksError;
+
+    #[test]
+    fn test_reload() {
+        let handle = ConfigHandle::default();
+        let session = handle.current();
+
+        let mut config = Config::default();
+        config.set_request_timeout(3);
+        handle.reload(|| Ok(config)).unwrap();
+        assert_eq!(handle.generation(), 1);
+        assert_eq!(handle.current().request_timeout, 3);
+        assert_eq!(session.request_timeout, 10);
+
+        let failed = handle.reload(|| Err(SocksError::ArgumentInputError("invalid")));
+        assert!(failed.is_err());
+        assert_eq!(handle.generation(), 1);
+        assert_eq!(handle.current().request_timeout, 3);
+    }
+}
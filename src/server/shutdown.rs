@@ -0,0 +1,126 @@
+//! Graceful shutdown of the server.
+//!
+//! [`ShutdownHandle::shutdown()`] stops accepting new clients, and lets the running sessions end
+//! by themselves, including the ones still in the handshake. Once the deadline passes, the
+//! remaining sessions are closed.
+//!
+//! ```no_run
+//! # use fast_socks5::server::Socks5Server;
+//! # use std::time::Duration;
+//! # async fn run() -> std::io::Result<()> {
+//! let server = Socks5Server::bind("127.0.0.1:1080").await?;
+//! let handle = server.shutdown_handle();
+//!
+//! async_std::task::spawn(async move {
+//!     // eg. on SIGTERM
+//!     handle.shutdown(Duration::from_secs(30)).await;
+//! });
+//! # Ok(())
+//! # }
+//! ```
+use crate::util::signal::Signal;
+use async_std::channel::{unbounded, Receiver, Sender};
+use async_std::future;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+struct Shared {
+    stop: Signal,
+    force: Signal,
+    active: AtomicUsize,
+    /// A session ended while shutting down
+    ended: (Sender<()>, Receiver<()>),
+}
+
+/// Stops a server, can be cloned and sent to another task.
+#[derive(Clone)]
+pub struct ShutdownHandle {
+    shared: Arc<Shared>,
+}
+
+impl Default for ShutdownHandle {
+    fn default() -> Self {
+        ShutdownHandle {
+            shared: Arc::new(Shared {
+                stop: Signal::new(),
+                force: Signal::new(),
+                active: AtomicUsize::new(0),
+                ended: unbounded(),
+            }),
+        }
+    }
+}
+
+impl ShutdownHandle {
+    /// Stop accepting clients, wait for the sessions to end up to `deadline`, then close the
+    /// remaining ones. Returns the number of sessions closed.
+    ///
+    /// The closed sessions are only told to stop, they may still be running when this returns.
+    pub async fn shutdown(&self, deadline: Duration) -> usize {
+        info!(
+            "Shutting down, waiting up to {:?} for {} sessions",
+            deadline,
+            self.active_sessions()
+        );
+        self.shared.stop.fire();
+
+        let deadline = Instant::now() + deadline;
+        while self.active_sessions() > 0 {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining == Duration::from_secs(0) {
+                break;
+            }
+            let _ = future::timeout(remaining, self.shared.ended.1.recv()).await;
+        }
+
+        let remaining = self.active_sessions();
+        if remaining > 0 {
+            warn!("Deadline reached, closing {} sessions", remaining);
+        }
+        self.shared.force.fire();
+
+        remaining
+    }
+
+    pub fn is_shutting_down(&self) -> bool {
+        self.shared.stop.is_fired()
+    }
+
+    /// Number of sessions accepted and not over yet: from `incoming()` yielding them to the end of
+    /// `upgrade_to_socks5()`.
+    pub fn active_sessions(&self) -> usize {
+        self.shared.active.load(Ordering::SeqCst)
+    }
+
+    /// Resolves once the shutdown started.
+    pub(crate) async fn stopping(&self) {
+        self.shared.stop.wait().await
+    }
+
+    /// Resolves once the deadline passed, and the sessions have to close.
+    pub(crate) async fn forced(&self) {
+        self.shared.force.wait().await
+    }
+
+    /// Count a session as active until the guard is dropped.
+    pub(crate) fn register(&self) -> SessionGuard {
+        self.shared.active.fetch_add(1, Ordering::SeqCst);
+        SessionGuard {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+pub(crate) struct SessionGuard {
+    shared: Arc<Shared>,
+}
+
+impl Drop for SessionGuard {
+    fn drop(&mut self) {
+        self.shared.active.fetch_sub(1, Ordering::SeqCst);
+        if self.shared.stop.is_fired() {
+            let _ = self.shared.ended.0.try_send(());
+        }
+    }
+}
@@ -0,0 +1,145 @@
+//! Protection against Server-Side Request Forgery.
+//!
+//! Stop the clients from reaching the loopback, the private networks or the cloud metadata
+//! endpoints through the proxy. The check is performed on the addresses the server is about to
+//! connect to, after the DNS resolution, so a domain resolving to an internal address (DNS
+//! rebinding) is blocked as well.
+use ipnet::IpNet;
+use std::net::{IpAddr, Ipv4Addr};
+
+/// Ranges forbidden by default.
+///
+/// The cloud metadata endpoints are included: `169.254.169.254` is link-local,
+/// `100.100.100.200` is within the shared address space and `fd00:ec2::254` is unique local.
+///
+/// The NAT64 (`64:ff9b::/96`) and 6to4 (`2002::/16`) addresses are checked against these ranges
+/// with the IPv4 address they embed as well.
+#[rustfmt::skip]
+pub const DEFAULT_FORBIDDEN_RANGES: &[&str] = &[
+    "0.0.0.0/8",       // "this" network
+    "10.0.0.0/8",      // private
+    "100.64.0.0/10",   // shared address space (carrier-grade NAT)
+    "127.0.0.0/8",     // loopback
+    "169.254.0.0/16",  // link-local
+    "172.16.0.0/12",   // private
+    "192.0.0.0/24",    // IETF protocol assignments
+    "192.168.0.0/16",  // private
+    "198.18.0.0/15",   // benchmarking
+    "224.0.0.0/4",     // multicast
+    "240.0.0.0/4",     // reserved, and broadcast
+    "::/128",          // unspecified
+    "::1/128",         // loopback
+    "fc00::/7",        // unique local
+    "fe80::/10",       // link-local
+    "ff00::/8",        // multicast
+];
+
+#[derive(Debug, Clone)]
+pub struct SsrfProtection {
+    forbidden: Vec<IpNet>,
+    exceptions: Vec<IpNet>,
+}
+
+impl Default for SsrfProtection {
+    /// Forbid the [`DEFAULT_FORBIDDEN_RANGES`].
+    fn default() -> Self {
+        SsrfProtection {
+            forbidden: DEFAULT_FORBIDDEN_RANGES
+                .iter()
+                .map(|range| range.parse().expect("invalid default range"))
+                .collect(),
+            exceptions: Vec::new(),
+        }
+    }
+}
+
+impl SsrfProtection {
+    /// Nothing forbidden, ranges have to be added with `add_forbidden()`.
+    pub fn empty() -> Self {
+        SsrfProtection {
+            forbidden: Vec::new(),
+            exceptions: Vec::new(),
+        }
+    }
+
+    pub fn add_forbidden(&mut self, network: IpNet) -> &mut Self {
+        self.forbidden.push(network);
+        self
+    }
+
+    /// Allow a network even if it belongs to a forbidden range,
+    /// eg. an internal service the clients are expected to reach.
+    pub fn add_exception(&mut self, network: IpNet) -> &mut Self {
+        self.exceptions.push(network);
+        self
+    }
+
+    pub fn is_allowed(&self, ip: IpAddr) -> bool {
+        // IPv4-mapped IPv6 addresses (::ffff:127.0.0.1) are checked as IPv4
+        let ip = ip.to_canonical();
+
+        self.allows(ip) && embedded_ipv4(ip).is_none_or(|ipv4| self.allows(ipv4.into()))
+    }
+
+    fn allows(&self, ip: IpAddr) -> bool {
+        self.exceptions.iter().any(|net| net.contains(&ip))
+            || !self.forbidden.iter().any(|net| net.contains(&ip))
+    }
+}
+
+/// IPv4 address reached through a NAT64 (`64:ff9b::/96`) or a 6to4 (`2002::/16`) address.
+fn embedded_ipv4(ip: IpAddr) -> Option<Ipv4Addr> {
+    let ip = match ip {
+        IpAddr::V6(ip) => ip,
+        IpAddr::V4(_) => return None,
+    };
+    let octets = ip.octets();
+    match ip.segments() {
+        [0x64, 0xff9b, 0, 0, 0, 0, _, _] => Some(Ipv4Addr::new(
+            octets[12], octets[13], octets[14], octets[15],
+        )),
+        [0x2002, ..] => Some(Ipv4Addr::new(octets[2], octets[3], octets[4], octets[5])),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::SsrfProtection;
+
+    #[test]
+    fn test_default_ranges() {
+        let mut protection = SsrfProtection::default();
+        for ip in &[
+            "127.0.0.1",
+            "::ffff:10.0.0.1",
+            "169.254.169.254",
+            "fd00:ec2::254",
+        ] {
+            assert!(!protection.is_allowed(ip.parse().unwrap()), "{}", ip);
+        }
+        for ip in &["1.1.1.1", "2606:4700:4700::1111"] {
+            assert!(protection.is_allowed(ip.parse().unwrap()), "{}", ip);
+        }
+
+        protection.add_exception("10.1.0.0/16".parse().unwrap());
+        assert!(protection.is_allowed("10.1.2.3".parse().unwrap()));
+        assert!(!protection.is_allowed("10.2.2.3".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_embedded_ipv4() {
+        let mut protection = SsrfProtection::default();
+        for ip in &["64:ff9b::a9fe:a9fe", "64:ff9b::127.0.0.1", "2002:a00:1::1"] {
+            assert!(!protection.is_allowed(ip.parse().unwrap()), "{}", ip);
+        }
+        for ip in &["64:ff9b::1.1.1.1", "2002:101:101::1"] {
+            assert!(protection.is_allowed(ip.parse().unwrap()), "{}", ip);
+        }
+
+        protection.add_exception("10.1.0.0/16".parse().unwrap());
+        assert!(protection.is_allowed("64:ff9b::10.1.2.3".parse().unwrap()));
+        protection.add_forbidden("2002::/16".parse().unwrap());
+        assert!(!protection.is_allowed("2002:101:101::1".parse().unwrap()));
+    }
+}
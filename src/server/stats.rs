@@ -0,0 +1,61 @@
+//! What happened during a session, for billing and analytics.
+use crate::server::registry::SessionId;
+use crate::server::CloseReason;
+use crate::util::target_addr::TargetAddr;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// Bytes relayed by a session, updated as they are transferred.
+#[derive(Debug, Default)]
+pub struct Traffic {
+    sent: AtomicU64,
+    received: AtomicU64,
+}
+
+impl Traffic {
+    /// Bytes relayed from the client to the target.
+    pub fn sent(&self) -> u64 {
+        self.sent.load(Ordering::Relaxed)
+    }
+
+    /// Bytes relayed from the target to the client.
+    pub fn received(&self) -> u64 {
+        self.received.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn sent_counter(&self) -> &AtomicU64 {
+        &self.sent
+    }
+
+    pub(crate) fn received_counter(&self) -> &AtomicU64 {
+        &self.received
+    }
+}
+
+/// Summary of a session.
+#[derive(Debug, Clone)]
+pub struct SessionStats {
+    pub id: SessionId,
+    pub peer_addr: Option<SocketAddr>,
+    /// Label of the listener which accepted the client.
+    pub listener: Option<String>,
+    /// Username of the authenticated client.
+    pub user: Option<String>,
+    /// Destination as requested by the client.
+    pub target: Option<TargetAddr>,
+    /// Address the server connected to.
+    pub remote_addr: Option<SocketAddr>,
+    /// Bytes relayed from the client to the target.
+    pub bytes_sent: u64,
+    /// Bytes relayed from the target to the client.
+    pub bytes_received: u64,
+    /// From accept to request parsed.
+    pub handshake_duration: Option<Duration>,
+    /// Time taken to connect to the target, DNS resolution excluded.
+    pub connect_latency: Option<Duration>,
+    /// From accept to close, or until now if the session is still running.
+    pub duration: Duration,
+    /// `None` if the session ended before relaying anything, or is still running.
+    pub close_reason: Option<CloseReason>,
+}
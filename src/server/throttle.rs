@@ -0,0 +1,319 @@
+//! Bandwidth throttling of the relayed sessions.
+//!
+//! Throughput is capped by token buckets: a bucket is refilled at `rate` bytes per second, and
+//! holds up to `burst` bytes, the amount a session can send at once after being quiet for a
+//! while. Each byte relayed has to go through every bucket applying to the session: its own one,
+//! the one shared by all the sessions of its user, and the global one. Upload (client to target)
+//! and download (target to client) are limited separately.
+//!
+//! All the limits can be changed while the server runs, and apply to the running sessions.
+//!
+//! ```
+//! use fast_socks5::server::throttle::{BandwidthLimiter, Limit, Limits};
+//!
+//! let limiter = BandwidthLimiter::default();
+//! limiter
+//!     .set_global_limits(Limits::symmetric(Limit::new(100_000_000, 10_000_000)))
+//!     .set_user_limits(Limits {
+//!         upload: Some(Limit::new(1_000_000, 2_000_000)),
+//!         download: Some(Limit::new(10_000_000, 20_000_000)),
+//!     })
+//!     .set_limits_for_user("admin", Some(Limits::unlimited()));
+//! ```
+use async_std::task;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
+
+/// Direction of the relayed bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// From the client to the target.
+    Upload,
+    /// From the target to the client.
+    Download,
+}
+
+/// Throughput limit of a token bucket.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limit {
+    /// Bytes per second, at least 1.
+    pub rate: u64,
+    /// Bytes which can be sent at once, at least 1.
+    pub burst: u64,
+}
+
+impl Limit {
+    pub fn new(rate: u64, burst: u64) -> Self {
+        Limit {
+            rate: rate.max(1),
+            burst: burst.max(1),
+        }
+    }
+}
+
+/// Limits of both directions, `None` is unlimited.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Limits {
+    pub upload: Option<Limit>,
+    pub download: Option<Limit>,
+}
+
+impl Limits {
+    pub fn unlimited() -> Self {
+        Limits::default()
+    }
+
+    /// Same limit for the upload and the download.
+    pub fn symmetric(limit: Limit) -> Self {
+        Limits {
+            upload: Some(limit),
+            download: Some(limit),
+        }
+    }
+}
+
+struct BucketState {
+    limit: Option<Limit>,
+    /// Negative when in debt: bytes have been let through ahead of the refill
+    tokens: f64,
+    last_refill: Instant,
+}
+
+struct TokenBucket {
+    state: Mutex<BucketState>,
+}
+
+impl TokenBucket {
+    fn new(limit: Option<Limit>) -> Self {
+        TokenBucket {
+            state: Mutex::new(BucketState {
+                limit,
+                tokens: limit.map_or(0.0, |limit| limit.burst as f64),
+                last_refill: Instant::now(),
+            }),
+        }
+    }
+
+    fn set_limit(&self, limit: Option<Limit>) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(limit) = limit {
+            state.tokens = state.tokens.min(limit.burst as f64);
+        }
+        state.limit = limit;
+    }
+
+    /// Take `n` tokens, returns how long to wait for the bucket to be refilled.
+    fn consume(&self, n: usize) -> Duration {
+        let mut state = self.state.lock().unwrap();
+        let limit = match state.limit {
+            Some(limit) => limit,
+            None => return Duration::from_secs(0),
+        };
+
+        let now = Instant::now();
+        let refill = now.duration_since(state.last_refill).as_secs_f64() * limit.rate as f64;
+        state.last_refill = now;
+        state.tokens = (state.tokens + refill).min(limit.burst as f64) - n as f64;
+
+        if state.tokens >= 0.0 {
+            Duration::from_secs(0)
+        } else {
+            Duration::from_secs_f64(-state.tokens / limit.rate as f64)
+        }
+    }
+}
+
+struct Buckets {
+    upload: TokenBucket,
+    download: TokenBucket,
+}
+
+impl Buckets {
+    fn new(limits: Limits) -> Self {
+        Buckets {
+            upload: TokenBucket::new(limits.upload),
+            download: TokenBucket::new(limits.download),
+        }
+    }
+
+    fn set_limits(&self, limits: Limits) {
+        self.upload.set_limit(limits.upload);
+        self.download.set_limit(limits.download);
+    }
+
+    fn consume(&self, direction: Direction, n: usize) -> Duration {
+        match direction {
+            Direction::Upload => self.upload.consume(n),
+            Direction::Download => self.download.consume(n),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Connections {
+    limits: Limits,
+    live: Vec<Weak<Buckets>>,
+}
+
+#[derive(Default)]
+struct Users {
+    limits: Limits,
+    overrides: HashMap<String, Limits>,
+    /// Buckets shared by the running sessions of each user
+    live: HashMap<String, Weak<Buckets>>,
+}
+
+impl Users {
+    fn limits_of(&self, user: &str) -> Limits {
+        self.overrides.get(user).copied().unwrap_or(self.limits)
+    }
+
+    /// Apply the current limits to the running sessions.
+    fn update_live(&mut self) {
+        let live = std::mem::take(&mut self.live);
+        self.live = live
+            .into_iter()
+            .filter_map(|(user, buckets)| {
+                let buckets = buckets.upgrade()?;
+                buckets.set_limits(self.limits_of(&user));
+                Some((user, Arc::downgrade(&buckets)))
+            })
+            .collect();
+    }
+}
+
+/// Bandwidth limits of the server, unlimited by default.
+pub struct BandwidthLimiter {
+    global: Buckets,
+    connections: Mutex<Connections>,
+    users: Mutex<Users>,
+}
+
+impl Default for BandwidthLimiter {
+    fn default() -> Self {
+        BandwidthLimiter {
+            global: Buckets::new(Limits::unlimited()),
+            connections: Mutex::new(Connections::default()),
+            users: Mutex::new(Users::default()),
+        }
+    }
+}
+
+impl BandwidthLimiter {
+    /// Limits shared by all the sessions.
+    pub fn set_global_limits(&self, limits: Limits) -> &Self {
+        self.global.set_limits(limits);
+        self
+    }
+
+    /// Limits of each session.
+    pub fn set_connection_limits(&self, limits: Limits) -> &Self {
+        let mut connections = self.connections.lock().unwrap();
+        connections.limits = limits;
+        connections.live.retain(|buckets| match buckets.upgrade() {
+            Some(buckets) => {
+                buckets.set_limits(limits);
+                true
+            }
+            None => false,
+        });
+        self
+    }
+
+    /// Limits shared by all the sessions of an authenticated user,
+    /// unless the user has its own limits.
+    pub fn set_user_limits(&self, limits: Limits) -> &Self {
+        let mut users = self.users.lock().unwrap();
+        users.limits = limits;
+        users.update_live();
+        self
+    }
+
+    /// Limits of a specific user, `None` to go back to the limits set by `set_user_limits()`.
+    pub fn set_limits_for_user(&self, user: &str, limits: Option<Limits>) -> &Self {
+        let mut users = self.users.lock().unwrap();
+        match limits {
+            Some(limits) => users.overrides.insert(user.to_string(), limits),
+            None => users.overrides.remove(user),
+        };
+        users.update_live();
+        self
+    }
+
+    /// Buckets applying to a new session.
+    pub(crate) fn session(self: &Arc<Self>, user: Option<&str>) -> SessionThrottle {
+        let connection = {
+            let mut connections = self.connections.lock().unwrap();
+            let buckets = Arc::new(Buckets::new(connections.limits));
+            connections
+                .live
+                .retain(|buckets| buckets.strong_count() > 0);
+            connections.live.push(Arc::downgrade(&buckets));
+            buckets
+        };
+
+        let user = user.map(|user| {
+            let mut users = self.users.lock().unwrap();
+            if let Some(buckets) = users.live.get(user).and_then(Weak::upgrade) {
+                return buckets;
+            }
+
+            let buckets = Arc::new(Buckets::new(users.limits_of(user)));
+            users.live.retain(|_, buckets| buckets.strong_count() > 0);
+            users
+                .live
+                .insert(user.to_string(), Arc::downgrade(&buckets));
+            buckets
+        });
+
+        SessionThrottle {
+            limiter: self.clone(),
+            connection,
+            user,
+        }
+    }
+}
+
+/// Every bucket a session goes through.
+pub(crate) struct SessionThrottle {
+    limiter: Arc<BandwidthLimiter>,
+    connection: Arc<Buckets>,
+    user: Option<Arc<Buckets>>,
+}
+
+impl SessionThrottle {
+    /// Wait until `n` bytes can be relayed.
+    pub(crate) async fn consume(&self, direction: Direction, n: usize) {
+        let delay = self
+            .connection
+            .consume(direction, n)
+            .max(self.limiter.global.consume(direction, n));
+        let delay = match &self.user {
+            Some(user) => delay.max(user.consume(direction, n)),
+            None => delay,
+        };
+
+        if delay > Duration::from_secs(0) {
+            task::sleep(delay).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Limit, TokenBucket};
+    use std::time::Duration;
+
+    #[test]
+    fn test_token_bucket() {
+        let bucket = TokenBucket::new(Some(Limit::new(1000, 2000)));
+        assert_eq!(bucket.consume(2000), Duration::from_secs(0));
+
+        let delay = bucket.consume(500);
+        assert!(delay > Duration::from_millis(490) && delay <= Duration::from_millis(500));
+
+        bucket.set_limit(None);
+        assert_eq!(bucket.consume(1_000_000), Duration::from_secs(0));
+    }
+}
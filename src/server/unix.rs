@@ -0,0 +1,289 @@
+//! Clients connected through Unix domain sockets, see `Socks5Server::add_unix_listener()`.
+//!
+//! Such clients have no IP address, they are identified by the credentials of their process
+//! instead (`SO_PEERCRED`, Linux only), which the authentication and the access control rules
+//! can match.
+#[cfg(unix)]
+use async_std::os::unix::net::{UnixListener, UnixStream};
+use std::fmt;
+#[cfg(unix)]
+use std::io;
+#[cfg(unix)]
+use std::path::{Path, PathBuf};
+
+/// Credentials of the process at the other end of a Unix socket, as of `connect()`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PeerCredentials {
+    pub uid: u32,
+    pub gid: u32,
+    /// `None` if the process lives in another PID namespace
+    pub pid: Option<i32>,
+}
+
+impl fmt::Display for PeerCredentials {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "uid={} gid={}", self.uid, self.gid)?;
+        if let Some(pid) = self.pid {
+            write!(f, " pid={}", pid)?;
+        }
+        Ok(())
+    }
+}
+
+/// Credentials of the peer of this socket, `None` if the platform can't tell.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn peer_credentials(stream: &UnixStream) -> io::Result<Option<PeerCredentials>> {
+    let credentials = rustix::net::sockopt::socket_peercred(stream)?;
+    Ok(Some(PeerCredentials {
+        uid: credentials.uid.as_raw(),
+        gid: credentials.gid.as_raw(),
+        pid: Some(credentials.pid.as_raw_pid()).filter(|pid| *pid > 0),
+    }))
+}
+
+#[cfg(all(unix, not(any(target_os = "linux", target_os = "android"))))]
+pub fn peer_credentials(_stream: &UnixStream) -> io::Result<Option<PeerCredentials>> {
+    Ok(None)
+}
+
+/// How the socket file of a Unix listener is created.
+#[cfg(unix)]
+#[derive(Debug, Clone)]
+pub struct UnixListenerOptions {
+    mode: Option<u32>,
+    remove_stale: bool,
+}
+
+#[cfg(unix)]
+impl Default for UnixListenerOptions {
+    fn default() -> Self {
+        UnixListenerOptions {
+            mode: None,
+            remove_stale: true,
+        }
+    }
+}
+
+#[cfg(unix)]
+impl UnixListenerOptions {
+    /// Permissions of the socket file, eg. `0o660` to let only the group connect. They apply
+    /// as soon as the file appears: it's created in a private directory next to it, then moved.
+    /// Default is to keep the ones of the umask.
+    pub fn set_mode(&mut self, mode: u32) -> &mut Self {
+        self.mode = Some(mode);
+        self
+    }
+
+    /// Remove the socket file left by a server which didn't stop cleanly, if no server listens
+    /// on it anymore. Enabled by default.
+    pub fn set_remove_stale(&mut self, value: bool) -> &mut Self {
+        self.remove_stale = value;
+        self
+    }
+
+    pub(crate) async fn bind(&self, path: &Path) -> io::Result<BoundUnixListener> {
+        if self.remove_stale {
+            remove_stale(path).await?;
+        }
+
+        let listener = match self.mode {
+            Some(mode) => bind_with_mode(path, mode).await?,
+            None => UnixListener::bind(path).await?,
+        };
+        Ok(BoundUnixListener {
+            listener,
+            created: Some(path.to_path_buf()),
+        })
+    }
+}
+
+/// Id of the next private directory, unique within the process
+#[cfg(unix)]
+static NEXT_PRIVATE_DIR: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+
+/// Bind the socket in a private directory next to `path`, then move it into place once it has
+/// its permissions: nobody can connect to it meanwhile, whatever the umask.
+#[cfg(unix)]
+async fn bind_with_mode(path: &Path, mode: u32) -> io::Result<UnixListener> {
+    use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+    use std::sync::atomic::Ordering;
+
+    let parent = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    // short, the path of a socket is limited to about 100 bytes
+    let dir = parent.join(format!(
+        ".{}-{}",
+        std::process::id(),
+        NEXT_PRIVATE_DIR.fetch_add(1, Ordering::Relaxed)
+    ));
+    std::fs::DirBuilder::new().mode(0o700).create(&dir)?;
+
+    let private = dir.join("s");
+    let result = async {
+        let listener = UnixListener::bind(&private).await?;
+        std::fs::set_permissions(&private, std::fs::Permissions::from_mode(mode))?;
+        std::fs::rename(&private, path)?;
+        Ok(listener)
+    }
+    .await;
+
+    if result.is_err() {
+        let _ = std::fs::remove_file(&private);
+    }
+    if let Err(e) = std::fs::remove_dir(&dir) {
+        debug!("Can't remove {}: {}", dir.display(), e);
+    }
+    result
+}
+
+/// Remove the socket file if nobody listens on it.
+#[cfg(unix)]
+async fn remove_stale(path: &Path) -> io::Result<()> {
+    use std::os::unix::fs::FileTypeExt;
+
+    let metadata = match std::fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    if !metadata.file_type().is_socket() {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("{} exists and isn't a socket", path.display()),
+        ));
+    }
+
+    match UnixStream::connect(path).await {
+        Ok(_) => Err(io::Error::new(
+            io::ErrorKind::AddrInUse,
+            format!("{} is in use by another server", path.display()),
+        )),
+        Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => {
+            warn!("Removing the stale socket {}", path.display());
+            std::fs::remove_file(path)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Removes the socket file it created once dropped.
+#[cfg(unix)]
+pub(crate) struct BoundUnixListener {
+    pub(crate) listener: UnixListener,
+    /// `None` if the socket was inherited, its file belongs to the parent process
+    pub(crate) created: Option<PathBuf>,
+}
+
+#[cfg(unix)]
+impl BoundUnixListener {
+    pub(crate) fn inherited(listener: UnixListener) -> Self {
+        BoundUnixListener {
+            listener,
+            created: None,
+        }
+    }
+
+    /// Path of the socket file, empty for an unnamed or abstract socket.
+    pub(crate) fn path(&self) -> io::Result<PathBuf> {
+        match &self.created {
+            Some(path) => Ok(path.clone()),
+            None => Ok(self
+                .listener
+                .local_addr()?
+                .as_pathname()
+                .map(Path::to_path_buf)
+                .unwrap_or_default()),
+        }
+    }
+}
+
+#[cfg(unix)]
+impl Drop for BoundUnixListener {
+    fn drop(&mut self) {
+        if let Some(path) = &self.created {
+            if let Err(e) = std::fs::remove_file(path) {
+                debug!("Can't remove {}: {}", path.display(), e);
+            }
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod test {
+    use super::UnixListenerOptions;
+    use async_std::os::unix::net::UnixStream;
+    use async_std::task;
+    use std::os::unix::fs::PermissionsExt;
+
+    #[test]
+    fn test_stale_socket() {
+        task::block_on(async {
+            let path =
+                std::env::temp_dir().join(format!("fast-socks5-{}.sock", std::process::id()));
+            let mut options = UnixListenerOptions::default();
+            options.set_mode(0o600);
+
+            // left by a server which crashed
+            let stale = std::os::unix::net::UnixListener::bind(&path).unwrap();
+            drop(stale);
+
+            let bound = options.bind(&path).await.unwrap();
+            let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+            UnixStream::connect(&path).await.unwrap();
+
+            let in_use = options.bind(&path).await;
+            assert_eq!(
+                in_use.err().map(|e| e.kind()),
+                Some(std::io::ErrorKind::AddrInUse)
+            );
+
+            drop(bound);
+            assert!(!path.exists());
+        });
+    }
+
+    #[test]
+    fn test_mode_applies_from_the_start() {
+        task::block_on(async {
+            let dir = std::env::temp_dir().join(format!("fast-socks5-mode-{}", std::process::id()));
+            std::fs::create_dir(&dir).unwrap();
+            let path = dir.join("socks.sock");
+            let mut options = UnixListenerOptions::default();
+            options.set_mode(0o600);
+
+            // The window before the permissions are set is short, watch several binds
+            for _ in 0..1000 {
+                let watched = path.clone();
+                let (ready, started) = std::sync::mpsc::channel();
+                let watcher = std::thread::spawn(move || {
+                    ready.send(()).unwrap();
+                    loop {
+                        if let Ok(metadata) = std::fs::symlink_metadata(&watched) {
+                            return metadata.permissions().mode();
+                        }
+                    }
+                });
+                started.recv().unwrap();
+                let bound = options.bind(&path).await.unwrap();
+                let first_mode = watcher.join().unwrap();
+                assert_eq!(first_mode & 0o777, 0o600);
+                drop(bound);
+            }
+
+            let bound = options.bind(&path).await.unwrap();
+            UnixStream::connect(&path).await.unwrap();
+            // The private directory is gone
+            let entries: Vec<_> = std::fs::read_dir(&dir)
+                .unwrap()
+                .map(|entry| entry.unwrap().file_name())
+                .collect();
+            assert_eq!(entries, ["socks.sock"]);
+
+            drop(bound);
+            std::fs::remove_dir(&dir).unwrap();
+        });
+    }
+}
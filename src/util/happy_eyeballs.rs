@@ -0,0 +1,223 @@
+//! Happy Eyeballs v2 ([RFC 8305](https://tools.ietf.org/html/rfc8305)) connection racing.
+//!
+//! The resolved addresses are interleaved by family (the first family returned by the resolver
+//! goes first), then a new connection attempt is started every `attempt_delay`, or as soon as the
+//! previous attempt failed. The first attempt to succeed wins and every other attempt is dropped.
+use async_std::{net::SocketAddr, net::TcpStream, task};
+use futures::future::{self, Either};
+use futures::stream::{FuturesUnordered, StreamExt};
+use std::io;
+use std::time::Duration;
+
+/// Default "Connection Attempt Delay" recommended by the RFC.
+pub const DEFAULT_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// Reorder addresses so that IPv6 and IPv4 alternate, starting with the family of the first
+/// address. The relative order within a family (the resolver's preference) is kept.
+pub fn interleave(addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let prefer_v6 = match addrs.first() {
+        Some(addr) => addr.is_ipv6(),
+        None => return addrs,
+    };
+    let (v6, v4): (Vec<_>, Vec<_>) = addrs.into_iter().partition(|a| a.is_ipv6());
+    let (first, second) = if prefer_v6 { (v6, v4) } else { (v4, v6) };
+
+    let mut sorted = Vec::with_capacity(first.len() + second.len());
+    let mut first = first.into_iter();
+    let mut second = second.into_iter();
+    loop {
+        match (first.next(), second.next()) {
+            (None, None) => break,
+            (a, b) => sorted.extend(a.into_iter().chain(b)),
+        }
+    }
+    sorted
+}
+
+/// Race connection attempts to `addrs`.
+///
+/// On success, returns the winning stream with the address it is connected to. If every attempt
+/// failed, the errors of each attempt are returned, in the order they happened.
+pub async fn connect(
+    addrs: Vec<SocketAddr>,
+    attempt_delay: Duration,
+) -> Result<(TcpStream, SocketAddr), Vec<(SocketAddr, io::Error)>> {
+    let mut remaining = interleave(addrs).into_iter();
+    let mut attempts = FuturesUnordered::new();
+    let mut errors = Vec::new();
+
+    let attempt = |addr: SocketAddr| async move {
+        debug!("Happy Eyeballs: connection attempt to {}", addr);
+        (addr, TcpStream::connect(addr).await)
+    };
+
+    loop {
+        if attempts.is_empty() {
+            match remaining.next() {
+                Some(addr) => attempts.push(attempt(addr)),
+                None => return Err(errors),
+            }
+        }
+
+        // When there is no address left to try, there is nothing to stagger anymore:
+        // just wait for the in-flight attempts.
+        let next_attempt_due = if remaining.len() > 0 {
+            Either::Left(Box::pin(task::sleep(attempt_delay)))
+        } else {
+            Either::Right(future::pending::<()>())
+        };
+
+        // `None` means the attempt delay elapsed before any in-flight attempt completed
+        let completed = match future::select(attempts.next(), next_attempt_due).await {
+            Either::Left((completed, _)) => completed,
+            Either::Right(_) => None,
+        };
+
+        match completed {
+            Some((addr, Ok(stream))) => {
+                debug!("Happy Eyeballs: connected to {}", addr);
+                // dropping `attempts` cancels the losers
+                return Ok((stream, addr));
+            }
+            Some((addr, Err(err))) => {
+                debug!("Happy Eyeballs: attempt to {} failed: {}", addr, err);
+                errors.push((addr, err));
+            }
+            None => {}
+        }
+
+        // a failed attempt starts the next one right away
+        if let Some(addr) = remaining.next() {
+            attempts.push(attempt(addr));
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{connect, interleave};
+    #[cfg(unix)]
+    use crate::util::testing::blackhole;
+    use async_std::net::{SocketAddr, TcpListener};
+    use async_std::{future, task};
+    use std::time::{Duration, Instant};
+
+    fn addrs(list: &[&str]) -> Vec<SocketAddr> {
+        list.iter().map(|a| a.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn test_interleave() {
+        let sorted = interleave(addrs(&[
+            "[::1]:80",
+            "[::2]:80",
+            "[::3]:80",
+            "1.1.1.1:80",
+            "2.2.2.2:80",
+        ]));
+        assert_eq!(
+            sorted,
+            addrs(&[
+                "[::1]:80",
+                "1.1.1.1:80",
+                "[::2]:80",
+                "2.2.2.2:80",
+                "[::3]:80"
+            ])
+        );
+
+        let sorted = interleave(addrs(&["1.1.1.1:80", "2.2.2.2:80", "[::1]:80"]));
+        assert_eq!(sorted, addrs(&["1.1.1.1:80", "[::1]:80", "2.2.2.2:80"]));
+    }
+
+    /// Address where connecting is refused.
+    async fn refused() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        listener.local_addr().unwrap()
+    }
+
+    #[async_std::test]
+    async fn test_connect_first() {
+        let first = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let second = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addrs = vec![first.local_addr().unwrap(), second.local_addr().unwrap()];
+
+        let (_, addr) = connect(addrs.clone(), Duration::from_millis(50))
+            .await
+            .unwrap();
+        assert_eq!(addr, addrs[0]);
+        // No attempt to the next address once connected
+        task::sleep(Duration::from_millis(100)).await;
+        let accepted = future::timeout(Duration::from_millis(100), second.accept()).await;
+        assert!(accepted.is_err());
+    }
+
+    #[cfg(unix)]
+    #[async_std::test]
+    async fn test_attempt_delay() {
+        let (blackholed, _listener, _filler) = blackhole();
+        let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target_addr = target.local_addr().unwrap();
+
+        let start = Instant::now();
+        let (_, addr) = connect(vec![blackholed, target_addr], Duration::from_millis(200))
+            .await
+            .unwrap();
+        // The second attempt starts after the delay, while the first one still hangs
+        assert_eq!(addr, target_addr);
+        assert!(start.elapsed() >= Duration::from_millis(200));
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[cfg(unix)]
+    #[async_std::test]
+    async fn test_race() {
+        let (blackholed, _listener, _filler) = blackhole();
+        let (other_blackholed, _other_listener, _other_filler) = blackhole();
+        let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target_addr = target.local_addr().unwrap();
+
+        // Every attempt is still in flight when the last one wins
+        let start = Instant::now();
+        let addrs = vec![blackholed, other_blackholed, target_addr];
+        let (_, addr) = connect(addrs, Duration::from_millis(50)).await.unwrap();
+        assert_eq!(addr, target_addr);
+        assert!(start.elapsed() >= Duration::from_millis(100));
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[async_std::test]
+    async fn test_refused() {
+        let refused = refused().await;
+        let target = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target_addr = target.local_addr().unwrap();
+
+        // A failed attempt doesn't wait for the delay to start the next one
+        let start = Instant::now();
+        let (_, addr) = connect(vec![refused, target_addr], Duration::from_secs(10))
+            .await
+            .unwrap();
+        assert_eq!(addr, target_addr);
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[async_std::test]
+    async fn test_every_attempt_failed() {
+        let first = refused().await;
+        let second = refused().await;
+
+        let errors = connect(vec![first, second], Duration::from_millis(50))
+            .await
+            .unwrap_err();
+        let failed: Vec<_> = errors.iter().map(|(addr, _)| *addr).collect();
+        assert_eq!(failed, [first, second]);
+        assert!(errors
+            .iter()
+            .all(|(_, e)| e.kind() == std::io::ErrorKind::ConnectionRefused));
+
+        assert!(connect(Vec::new(), Duration::from_millis(50))
+            .await
+            .unwrap_err()
+            .is_empty());
+    }
+}
@@ -1,2 +1,8 @@
+pub(crate) mod date;
+pub mod happy_eyeballs;
+pub(crate) mod signal;
+pub(crate) mod span;
 pub mod stream;
 pub mod target_addr;
+#[cfg(all(test, unix))]
+pub(crate) mod testing;
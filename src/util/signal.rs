@@ -0,0 +1,28 @@
+use async_std::channel::{bounded, Receiver, Sender};
+
+/// Fired once, wakes up everyone waiting for it.
+#[derive(Clone)]
+pub(crate) struct Signal {
+    sender: Sender<()>,
+    receiver: Receiver<()>,
+}
+
+impl Signal {
+    pub(crate) fn new() -> Self {
+        let (sender, receiver) = bounded(1);
+        Signal { sender, receiver }
+    }
+
+    pub(crate) fn fire(&self) {
+        self.sender.close();
+    }
+
+    pub(crate) fn is_fired(&self) -> bool {
+        self.sender.is_closed()
+    }
+
+    pub(crate) async fn wait(&self) {
+        // nothing is ever sent, this only returns once the channel is closed
+        let _ = self.receiver.recv().await;
+    }
+}
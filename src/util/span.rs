@@ -0,0 +1,118 @@
+//! Spans of the sessions with the `tracing` feature, doing nothing without.
+//!
+//! The session span carries the session id, listener, peer, user and target. The handshake, DNS and
+//! connect spans are created inside it, as its children.
+use crate::util::target_addr::TargetAddr;
+use futures::Future;
+use std::fmt;
+use std::net::SocketAddr;
+
+#[derive(Clone)]
+pub(crate) struct Span {
+    #[cfg(feature = "tracing")]
+    inner: tracing::Span,
+}
+
+#[cfg(feature = "tracing")]
+impl Span {
+    pub(crate) fn none() -> Self {
+        Span {
+            inner: tracing::Span::none(),
+        }
+    }
+
+    pub(crate) fn session(id: u64, peer_addr: Option<SocketAddr>, listener: Option<&str>) -> Self {
+        let inner = info_span!(
+            "session",
+            id,
+            listener,
+            peer = tracing::field::Empty,
+            user = tracing::field::Empty,
+            target = tracing::field::Empty,
+        );
+        if let Some(peer_addr) = peer_addr {
+            inner.record("peer", tracing::field::display(peer_addr));
+        }
+        Span { inner }
+    }
+
+    pub(crate) fn client<P: fmt::Display>(proxy: P) -> Self {
+        Span {
+            inner: info_span!(
+                "socks5_client",
+                proxy = %proxy,
+                target = tracing::field::Empty,
+            ),
+        }
+    }
+
+    pub(crate) fn handshake() -> Self {
+        Span {
+            inner: info_span!("handshake"),
+        }
+    }
+
+    pub(crate) fn dns() -> Self {
+        Span {
+            inner: info_span!("dns"),
+        }
+    }
+
+    pub(crate) fn connect() -> Self {
+        Span {
+            inner: info_span!("connect"),
+        }
+    }
+
+    pub(crate) fn record_user(&self, user: &str) {
+        self.inner.record("user", user);
+    }
+
+    pub(crate) fn record_target(&self, target: &TargetAddr) {
+        self.inner.record("target", tracing::field::display(target));
+    }
+
+    /// Run the future inside the span.
+    pub(crate) fn instrument<F: Future>(&self, future: F) -> impl Future<Output = F::Output> {
+        tracing::Instrument::instrument(future, self.inner.clone())
+    }
+}
+
+#[cfg(not(feature = "tracing"))]
+impl Span {
+    pub(crate) fn none() -> Self {
+        Span {}
+    }
+
+    pub(crate) fn session(
+        _id: u64,
+        _peer_addr: Option<SocketAddr>,
+        _listener: Option<&str>,
+    ) -> Self {
+        Span {}
+    }
+
+    pub(crate) fn client<P: fmt::Display>(_proxy: P) -> Self {
+        Span {}
+    }
+
+    pub(crate) fn handshake() -> Self {
+        Span {}
+    }
+
+    pub(crate) fn dns() -> Self {
+        Span {}
+    }
+
+    pub(crate) fn connect() -> Self {
+        Span {}
+    }
+
+    pub(crate) fn record_user(&self, _user: &str) {}
+
+    pub(crate) fn record_target(&self, _target: &TargetAddr) {}
+
+    pub(crate) fn instrument<F: Future>(&self, future: F) -> impl Future<Output = F::Output> {
+        future
+    }
+}
@@ -1,3 +1,12 @@
+use async_std::net::{Shutdown, TcpStream};
+#[cfg(unix)]
+use async_std::os::unix::net::UnixStream;
+use futures::{AsyncRead, AsyncWrite, Future};
+use std::io;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
+
 /// Easy to destructure bytes buffers by naming each fields:
 ///
 /// # Examples (before)
@@ -28,3 +37,243 @@ macro_rules! read_exact {
         $stream.read_exact(&mut x).await.map(|_| x)
     }};
 }
+
+/// Same as [`read_exact!`], but fails with [`io::ErrorKind::TimedOut`] if the bytes
+/// don't come within the timeout. A `None` timeout waits forever.
+///
+/// # Examples
+///
+/// ```ignore
+/// let [version, method_len] = read_exact_timeout!(stream, [0u8; 2], Some(Duration::from_secs(5)));
+/// ```
+#[macro_export]
+macro_rules! read_exact_timeout {
+    ($stream: expr, $array: expr, $timeout: expr) => {{
+        let timeout: Option<std::time::Duration> = $timeout;
+        let mut x = $array;
+        $crate::util::stream::timeout(timeout, $stream.read_exact(&mut x))
+            .await
+            .map(|_| x)
+    }};
+}
+
+/// Await an I/O future with an optional timeout.
+pub async fn timeout<F, T>(duration: Option<Duration>, future: F) -> io::Result<T>
+where
+    F: Future<Output = io::Result<T>>,
+{
+    match duration {
+        Some(duration) => async_std::io::timeout(duration, future).await,
+        None => future.await,
+    }
+}
+
+/// Streams able to shut down their write side alone: the peer is told no more data is coming,
+/// while its data can still be received.
+///
+/// Required to relay half-closed connections to the client, see `Socks5Socket::set_half_close()`,
+/// as `AsyncWrite::poll_close()` of the async-std sockets only flushes them.
+pub trait HalfClose {
+    fn shutdown_write(&self) -> io::Result<()>;
+
+    /// Shut down both sides, once the session is over. Only the write side by default.
+    fn shutdown_both(&self) -> io::Result<()> {
+        self.shutdown_write()
+    }
+}
+
+impl HalfClose for TcpStream {
+    fn shutdown_write(&self) -> io::Result<()> {
+        self.shutdown(Shutdown::Write)
+    }
+
+    fn shutdown_both(&self) -> io::Result<()> {
+        self.shutdown(Shutdown::Both)
+    }
+}
+
+#[cfg(unix)]
+impl HalfClose for UnixStream {
+    fn shutdown_write(&self) -> io::Result<()> {
+        self.shutdown(Shutdown::Write)
+    }
+
+    fn shutdown_both(&self) -> io::Result<()> {
+        self.shutdown(Shutdown::Both)
+    }
+}
+
+impl<T: HalfClose + ?Sized> HalfClose for &mut T {
+    fn shutdown_write(&self) -> io::Result<()> {
+        (**self).shutdown_write()
+    }
+
+    fn shutdown_both(&self) -> io::Result<()> {
+        (**self).shutdown_both()
+    }
+}
+
+/// Connection of a client accepted by `Socks5Server`, through TCP or a Unix socket.
+#[derive(Debug)]
+pub enum ClientStream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl From<TcpStream> for ClientStream {
+    fn from(stream: TcpStream) -> Self {
+        ClientStream::Tcp(stream)
+    }
+}
+
+#[cfg(unix)]
+impl From<UnixStream> for ClientStream {
+    fn from(stream: UnixStream) -> Self {
+        ClientStream::Unix(stream)
+    }
+}
+
+impl HalfClose for ClientStream {
+    fn shutdown_write(&self) -> io::Result<()> {
+        match self {
+            ClientStream::Tcp(stream) => stream.shutdown_write(),
+            #[cfg(unix)]
+            ClientStream::Unix(stream) => stream.shutdown_write(),
+        }
+    }
+
+    fn shutdown_both(&self) -> io::Result<()> {
+        match self {
+            ClientStream::Tcp(stream) => stream.shutdown_both(),
+            #[cfg(unix)]
+            ClientStream::Unix(stream) => stream.shutdown_both(),
+        }
+    }
+}
+
+impl AsyncRead for ClientStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            ClientStream::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+            #[cfg(unix)]
+            ClientStream::Unix(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for ClientStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            ClientStream::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+            #[cfg(unix)]
+            ClientStream::Unix(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            ClientStream::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+            #[cfg(unix)]
+            ClientStream::Unix(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            ClientStream::Tcp(stream) => Pin::new(stream).poll_close(cx),
+            #[cfg(unix)]
+            ClientStream::Unix(stream) => Pin::new(stream).poll_close(cx),
+        }
+    }
+}
+
+/// [`HalfClose`] of a stream, picked when the stream is known to implement it.
+pub(crate) struct HalfCloseFns<S> {
+    write: fn(&S) -> io::Result<()>,
+    both: fn(&S) -> io::Result<()>,
+}
+
+impl<S: HalfClose> HalfCloseFns<S> {
+    pub(crate) fn of() -> Self {
+        HalfCloseFns {
+            write: S::shutdown_write,
+            both: S::shutdown_both,
+        }
+    }
+}
+
+impl<S> Clone for HalfCloseFns<S> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<S> Copy for HalfCloseFns<S> {}
+
+/// Wrapper whose `poll_close()` shuts down the write side of the stream, with [`HalfClose`].
+/// Without it, `poll_close()` of the stream is called, which may close both sides (eg. TLS).
+pub(crate) struct ShutdownOnClose<'a, S> {
+    stream: &'a mut S,
+    half_close: Option<HalfCloseFns<S>>,
+}
+
+impl<'a, S: AsyncWrite + Unpin> ShutdownOnClose<'a, S> {
+    pub(crate) fn new(stream: &'a mut S, half_close: Option<HalfCloseFns<S>>) -> Self {
+        ShutdownOnClose { stream, half_close }
+    }
+
+    pub(crate) fn can_half_close(&self) -> bool {
+        self.half_close.is_some()
+    }
+
+    /// Shut down both sides, once the session is over.
+    pub(crate) async fn close_both(self) -> io::Result<()> {
+        match self.half_close {
+            Some(half_close) => (half_close.both)(self.stream),
+            None => futures::AsyncWriteExt::close(self.stream).await,
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for ShutdownOnClose<'_, S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut *self.stream).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for ShutdownOnClose<'_, S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut *self.stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut *self.stream).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.half_close {
+            Some(half_close) => {
+                ready!(Pin::new(&mut *self.stream).poll_flush(cx))?;
+                Poll::Ready((half_close.write)(self.stream))
+            }
+            None => Pin::new(&mut *self.stream).poll_close(cx),
+        }
+    }
+}
@@ -1,10 +1,11 @@
 use crate::consts;
-use crate::read_exact;
+use crate::read_exact_timeout;
 use anyhow::Context;
 use async_std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6, ToSocketAddrs};
 use futures::{AsyncRead, AsyncReadExt};
 use std::fmt;
 use std::io;
+use std::time::Duration;
 use std::vec::IntoIter;
 use thiserror::Error;
 
@@ -47,20 +48,36 @@ impl TargetAddr {
     pub async fn resolve_dns(self) -> anyhow::Result<TargetAddr> {
         match self {
             TargetAddr::Ip(ip) => Ok(TargetAddr::Ip(ip)),
+            TargetAddr::Domain(_, _) => {
+                let socket_addr = self.resolve_dns_all().await?[0];
+
+                // has been converted to an ip
+                Ok(TargetAddr::Ip(socket_addr))
+            }
+        }
+    }
+
+    /// Resolve the domain name to every address returned by the resolver, in the order of
+    /// preference of the system. Never returns an empty list.
+    pub async fn resolve_dns_all(&self) -> anyhow::Result<Vec<SocketAddr>> {
+        match self {
+            TargetAddr::Ip(ip) => Ok(vec![*ip]),
             TargetAddr::Domain(domain, port) => {
                 debug!("Attempt to DNS resolve the domain {}...", &domain);
-                let socket_addr = (&domain[..], port)
+                let socket_addrs: Vec<SocketAddr> = (&domain[..], *port)
                     .to_socket_addrs()
                     .await
                     .context(AddrError::DNSResolutionFailed)?
-                    .next()
-                    .ok_or(AddrError::Custom(
+                    .collect();
+
+                if socket_addrs.is_empty() {
+                    return Err(AddrError::Custom(
                         "Can't fetch DNS to the domain.".to_string(),
                     ))?;
-                debug!("domain name resolved to {}", socket_addr);
+                }
+                debug!("domain name resolved to {:?}", socket_addrs);
 
-                // has been converted to an ip
-                Ok(TargetAddr::Ip(socket_addr))
+                Ok(socket_addrs)
             }
         }
     }
@@ -161,20 +178,37 @@ pub enum Addr {
 pub async fn read_address<T: AsyncRead + Unpin>(
     stream: &mut T,
     atyp: u8,
+) -> anyhow::Result<TargetAddr> {
+    read_address_timeout(stream, atyp, None).await
+}
+
+/// Same as [`read_address`], but each read fails with [`io::ErrorKind::TimedOut`] if its bytes
+/// don't come within the timeout.
+pub async fn read_address_timeout<T: AsyncRead + Unpin>(
+    stream: &mut T,
+    atyp: u8,
+    timeout: Option<Duration>,
 ) -> anyhow::Result<TargetAddr> {
     let addr = match atyp {
         consts::SOCKS5_ADDR_TYPE_IPV4 => {
             debug!("Address type `IPv4`");
-            Addr::V4(read_exact!(stream, [0u8; 4]).context(AddrError::IPv4Unreadable)?)
+            Addr::V4(
+                read_exact_timeout!(stream, [0u8; 4], timeout)
+                    .context(AddrError::IPv4Unreadable)?,
+            )
         }
         consts::SOCKS5_ADDR_TYPE_IPV6 => {
             debug!("Address type `IPv6`");
-            Addr::V6(read_exact!(stream, [0u8; 16]).context(AddrError::IPv6Unreadable)?)
+            Addr::V6(
+                read_exact_timeout!(stream, [0u8; 16], timeout)
+                    .context(AddrError::IPv6Unreadable)?,
+            )
         }
         consts::SOCKS5_ADDR_TYPE_DOMAIN_NAME => {
             debug!("Address type `domain`");
-            let len = read_exact!(stream, [0]).context(AddrError::DomainLenUnreadable)?[0];
-            let domain = read_exact!(stream, vec![0u8; len as usize])
+            let len = read_exact_timeout!(stream, [0], timeout)
+                .context(AddrError::DomainLenUnreadable)?[0];
+            let domain = read_exact_timeout!(stream, vec![0u8; len as usize], timeout)
                 .context(AddrError::DomainContentUnreadable)?;
             // make sure the bytes are correct utf8 string
             let domain = String::from_utf8(domain).context(AddrError::Utf8)?;
@@ -185,7 +219,8 @@ pub async fn read_address<T: AsyncRead + Unpin>(
     };
 
     // Find port number
-    let port = read_exact!(stream, [0u8; 2]).context(AddrError::PortNumberUnreadable)?;
+    let port =
+        read_exact_timeout!(stream, [0u8; 2], timeout).context(AddrError::PortNumberUnreadable)?;
     // Convert (u8 * 2) into u16
     let port = (port[0] as u16) << 8 | port[1] as u16;
 
@@ -0,0 +1,16 @@
+//! Helpers of the tests.
+use rustix::net::{AddressFamily, SocketType};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+
+/// Address where connecting hangs, like a firewall dropping the packets: a listener whose
+/// backlog is full. Keep the returned sockets while connecting to it.
+pub(crate) fn blackhole() -> (SocketAddr, TcpListener, TcpStream) {
+    let fd = rustix::net::socket(AddressFamily::INET, SocketType::STREAM, None).unwrap();
+    rustix::net::bind(&fd, &"127.0.0.1:0".parse::<SocketAddr>().unwrap()).unwrap();
+    rustix::net::listen(&fd, 0).unwrap();
+    let listener = TcpListener::from(fd);
+    let addr = listener.local_addr().unwrap();
+    // Takes the only place of the backlog, never accepted
+    let filler = TcpStream::connect(addr).unwrap();
+    (addr, listener, filler)
+}